@@ -0,0 +1,90 @@
+use std::{path::PathBuf, time::Duration};
+
+use libloading::library_filename;
+use serde::Deserialize;
+
+use crate::error::Result;
+
+/// Settings for the server itself, loaded from the same `config.json` as the
+/// module's settings; each side ignores the other's keys.
+///
+/// Every field has a default, so the file (and any of its keys) is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// The module to load, and to reload whenever it changes; defaults to
+    /// where cargo builds it, for the same profile as fluctlight-main.
+    #[cfg_attr(feature = "static-module", allow(dead_code))]
+    pub module_path: PathBuf,
+    /// How long (in milliseconds) the module has to stay unchanged after being
+    /// written, before it gets reloaded.
+    pub reload_debounce_ms: u64,
+    /// How long (in seconds) a reload waits for requests still running on the
+    /// old module before shutting it down anyway; new requests wait for the
+    /// new module meanwhile.
+    #[cfg_attr(feature = "static-module", allow(dead_code))]
+    pub drain_timeout_seconds: u64,
+    /// Where the TLS certificates are, as one directory per server name with
+    /// a `fullchain.pem` and a `privkey.pem` in it; this is how certbot lays
+    /// out `/etc/letsencrypt/live`, which can be used as is.
+    pub tls_dir: PathBuf,
+    /// The certificate for clients that don't ask for a server name (or ask
+    /// for one we have no certificate for); not needed with a single one.
+    pub tls_default_server_name: Option<String>,
+    /// How many requests a single HTTP/2 connection may have going at once.
+    pub http2_max_concurrent_streams: u32,
+    /// How long (in seconds) shutting down waits for requests to finish, and
+    /// then again for the module to get its storage onto disk.
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut module_path = PathBuf::from("target");
+        module_path.push(if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        });
+        module_path.push(library_filename("fluctlight_router"));
+
+        Config {
+            module_path,
+            reload_debounce_ms: 500,
+            drain_timeout_seconds: 60,
+            tls_dir: PathBuf::from("tls"),
+            tls_default_server_name: None,
+            http2_max_concurrent_streams: 100,
+            shutdown_timeout_seconds: 30,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load() -> Result<Self> {
+        if !std::path::Path::new("config.json").exists() {
+            return Ok(Config::default());
+        }
+
+        let config_file = std::fs::File::open("config.json")
+            .map_err(|err| format!("Could not open config.json: {}", err))?;
+
+        let config = serde_json::from_reader(std::io::BufReader::new(config_file))
+            .map_err(|err| format!("Could not parse config.json: {}", err))?;
+
+        Ok(config)
+    }
+
+    pub(crate) fn reload_debounce(&self) -> Duration {
+        Duration::from_millis(self.reload_debounce_ms)
+    }
+
+    #[cfg(not(feature = "static-module"))]
+    pub(crate) fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_seconds)
+    }
+
+    pub(crate) fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_seconds)
+    }
+}
@@ -1,95 +1,349 @@
-use std::{borrow::Cow, path::PathBuf, sync::Arc};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Write,
+    hash::Hasher,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use fluctlight_mod_interface::{
-    CreateStateFunc, DestroyStateFunc, OpaqueModuleState, ProcessRequestFunc, Request,
+    CheckRequestHeadFunc, CreateStateFromSnapshotFunc, CreateStateFunc, DestroyStateFunc,
+    ModuleABIFunc, OpaqueModuleState, ProcessRequestFunc, RResult, RString, Request, RequestHead,
+    SelfTestFunc, ShutdownFunc, SnapshotStateFunc,
+};
+use hyper::{HeaderMap, Method, Uri};
+use libloading::{Library, Symbol};
+use tokio::{sync::Mutex, task::spawn_blocking, time::sleep};
+
+use crate::{
+    config::Config,
+    error::Result,
+    module::{path_and_query, result_to_http_response, with_module_headers, ModuleResponse},
 };
-use hyper::{Method, Uri};
-use libloading::{library_filename, Library, Symbol};
-use tokio::{sync::RwLock, task::spawn_blocking};
-
-use crate::error::Result;
 
 pub(crate) struct MainModule {
-    library: Arc<RwLock<LibraryAndState>>,
+    module_path: PathBuf,
+    current: RwLock<Arc<Generation>>,
+    /// Held for the whole reload, so that reloads don't overlap; requests
+    /// never wait for it.
+    restarting: Mutex<()>,
+    /// Held for writing while the old module hands over to the new one, so
+    /// that new requests wait for the new module instead of failing.
+    swapping: tokio::sync::RwLock<()>,
+    /// Set (while holding `restarting`) once the module has been shut down,
+    /// after which it must not be reloaded.
+    shut_down: AtomicBool,
+    drain_timeout: Duration,
+    reload_status: std::sync::Mutex<ReloadStatus>,
 }
 
-struct LibraryAndState(Option<(Library, OpaqueModuleState)>);
+/// One loaded module; each in-flight request holds a reference to the
+/// generation it started on, which gets unloaded after the last one finishes.
+struct Generation {
+    number: usize,
+    build_id: String,
+    module: LibraryAndState,
+}
 
-impl MainModule {
-    pub(crate) fn library_name() -> PathBuf {
-        let mut path = PathBuf::from("target");
-        path.push(if cfg!(debug_assertions) {
-            "debug"
-        } else {
-            "release"
-        });
-        path.push(library_filename("fluctlight_router"));
-        path
-    }
+/// The library is shared with a replacement state for the same module, in
+/// case a reload has to fall back to it.
+struct LibraryAndState(Option<(Arc<Library>, OpaqueModuleState)>);
 
-    pub(crate) fn new() -> Result<Self> {
-        let library = unsafe { Library::new(Self::library_name())? };
+/// How the last reload went, shown at `/admin/module`.
+#[derive(Default)]
+struct ReloadStatus {
+    last_reload: Option<Instant>,
+    last_error: Option<String>,
+}
 
-        let create_state: Symbol<CreateStateFunc> = unsafe {
-            library.get(b"create_state").map_err(|err| {
-                format!("Could not load create_state symbol from library: {}", err)
-            })?
+impl MainModule {
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        let (library, build_id) = load_module_copy(&config.module_path)?;
+        let module_state = create_state(&library, None)?;
+        let library = Arc::new(library);
+
+        let generation = Generation {
+            number: 1,
+            build_id,
+            module: LibraryAndState(Some((library, module_state))),
         };
 
-        let module_state = unsafe { create_state() };
-
         Ok(MainModule {
-            library: Arc::new(RwLock::new(LibraryAndState(Some((library, module_state))))),
+            module_path: config.module_path.clone(),
+            current: RwLock::new(Arc::new(generation)),
+            restarting: Mutex::new(()),
+            swapping: tokio::sync::RwLock::new(()),
+            shut_down: AtomicBool::new(false),
+            drain_timeout: config.drain_timeout(),
+            reload_status: Default::default(),
         })
     }
 
+    pub(crate) fn module_path(&self) -> &Path {
+        &self.module_path
+    }
+
     pub(crate) async fn process_request(
         &self,
         uri: Uri,
         method: Method,
+        headers: HeaderMap,
         body: Vec<u8>,
-    ) -> (u16, &'static str, Cow<'static, [u8]>) {
-        if uri == "/restart" {
-            eprintln!("Acquiring module write lock...");
-
-            let mut module = self.library.write().await;
-            let result = module
+    ) -> ModuleResponse {
+        // HTTP/2 requests come with an absolute URI, so only the path counts
+        if uri.path() == "/restart" {
+            let result = self
                 .restart()
-                .map(|()| (200, "text/plain", "Restarted.\n".as_bytes().into()));
+                .await
+                .map(|()| ModuleResponse::new(200, "text/plain", "Restarted.\n".as_bytes().into()));
             result_to_http_response(result)
+        } else if uri.path() == "/admin/module" {
+            ModuleResponse::new(200, "text/plain", self.status_text().into_bytes().into())
         } else {
-            let library = self.library.clone().read_owned().await;
+            let generation = {
+                let _swapping = self.swapping.read().await;
+                self.current_generation()
+            };
 
             let response = spawn_blocking(move || {
-                result_to_http_response(library.process_request(uri, method, body))
+                let result = generation
+                    .module
+                    .process_request(&uri, &method, &headers, &body);
+                result_to_http_response(result)
             });
 
             response.await.expect("Process handler should never panic")
         }
     }
 
+    /// Asks the module whether a request's body is worth reading, and how
+    /// large it may be; otherwise returns the response to send instead.
+    pub(crate) fn check_request_head(
+        &self,
+        uri: &Uri,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> std::result::Result<u64, ModuleResponse> {
+        if uri.path() == "/restart" || uri.path() == "/admin/module" {
+            return Ok(0);
+        }
+
+        let generation = self.current_generation();
+
+        match generation.module.check_request_head(uri, method, headers) {
+            Ok(verdict) => verdict,
+            Err(err) => Err(result_to_http_response(Err(err))),
+        }
+    }
+
+    /// Switches over to a freshly built module, once the old one has
+    /// finished its requests (or the drain timeout ran out); new requests
+    /// wait for the switch meanwhile.
     pub(crate) async fn restart(&self) -> Result<()> {
-        eprintln!("Acquiring module lock...");
+        let restarting = self.restarting.lock().await;
+
+        if self.shut_down.load(Ordering::Relaxed) {
+            return Err("Not reloading, the server is shutting down".into());
+        }
+
+        let result = self.reload().await;
+
+        drop(restarting);
+
+        let old_generation = result?;
+        let number = old_generation.number;
+        let weak_generation = Arc::downgrade(&old_generation);
+        drop(old_generation);
+
+        if weak_generation.strong_count() == 0 {
+            eprintln!("Module generation {} unloaded.", number);
+        } else {
+            eprintln!(
+                "Module generation {} will unload once its last requests finish.",
+                number
+            );
+        }
 
-        let mut module = self.library.write().await;
-        module.restart()
+        Ok(())
     }
-}
 
-fn result_to_http_response(
-    result: Result<(u16, &'static str, Cow<'static, [u8]>)>,
-) -> (u16, &'static str, Cow<'static, [u8]>) {
-    match result {
-        Ok(response) => response,
-        Err(err) => {
-            eprintln!("Fatal error: {}", err);
-            (
-                500,
-                "text/plain",
-                format!("Internal server error\n\n{}\n", err)
-                    .into_bytes()
-                    .into(),
-            )
+    /// Lets the current module get everything onto disk before the process
+    /// exits; requests should have stopped coming by then.
+    pub(crate) async fn shutdown(&self) -> Result<()> {
+        let _restarting = self.restarting.lock().await;
+        self.shut_down.store(true, Ordering::Relaxed);
+
+        let generation = self.current_generation();
+
+        // Doesn't wait for requests still running on the module; those fail
+        // instead of writing anything once it has synced
+        let result =
+            spawn_blocking(move || generation.module.shutdown().map_err(|err| err.to_string()));
+
+        Ok(result.await.expect("Shutdown handler should never panic")?)
+    }
+
+    /// Swaps in the new module, keeping track of how that went.
+    async fn reload(&self) -> Result<Arc<Generation>> {
+        let result = self.swap_in_new_module().await;
+
+        let mut reload_status = self
+            .reload_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        reload_status.last_reload = Some(Instant::now());
+        reload_status.last_error = result.as_ref().err().map(ToString::to_string);
+
+        result
+    }
+
+    fn status_text(&self) -> String {
+        let generation = self.current_generation();
+        let reload_status = self
+            .reload_status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut text = String::new();
+
+        writeln!(text, "Module: {}", self.module_path.display()).unwrap();
+        writeln!(text, "Generation: {}", generation.number).unwrap();
+        writeln!(text, "Build ID: {}", generation.build_id).unwrap();
+
+        match reload_status.last_reload {
+            Some(last_reload) => writeln!(
+                text,
+                "Last reload: {} seconds ago",
+                last_reload.elapsed().as_secs()
+            ),
+            None => writeln!(text, "Last reload: never"),
+        }
+        .unwrap();
+
+        if let Some(last_error) = &reload_status.last_error {
+            writeln!(text, "Last reload failed: {}", last_error).unwrap();
+        }
+
+        text
+    }
+
+    fn current_generation(&self) -> Arc<Generation> {
+        // Only ever replaced wholesale, so it can't be left half-changed
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    async fn swap_in_new_module(&self) -> Result<Arc<Generation>> {
+        eprintln!("Restarting...");
+
+        // Loaded side by side with the old module, whose library stays around
+        // until the requests still running on it are done.
+        let (new_library, build_id) = load_module_copy(&self.module_path)?;
+
+        // The new state can't be created next to the old one, since it opens
+        // the same files (and sled locks its database), so the old one has
+        // to sync and close them first. New requests are held back until the
+        // new state takes them, rather than failing on the closed old one.
+        let _swapping = self.swapping.write().await;
+
+        let old_generation = self.current_generation();
+
+        self.drain(&old_generation).await;
+
+        let (library, module_state) = old_generation
+            .module
+            .0
+            .as_ref()
+            .ok_or("Module not loaded")?;
+
+        // Requests still running on it fail from then on instead of writing
+        // anything, and get retried on the new one.
+        if let Err(err) = old_generation.module.shutdown() {
+            eprintln!("Old module did not shut down cleanly: {}", err);
+        }
+
+        // Nothing changes the old state anymore, so this has all of it
+        let snapshot = snapshot_state(library, module_state);
+
+        let new_module = create_state(&new_library, snapshot.as_deref())
+            .map(|new_module_state| {
+                LibraryAndState(Some((Arc::new(new_library), new_module_state)))
+            })
+            .and_then(|new_module| {
+                new_module
+                    .smoke_test()
+                    .map_err(|err| format!("Failed its smoke test: {}", err))?;
+                Ok(new_module)
+            });
+
+        // The old state can't write anymore either way, so a new module that
+        // didn't work out is replaced by the old one with a fresh state
+        let (module, build_id, result) = match new_module {
+            Ok(new_module) => (new_module, build_id, Ok(())),
+            Err(err) => {
+                let module_state =
+                    create_state(library, snapshot.as_deref()).map_err(|restart_err| {
+                        format!(
+                            "New module failed ({}), and the old one could not start again: {}",
+                            err, restart_err
+                        )
+                    })?;
+
+                (
+                    LibraryAndState(Some((library.clone(), module_state))),
+                    old_generation.build_id.clone(),
+                    Err(format!("New module failed, kept the old one: {}", err).into()),
+                )
+            }
+        };
+
+        let new_generation = Arc::new(Generation {
+            number: old_generation.number + 1,
+            build_id,
+            module,
+        });
+
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = new_generation;
+
+        eprintln!(
+            "Switched to module generation {}.",
+            old_generation.number + 1
+        );
+
+        result.map(|()| old_generation)
+    }
+
+    /// Waits for the requests running on the current generation to finish,
+    /// for up to the drain timeout; whatever is still running then gets shut
+    /// down under it.
+    async fn drain(&self, generation: &Arc<Generation>) {
+        let started = Instant::now();
+
+        loop {
+            // Not counting ours and the one in `current`
+            let in_flight = Arc::strong_count(generation).saturating_sub(2);
+
+            if in_flight == 0 {
+                eprintln!("Module generation {} drained.", generation.number);
+                return;
+            }
+
+            if started.elapsed() >= self.drain_timeout {
+                eprintln!(
+                    "Module generation {} still has {} requests running after {:?}; \
+                    shutting it down anyway, they will fail instead of writing anything.",
+                    generation.number, in_flight, self.drain_timeout
+                );
+                return;
+            }
+
+            sleep(Duration::from_millis(100)).await;
         }
     }
 }
@@ -97,76 +351,229 @@ fn result_to_http_response(
 impl LibraryAndState {
     fn process_request(
         &self,
-        uri: Uri,
-        method: Method,
-        body: Vec<u8>,
-    ) -> Result<(u16, &'static str, Cow<'static, [u8]>)> {
+        uri: &Uri,
+        method: &Method,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<ModuleResponse> {
         let (library, module_state) = self.0.as_ref().ok_or("Module not loaded")?;
 
-        // SAFETY: The library is trusted, and uses abi_stable
-        // Although if `ProcessRequestFunc`'s types are out of sync, all
-        // hell will break loose.
-        let module_response = unsafe {
-            let entry_point: Symbol<ProcessRequestFunc> =
-                library.get(b"process_request").map_err(|err| {
-                    format!(
-                        "Could not load process_request symbol from library: {}",
-                        err
-                    )
-                })?;
-            entry_point(Request::new(
-                module_state,
-                uri.path(),
-                method.as_str(),
-                body.as_slice(),
-            ))
-        };
+        let module_response = with_module_headers(headers, |headers| -> Result<_> {
+            // SAFETY: The library is trusted, uses abi_stable, and its ABI was
+            // checked against ours when it was loaded.
+            unsafe {
+                let entry_point: Symbol<ProcessRequestFunc> =
+                    library.get(b"process_request").map_err(|err| {
+                        format!(
+                            "Could not load process_request symbol from library: {}",
+                            err
+                        )
+                    })?;
+                Ok(entry_point(Request::new(
+                    module_state,
+                    path_and_query(uri),
+                    method.as_str(),
+                    headers,
+                    body,
+                )))
+            }
+        })?;
         Ok(module_response.into_result()?.into())
     }
 
-    fn restart(&mut self) -> Result<()> {
-        let module = self;
+    fn check_request_head(
+        &self,
+        uri: &Uri,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> Result<std::result::Result<u64, ModuleResponse>> {
+        let (library, module_state) = self.0.as_ref().ok_or("Module not loaded")?;
 
-        eprintln!("Restarting...");
+        let verdict = with_module_headers(headers, |headers| -> Result<_> {
+            // SAFETY: Same as for process_request above.
+            unsafe {
+                let entry_point: Symbol<CheckRequestHeadFunc> =
+                    library.get(b"check_request_head").map_err(|err| {
+                        format!(
+                            "Could not load check_request_head symbol from library: {}",
+                            err
+                        )
+                    })?;
+                Ok(entry_point(RequestHead::new(
+                    module_state,
+                    path_and_query(uri),
+                    method.as_str(),
+                    headers,
+                )))
+            }
+        })?;
+        Ok(verdict.into_result().map_err(Into::into))
+    }
 
-        let (library, module_state) = module.0.take().ok_or("Module not loaded")?;
+    fn shutdown(&self) -> Result<()> {
+        let (library, module_state) = self.0.as_ref().ok_or("Module not loaded")?;
 
-        let destroy_state: Symbol<DestroyStateFunc> = unsafe {
-            library.get(b"destroy_state").map_err(|err| {
-                format!("Could not load destroy_state symbol from library: {}", err)
-            })?
+        // SAFETY: Same as for process_request above.
+        let result = unsafe {
+            let shutdown: Symbol<ShutdownFunc> = library
+                .get(b"shutdown")
+                .map_err(|err| format!("Could not load shutdown symbol from library: {}", err))?;
+            shutdown(module_state)
         };
 
-        let destroyed = unsafe { destroy_state(module_state) };
+        result.into_result().map_err(|err| {
+            format!("Module could not shut down cleanly: {}", String::from(err)).into()
+        })
+    }
+
+    /// Checks that a module with a freshly created state can answer requests.
+    fn smoke_test(&self) -> Result<()> {
+        let uri = Uri::from_static("/_matrix/federation/v1/version");
+        let response = self.process_request(&uri, &Method::GET, &HeaderMap::new(), &[])?;
 
-        if !destroyed {
-            return Err("Could not destroy old module's state; restart aborted.".into());
+        if response.status != 200 {
+            return Err(format!("Version request failed with status {}", response.status).into());
         }
 
+        Ok(())
+    }
+}
+
+/// Loads the module, refusing it if it was built against a different
+/// interface, is missing any of the entry points we need, or fails its own
+/// self-test.
+fn load_module(path: &Path) -> Result<Library> {
+    let library = unsafe {
+        Library::new(path)
+            .map_err(|err| format!("Could not load module {}: {}", path.display(), err))?
+    };
+
+    let module_abi: Symbol<ModuleABIFunc> = unsafe {
+        library.get(b"module_abi").map_err(|err| {
+            format!(
+                "Module has no ABI information, it was likely built against an older interface: {}",
+                err
+            )
+        })?
+    };
+
+    // SAFETY: `ModuleABI`'s layout never changes, see its definition.
+    let module_abi = unsafe { module_abi() };
+    module_abi
+        .check_compatible()
+        .map_err(|err| format!("Refusing to load module: {}", err))?;
+
+    for symbol in [
+        "process_request",
+        "check_request_head",
+        "create_state",
+        "destroy_state",
+        "shutdown",
+    ] {
+        unsafe { library.get::<*const ()>(symbol.as_bytes()) }
+            .map_err(|err| format!("Module has no {} symbol: {}", symbol, err))?;
+    }
+
+    let self_test: Symbol<SelfTestFunc> = unsafe {
         library
-            .close()
-            .map_err(|err| format!("Could not close module: {}", err))?;
-        let library = unsafe {
-            Library::new(MainModule::library_name())
-                .map_err(|err| format!("Could not load module: {}", err))?
-        };
+            .get(b"self_test")
+            .map_err(|err| format!("Module has no self_test symbol: {}", err))?
+    };
 
-        let create_state: Symbol<CreateStateFunc> = unsafe {
-            library.get(b"create_state").map_err(|err| {
-                format!("Could not load create_state symbol from library: {}", err)
-            })?
-        };
+    unsafe { self_test() }
+        .into_result()
+        .map_err(|err| format!("Module failed its self-test: {}", String::from(err)))?;
+
+    Ok(library)
+}
 
-        let module_state = unsafe { create_state() };
+/// Loads the module from a copy with a unique name, since loading the same
+/// path again would only return the library that is already loaded.
+///
+/// Also returns the module's build ID, a hash of the library's contents.
+fn load_module_copy(path: &Path) -> Result<(Library, String)> {
+    static LOADED_COPIES: AtomicUsize = AtomicUsize::new(0);
 
-        *module = LibraryAndState(Some((library, module_state)));
+    let file_name = path.file_name().ok_or("Module path has no file name")?;
+    let copy_path = std::env::temp_dir().join(format!(
+        "{}.{}.{}",
+        std::process::id(),
+        LOADED_COPIES.fetch_add(1, Ordering::Relaxed),
+        file_name.to_string_lossy()
+    ));
 
-        eprintln!("Done.");
+    let contents = std::fs::read(path)
+        .map_err(|err| format!("Could not read module {}: {}", path.display(), err))?;
 
-        Ok(())
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&contents);
+    let build_id = format!("{:016x}", hasher.finish());
+
+    std::fs::write(&copy_path, contents)
+        .map_err(|err| format!("Could not copy module to {}: {}", copy_path.display(), err))?;
+
+    let library = load_module(&copy_path);
+
+    // Already mapped by now, where the platform allows removing it
+    let _ = std::fs::remove_file(&copy_path);
+
+    Ok((library?, build_id))
+}
+
+/// Asks the module for a snapshot of its in-memory state, if it supports
+/// handing it over to the next module.
+fn snapshot_state(library: &Library, module_state: &OpaqueModuleState) -> Option<Vec<u8>> {
+    let snapshot_state: Symbol<SnapshotStateFunc> = unsafe { library.get(b"snapshot_state").ok()? };
+
+    eprintln!("Taking state snapshot...");
+    let module_snapshot = unsafe { snapshot_state(module_state) };
+
+    // Copied out, since the module's allocator goes away with the module
+    let snapshot = module_snapshot.as_slice().to_vec();
+    drop(module_snapshot);
+
+    if snapshot.is_empty() {
+        None
+    } else {
+        Some(snapshot)
     }
 }
 
+/// Creates the module's state, from a previous module's snapshot if there is
+/// one and the module knows how to take it over.
+fn create_state(library: &Library, snapshot: Option<&[u8]>) -> Result<OpaqueModuleState> {
+    if let Some(snapshot) = snapshot {
+        let create_state_from_snapshot =
+            unsafe { library.get::<CreateStateFromSnapshotFunc>(b"create_state_from_snapshot") };
+
+        match create_state_from_snapshot {
+            Ok(create_state_from_snapshot) => {
+                let module_state = unsafe { create_state_from_snapshot(snapshot.into()) };
+                return module_state_result(module_state);
+            }
+            Err(_err) => {
+                eprintln!("Module cannot take over state snapshots, loading from disk...");
+            }
+        }
+    }
+
+    let create_state: Symbol<CreateStateFunc> = unsafe {
+        library
+            .get(b"create_state")
+            .map_err(|err| format!("Could not load create_state symbol from library: {}", err))?
+    };
+
+    module_state_result(unsafe { create_state() })
+}
+
+fn module_state_result(
+    module_state: RResult<OpaqueModuleState, RString>,
+) -> Result<OpaqueModuleState> {
+    module_state
+        .into_result()
+        .map_err(|err| format!("Module could not create its state: {}", String::from(err)).into())
+}
+
 // Destroy the state before unloading the library.
 impl Drop for LibraryAndState {
     fn drop(&mut self) {
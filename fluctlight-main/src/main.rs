@@ -1,23 +1,38 @@
-use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc, time::Duration};
 
+use futures_util::{compat::Stream01CompatExt, Stream, StreamExt};
 use hyper::{
+    body::HttpBody,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH},
     server::conn::AddrIncoming,
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server,
 };
-use libloading::library_filename;
 use tls_listener::TlsListener;
-use tokio_inotify::{AsyncINotify, IN_CREATE};
-use tokio_rustls::{
-    rustls::{Certificate, PrivateKey, ServerConfig},
-    TlsAcceptor,
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::timeout,
 };
+use tokio_inotify::{AsyncINotify, IN_CLOSE_WRITE, IN_CREATE, IN_MOVED_TO};
 
+use crate::config::Config;
 use crate::error::Result;
+#[cfg(not(feature = "static-module"))]
 use crate::libloader::MainModule;
+use crate::module::ModuleResponse;
+#[cfg(feature = "static-module")]
+use crate::static_module::StaticModule as MainModule;
+use crate::tls::{tls_acceptor, TlsCertificates};
 
+mod config;
 mod error;
+#[cfg(not(feature = "static-module"))]
 mod libloader;
+mod module;
+#[cfg(feature = "static-module")]
+mod static_module;
+mod tls;
 
 fn main() -> Result<()> {
     eprintln!("Starting runtime...");
@@ -29,11 +44,26 @@ fn main() -> Result<()> {
     eprintln!("Creating server at http://127.1.0.2:8008/admin/view");
     let addr = SocketAddr::from(([127, 1, 0, 2], 8008));
 
-    let main_module = Arc::new(MainModule::new().unwrap());
+    let config = Config::load()?;
+
+    let tls_certificates = Arc::new(TlsCertificates::load(&config)?);
+
+    let main_module = Arc::new(MainModule::new(&config).unwrap());
 
     let main_module_2 = main_module.clone();
+    let main_module_3 = main_module.clone();
+
+    // Tells both listeners to stop accepting connections and drain
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let shutdown_sender = Arc::new(shutdown_sender);
 
-    tokio_runtime.spawn(watch_module(main_module.clone()));
+    #[cfg(not(feature = "static-module"))]
+    tokio_runtime.spawn(watch_module(main_module.clone(), config.reload_debounce()));
+    tokio_runtime.spawn(watch_certificates(
+        tls_certificates.clone(),
+        config.reload_debounce(),
+    ));
+    tokio_runtime.spawn(reload_certificates_on_hangup(tls_certificates.clone()));
 
     let make_server = async {
         eprintln!("Loading module...");
@@ -48,10 +78,13 @@ fn main() -> Result<()> {
             }
         });
 
+        // Also speaks HTTP/2 to clients that start with its preface (h2c with
+        // prior knowledge), e.g. for local testing without TLS
         std::result::Result::<_, hyper::Error>::Ok(
             Server::try_bind(&addr)?
+                .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
                 .serve(make_service)
-                .with_graceful_shutdown(shutdown_signal()),
+                .with_graceful_shutdown(shutdown_requested(shutdown_receiver.clone())),
         )
     };
 
@@ -60,19 +93,7 @@ fn main() -> Result<()> {
 
     let make_tls_server = async {
         let addr = SocketAddr::from(([127, 1, 0, 2], 8448));
-        let acceptor: TlsAcceptor = {
-            let key = PrivateKey(include_bytes!("../../key.der").as_slice().into());
-            let cert = Certificate(include_bytes!("../../cert.der").as_slice().into());
-
-            Arc::new(
-                ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(vec![cert], key)
-                    .unwrap(),
-            )
-            .into()
-        };
+        let acceptor = tls_acceptor(tls_certificates);
 
         let incoming = TlsListener::new(acceptor, AddrIncoming::bind(&addr)?);
 
@@ -88,64 +109,208 @@ fn main() -> Result<()> {
 
         std::result::Result::<_, hyper::Error>::Ok(
             Server::builder(incoming)
+                .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
                 .serve(make_service)
-                .with_graceful_shutdown(shutdown_signal()),
+                .with_graceful_shutdown(shutdown_requested(shutdown_receiver.clone())),
         )
     };
     let https_server = tokio_runtime.block_on(make_tls_server)?;
     eprintln!("HTTPS server is ready.");
 
-    tokio_runtime.spawn(http_server);
-    let handle = tokio_runtime.spawn(https_server);
-    tokio_runtime.block_on(handle)??;
+    let http_listener =
+        tokio_runtime.spawn(run_listener("HTTP", http_server, shutdown_sender.clone()));
+    let https_listener =
+        tokio_runtime.spawn(run_listener("HTTPS", https_server, shutdown_sender.clone()));
+
+    let shutdown_timeout = config.shutdown_timeout();
+
+    tokio_runtime.block_on(async {
+        tokio::select! {
+            () = shutdown_signal() => {
+                shutdown_sender.send_replace(true);
+            }
+            () = shutdown_requested(shutdown_receiver.clone()) => {}
+        }
+
+        let listeners = async {
+            http_listener.await.ok();
+            https_listener.await.ok();
+        };
+
+        if timeout(shutdown_timeout, listeners).await.is_err() {
+            eprintln!("Requests still running after the shutdown timeout, shutting down anyway");
+        }
+
+        eprintln!("Shutting down module...");
+        match timeout(shutdown_timeout, main_module_3.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("{}", err),
+            Err(_elapsed) => eprintln!("Module did not shut down within the shutdown timeout"),
+        }
+    });
+
+    // Requests stuck in the module shouldn't keep the process from exiting
+    tokio_runtime.shutdown_background();
 
     eprintln!("Shutdown complete.");
 
     Ok(())
 }
 
-async fn watch_module(main_module: Arc<MainModule>) {
+/// Reloads the module once its file has been written and left alone for the
+/// debounce interval, e.g. by cargo finishing a build.
+#[cfg(not(feature = "static-module"))]
+async fn watch_module(main_module: Arc<MainModule>, debounce: Duration) {
+    use std::path::Path;
+
     let inotify = AsyncINotify::init().expect("Failed to install inotify watcher");
 
-    let mut module_path = PathBuf::from("target");
-    module_path.push(if cfg!(debug_assertions) {
-        "debug"
-    } else {
-        "release"
-    });
-    let module_name = library_filename("fluctlight_router");
+    let module_path = main_module.module_path().to_owned();
+    let module_dir = match module_path.parent() {
+        Some(module_dir) if module_dir != Path::new("") => module_dir,
+        _ => Path::new("."),
+    };
+    let module_name = module_path
+        .file_name()
+        .expect("Module path should have a file name");
+
     inotify
-        .add_watch(&module_path, IN_CREATE)
+        .add_watch(module_dir, IN_CREATE | IN_CLOSE_WRITE | IN_MOVED_TO)
         .expect("Failed to watch module path with inotify");
 
     eprintln!(
         "Installed watch on {} for {}",
-        module_path.display(),
+        module_dir.display(),
         module_name.to_string_lossy()
     );
 
-    use futures_util::compat::Stream01CompatExt;
-    use futures_util::StreamExt;
     let mut inotify_stream = inotify.compat();
 
     while let Some(event) = inotify_stream.next().await {
         let event = event.expect("Failed to get inotify event");
 
-        if event.is_create() && event.name.ends_with(&module_name) {
-            eprintln!("Received inotify event...");
-            if let Err(err) = main_module.restart().await {
-                eprintln!("Could not restart module on inotify event: {}", err);
+        let is_written = event.is_create() || event.is_close_write() || event.is_moved_to();
+        if !is_written || !event.name.ends_with(module_name) {
+            continue;
+        }
+
+        eprintln!("Module changed, waiting for it to settle...");
+
+        // Anything else changing next to it (e.g. the rest of a cargo build)
+        // restarts the wait
+        if !wait_until_settled(&mut inotify_stream, debounce).await {
+            return;
+        }
+
+        if let Err(err) = main_module.restart().await {
+            eprintln!("Could not restart module on inotify event: {}", err);
+        }
+    }
+}
+
+/// Reloads the TLS certificates once they changed and settled, e.g. after an
+/// ACME client renewed them.
+async fn watch_certificates(tls_certificates: Arc<TlsCertificates>, debounce: Duration) {
+    let inotify = AsyncINotify::init().expect("Failed to install inotify watcher");
+
+    let add_watches = |inotify: &AsyncINotify| {
+        for watched_dir in tls_certificates.watched_dirs() {
+            if let Err(err) =
+                inotify.add_watch(&watched_dir, IN_CREATE | IN_CLOSE_WRITE | IN_MOVED_TO)
+            {
+                eprintln!("Could not watch {}: {}", watched_dir.display(), err);
+            }
+        }
+    };
+    add_watches(&inotify);
+
+    let mut inotify_stream = inotify.compat();
+
+    while let Some(event) = inotify_stream.next().await {
+        event.expect("Failed to get inotify event");
+
+        if !wait_until_settled(&mut inotify_stream, debounce).await {
+            return;
+        }
+
+        reload_certificates(&tls_certificates);
+
+        // Also picks up the directories of newly added server names
+        add_watches(inotify_stream.get_ref());
+    }
+}
+
+async fn reload_certificates_on_hangup(tls_certificates: Arc<TlsCertificates>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to install hangup signal handler");
+
+    while hangup.recv().await.is_some() {
+        eprintln!("Hangup signal received, reloading TLS certificates...");
+        reload_certificates(&tls_certificates);
+    }
+}
+
+fn reload_certificates(tls_certificates: &TlsCertificates) {
+    match tls_certificates.reload() {
+        Ok(()) => eprintln!("TLS certificates reloaded."),
+        Err(err) => eprintln!(
+            "Could not reload TLS certificates, keeping the old ones: {}",
+            err
+        ),
+    }
+}
+
+/// Waits for inotify events to stop coming for the debounce interval; returns
+/// false if the watch ended instead.
+async fn wait_until_settled<S, T, E>(inotify_stream: &mut S, debounce: Duration) -> bool
+where
+    S: Stream<Item = std::result::Result<T, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    loop {
+        match timeout(debounce, inotify_stream.next()).await {
+            Ok(Some(event)) => {
+                event.expect("Failed to get inotify event");
             }
+            Ok(None) => return false,
+            Err(_elapsed) => return true,
+        }
+    }
+}
+
+/// Runs a listener until it has drained; if it stops for any other reason,
+/// the whole server shuts down along with it.
+async fn run_listener<F>(name: &'static str, server: F, shutdown_sender: Arc<watch::Sender<bool>>)
+where
+    F: Future<Output = std::result::Result<(), hyper::Error>>,
+{
+    if let Err(err) = server.await {
+        eprintln!("{} server failed: {}", name, err);
+    }
+
+    shutdown_sender.send_replace(true);
+}
+
+async fn shutdown_requested(mut shutdown_receiver: watch::Receiver<bool>) {
+    while !*shutdown_receiver.borrow() {
+        if shutdown_receiver.changed().await.is_err() {
+            return;
         }
     }
 }
 
+/// Waits for either Ctrl-C or SIGTERM (e.g. from systemd).
 async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to install interrupt signal handler");
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Failed to install terminate signal handler");
 
-    eprintln!("\nInterrupt signal received, shutting down...")
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.expect("Failed to install interrupt signal handler");
+        }
+        _ = terminate.recv() => {}
+    }
+
+    eprintln!("\nShutdown signal received, draining requests...")
 }
 
 async fn process_request_in_module(
@@ -153,16 +318,96 @@ async fn process_request_in_module(
     req: Request<Body>,
 ) -> std::result::Result<Response<Body>, Infallible> {
     let (parts, body) = req.into_parts();
-    // FIXME: fix unwrap
-    let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
     let uri = parts.uri;
     let method = parts.method;
 
-    let (status, content_type, body) = main_module.process_request(uri, method, body).await;
+    let body_limit = match main_module.check_request_head(&uri, &method, &parts.headers) {
+        Ok(body_limit) => body_limit,
+        Err(response) => return Ok(into_http_response(response)),
+    };
+
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if content_length.is_some_and(|content_length| content_length > body_limit) {
+        return Ok(into_http_response(payload_too_large()));
+    }
+
+    let body = match read_body(body, content_length, body_limit).await {
+        Ok(body) => body,
+        Err(response) => return Ok(into_http_response(response)),
+    };
+
+    let response = main_module
+        .process_request(uri, method, parts.headers, body)
+        .await;
+
+    Ok(into_http_response(response))
+}
+
+/// Reads at most `limit` bytes of the body, whatever its `Content-Length`
+/// said (if anything).
+async fn read_body(
+    mut body: Body,
+    content_length: Option<u64>,
+    limit: u64,
+) -> std::result::Result<Vec<u8>, ModuleResponse> {
+    let capacity = content_length.unwrap_or(0).min(limit);
+    let mut bytes = Vec::with_capacity(capacity as usize);
+
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let message = format!("Could not read request body: {}\n", err);
+                return Err(ModuleResponse::new(
+                    400,
+                    "text/plain",
+                    message.into_bytes().into(),
+                ));
+            }
+        };
+
+        if (bytes.len() + chunk.len()) as u64 > limit {
+            return Err(payload_too_large());
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+fn payload_too_large() -> ModuleResponse {
+    ModuleResponse::new(
+        413,
+        "application/json",
+        br#"{"errcode":"M_TOO_LARGE","error":"Request body is too large"}"#
+            .as_slice()
+            .into(),
+    )
+}
+
+fn into_http_response(module_response: ModuleResponse) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(module_response.status)
+        .header("Content-Type", module_response.content_type);
+
+    for (name, value) in module_response.headers {
+        // A header the module got wrong shouldn't cost the whole response
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => response = response.header(name, value),
+            _ => eprintln!("Dropping invalid response header: {}", name),
+        }
+    }
 
-    Ok(Response::builder()
-        .status(status)
-        .header("Content-Type", content_type)
-        .body(body.into())
-        .expect("Status and body should be valid"))
+    response
+        .body(module_response.body.into())
+        .expect("Status and body should be valid")
 }
@@ -0,0 +1,93 @@
+//! What the server and the module exchange, whichever way the module is run
+//! (see `libloader` and `static_module`).
+
+use std::borrow::Cow;
+
+use fluctlight_mod_interface::{Header, Response};
+use hyper::{HeaderMap, Uri};
+
+use crate::error::Result;
+
+/// Most requests have far fewer headers than this, so that passing them to
+/// the module needs no allocation.
+const INLINE_HEADERS: usize = 32;
+
+/// A response from the module (or on its behalf), copied out of it.
+pub(crate) struct ModuleResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Cow<'static, [u8]>,
+}
+
+impl ModuleResponse {
+    pub(crate) fn new(status: u16, content_type: &'static str, body: Cow<'static, [u8]>) -> Self {
+        ModuleResponse {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+impl From<Response> for ModuleResponse {
+    fn from(response: Response) -> Self {
+        let headers = response
+            .headers()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        let (status, content_type, body) = response.into();
+
+        ModuleResponse {
+            status,
+            content_type,
+            headers,
+            body,
+        }
+    }
+}
+
+pub(crate) fn result_to_http_response(result: Result<ModuleResponse>) -> ModuleResponse {
+    match result {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Fatal error: {}", err);
+            ModuleResponse::new(
+                500,
+                "text/plain",
+                format!("Internal server error\n\n{}\n", err)
+                    .into_bytes()
+                    .into(),
+            )
+        }
+    }
+}
+
+/// Lends the request headers to the module, without copying them anywhere
+/// but the stack unless there are unusually many.
+pub(crate) fn with_module_headers<R>(header_map: &HeaderMap, f: impl FnOnce(&[Header]) -> R) -> R {
+    let headers = header_map
+        .iter()
+        .map(|(name, value)| Header::new(name.as_str(), value.as_bytes()));
+
+    if header_map.len() <= INLINE_HEADERS {
+        let mut inline_headers = [Header::new("", &[]); INLINE_HEADERS];
+        let mut header_count = 0;
+
+        for (slot, header) in inline_headers.iter_mut().zip(headers) {
+            *slot = header;
+            header_count += 1;
+        }
+
+        f(&inline_headers[..header_count])
+    } else {
+        let headers: Vec<_> = headers.collect();
+        f(&headers)
+    }
+}
+
+pub(crate) fn path_and_query(uri: &Uri) -> &str {
+    uri.path_and_query()
+        .map_or_else(|| uri.path(), |path_and_query| path_and_query.as_str())
+}
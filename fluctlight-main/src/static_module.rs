@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use fluctlight_mod_interface::{OpaqueModuleState, Request, RequestHead};
+use hyper::{HeaderMap, Method, Uri};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    config::Config,
+    error::Result,
+    module::{path_and_query, result_to_http_response, with_module_headers, ModuleResponse},
+};
+
+/// The router linked into the server, and called like any other library.
+///
+/// Answers requests the same way as `MainModule`, but can't be reloaded,
+/// which is what a release build needs anyway.
+pub(crate) struct StaticModule {
+    module_state: Option<OpaqueModuleState>,
+}
+
+impl StaticModule {
+    pub(crate) fn new(_config: &Config) -> Result<Self> {
+        fluctlight_router::self_test()
+            .into_result()
+            .map_err(|err| format!("Module failed its self-test: {}", String::from(err)))?;
+
+        let module_state = fluctlight_router::create_state()
+            .into_result()
+            .map_err(|err| format!("Module could not create its state: {}", String::from(err)))?;
+
+        Ok(StaticModule {
+            module_state: Some(module_state),
+        })
+    }
+
+    pub(crate) async fn process_request(
+        self: &Arc<Self>,
+        uri: Uri,
+        method: Method,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> ModuleResponse {
+        if uri.path() == "/admin/module" {
+            return ModuleResponse::new(200, "text/plain", "Module: built in\n".as_bytes().into());
+        }
+
+        let module = self.clone();
+
+        let response = spawn_blocking(move || {
+            let module_response = with_module_headers(&headers, |headers| {
+                fluctlight_router::process_request(Request::new(
+                    module.module_state(),
+                    path_and_query(&uri),
+                    method.as_str(),
+                    headers,
+                    &body,
+                ))
+            });
+
+            let result = module_response
+                .into_result()
+                .map(ModuleResponse::from)
+                .map_err(Into::into);
+            result_to_http_response(result)
+        });
+
+        response.await.expect("Process handler should never panic")
+    }
+
+    pub(crate) fn check_request_head(
+        &self,
+        uri: &Uri,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> std::result::Result<u64, ModuleResponse> {
+        if uri.path() == "/admin/module" {
+            return Ok(0);
+        }
+
+        let verdict = with_module_headers(headers, |headers| {
+            fluctlight_router::check_request_head(RequestHead::new(
+                self.module_state(),
+                path_and_query(uri),
+                method.as_str(),
+                headers,
+            ))
+        });
+
+        verdict.into_result().map_err(Into::into)
+    }
+
+    pub(crate) async fn shutdown(self: &Arc<Self>) -> Result<()> {
+        let module = self.clone();
+
+        // Doesn't wait for requests still running on the module; those fail
+        // instead of writing anything once it has synced
+        let result = spawn_blocking(move || {
+            fluctlight_router::shutdown(module.module_state())
+                .into_result()
+                .map_err(|err| format!("Module could not shut down cleanly: {}", String::from(err)))
+        });
+
+        Ok(result.await.expect("Shutdown handler should never panic")?)
+    }
+
+    fn module_state(&self) -> &OpaqueModuleState {
+        self.module_state
+            .as_ref()
+            .expect("Module state only goes away on drop")
+    }
+}
+
+impl Drop for StaticModule {
+    fn drop(&mut self) {
+        if let Some(module_state) = self.module_state.take() {
+            fluctlight_router::destroy_state(module_state);
+        }
+    }
+}
@@ -1,22 +1,51 @@
 use std::{any::Any, borrow::Cow};
 
 use abi_stable::{
+    abi_stability::abi_checking::check_layout_compatibility,
     erased_types::TypeInfo,
-    std_types::{RBox, RCow, RSlice, RStr, RString},
+    std_types::{RBox, RCow, RStr},
+    type_layout::TypeLayout,
     DynTrait, ImplType, StableAbi,
 };
 
-pub use abi_stable::std_types::RResult;
+pub use abi_stable::std_types::{RResult, RSlice, RString, RVec};
 
 #[derive(StableAbi)]
 #[repr(C)]
 pub struct Request<'a> {
     module_state: &'a OpaqueModuleState,
+    /// The path, followed by the query string if there is one.
     uri: RStr<'a>,
     method: RStr<'a>,
+    headers: RSlice<'a, Header<'a>>,
     body: RSlice<'a, u8>,
 }
 
+/// What is known about a request before reading its body.
+#[derive(StableAbi)]
+#[repr(C)]
+pub struct RequestHead<'a> {
+    module_state: &'a OpaqueModuleState,
+    uri: RStr<'a>,
+    method: RStr<'a>,
+    headers: RSlice<'a, Header<'a>>,
+}
+
+/// A request header, borrowed from wherever the server keeps it.
+#[derive(StableAbi, Clone, Copy)]
+#[repr(C)]
+pub struct Header<'a> {
+    name: RStr<'a>,
+    value: RSlice<'a, u8>,
+}
+
+#[derive(StableAbi)]
+#[repr(C)]
+pub struct ResponseHeader {
+    name: RString,
+    value: RString,
+}
+
 #[derive(StableAbi)]
 #[repr(C)]
 pub struct ResponseResult {
@@ -28,6 +57,7 @@ pub struct ResponseResult {
 pub struct Response {
     status: u16,
     content_type: RStr<'static>,
+    headers: RVec<ResponseHeader>,
     body: RCow<'static, [u8]>,
 }
 
@@ -76,21 +106,141 @@ impl ImplType for ModuleState {
 #[sabi(impl_InterfaceType(Send, Sync))]
 pub struct ModuleStateInterface;
 
-pub type ProcessRequestFunc<'a> = unsafe extern "C" fn(Request<'a>) -> ResponseResult;
-pub type CreateStateFunc<'a> = unsafe extern "C" fn() -> OpaqueModuleState;
-pub type DestroyStateFunc<'a> = unsafe extern "C" fn(OpaqueModuleState) -> bool;
+pub type ProcessRequestFunc = for<'a> unsafe extern "C" fn(Request<'a>) -> ResponseResult;
+/// Creates the module's state, or says why it couldn't (e.g. because another
+/// state still has its files open).
+pub type CreateStateFunc = unsafe extern "C" fn() -> RResult<OpaqueModuleState, RString>;
+pub type DestroyStateFunc = unsafe extern "C" fn(OpaqueModuleState) -> bool;
+/// Serializes the module's in-memory state for the next module to pick up;
+/// returns an empty snapshot if there is nothing worth handing over.
+pub type SnapshotStateFunc = for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RVec<u8>;
+/// Like `CreateStateFunc`, but starting from a previous module's snapshot,
+/// which it is free to ignore (e.g. when written by an incompatible version).
+pub type CreateStateFromSnapshotFunc =
+    for<'a> unsafe extern "C" fn(RSlice<'a, u8>) -> RResult<OpaqueModuleState, RString>;
+
+/// Decides whether a request's body is worth reading, returning either how
+/// large it may be or the response that turns the request down.
+pub type CheckRequestHeadFunc =
+    for<'a> unsafe extern "C" fn(RequestHead<'a>) -> RResult<u64, Response>;
+/// Checks that the module could create its state and serve requests, before
+/// it replaces the one that is running.
+pub type SelfTestFunc = unsafe extern "C" fn() -> RResult<(), RString>;
+/// Gets everything the module has written onto disk and closes its storage,
+/// before the process exits or another state takes over the same files.
+/// Requests sent to the state afterwards must not write anything.
+pub type ShutdownFunc = for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RResult<(), RString>;
+/// Checks that a module was built against the same interface as the code
+/// loading it.
+pub type ModuleABIFunc = unsafe extern "C" fn() -> ModuleABI;
+
+/// Bumped whenever the meaning of the interface changes in a way its layout
+/// doesn't show, e.g. when an entry point is added, removed or renamed.
+pub const MODULE_ABI_VERSION: u32 = 6;
+
+/// What a module was built against, as returned by its `module_abi` symbol.
+///
+/// Its own layout must never change, since it's read before knowing whether
+/// anything else can be.
+#[repr(C)]
+pub struct ModuleABI {
+    version: u32,
+    layout: &'static TypeLayout,
+}
+
+/// The entry points a module exports; never built, only its layout matters.
+///
+/// `StableAbi` needs the signatures spelled out, so they are tied to the
+/// aliases the loader uses below.
+#[allow(dead_code)]
+#[derive(StableAbi)]
+#[repr(C)]
+struct ModuleEntryPoints {
+    process_request: for<'a> unsafe extern "C" fn(Request<'a>) -> ResponseResult,
+    check_request_head: for<'a> unsafe extern "C" fn(RequestHead<'a>) -> RResult<u64, Response>,
+    create_state: unsafe extern "C" fn() -> RResult<OpaqueModuleState, RString>,
+    create_state_from_snapshot:
+        for<'a> unsafe extern "C" fn(RSlice<'a, u8>) -> RResult<OpaqueModuleState, RString>,
+    snapshot_state: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RVec<u8>,
+    destroy_state: unsafe extern "C" fn(OpaqueModuleState) -> bool,
+    self_test: unsafe extern "C" fn() -> RResult<(), RString>,
+    shutdown: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RResult<(), RString>,
+}
+
+/// Doesn't compile unless every field of `ModuleEntryPoints` converts to its
+/// alias and back, i.e. unless they have the same signature.
+const _: fn(ModuleEntryPoints) -> ModuleEntryPoints = |entry_points| {
+    let ModuleEntryPoints {
+        process_request,
+        check_request_head,
+        create_state,
+        create_state_from_snapshot,
+        snapshot_state,
+        destroy_state,
+        self_test,
+        shutdown,
+    } = entry_points;
+
+    let process_request: ProcessRequestFunc = process_request;
+    let check_request_head: CheckRequestHeadFunc = check_request_head;
+    let create_state: CreateStateFunc = create_state;
+    let create_state_from_snapshot: CreateStateFromSnapshotFunc = create_state_from_snapshot;
+    let snapshot_state: SnapshotStateFunc = snapshot_state;
+    let destroy_state: DestroyStateFunc = destroy_state;
+    let self_test: SelfTestFunc = self_test;
+    let shutdown: ShutdownFunc = shutdown;
+
+    ModuleEntryPoints {
+        process_request,
+        check_request_head,
+        create_state,
+        create_state_from_snapshot,
+        snapshot_state,
+        destroy_state,
+        self_test,
+        shutdown,
+    }
+};
+
+impl ModuleABI {
+    pub fn current() -> Self {
+        ModuleABI {
+            version: MODULE_ABI_VERSION,
+            layout: <ModuleEntryPoints as StableAbi>::LAYOUT,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Compares a module's ABI against the one this crate was built with.
+    pub fn check_compatible(&self) -> Result<(), String> {
+        if self.version != MODULE_ABI_VERSION {
+            return Err(format!(
+                "Module was built for ABI version {}, expected version {}",
+                self.version, MODULE_ABI_VERSION
+            ));
+        }
+
+        check_layout_compatibility(Self::current().layout, self.layout)
+            .map_err(|errs| format!("Module's interface types are out of sync: {}", errs))
+    }
+}
 
 impl<'a> Request<'a> {
     pub fn new(
         module_state: &'a OpaqueModuleState,
         uri: &'a str,
         method: &'a str,
+        headers: &'a [Header<'a>],
         body: &'a [u8],
     ) -> Self {
         Request {
             module_state,
             uri: uri.into(),
             method: method.into(),
+            headers: headers.into(),
             body: body.into(),
         }
     }
@@ -103,20 +253,106 @@ impl<'a> Request<'a> {
         self.uri.into()
     }
 
+    pub fn path(&self) -> &'a str {
+        split_uri(self.uri.into()).0
+    }
+
+    pub fn query(&self) -> Option<&'a str> {
+        split_uri(self.uri.into()).1
+    }
+
     pub fn method(&self) -> &'a str {
         self.method.into()
     }
 
+    pub fn headers(&self) -> &'a [Header<'a>] {
+        self.headers.into()
+    }
+
     pub fn body(&self) -> &'a [u8] {
         self.body.into()
     }
 }
 
+impl<'a> RequestHead<'a> {
+    pub fn new(
+        module_state: &'a OpaqueModuleState,
+        uri: &'a str,
+        method: &'a str,
+        headers: &'a [Header<'a>],
+    ) -> Self {
+        RequestHead {
+            module_state,
+            uri: uri.into(),
+            method: method.into(),
+            headers: headers.into(),
+        }
+    }
+
+    pub fn module_state(&self) -> &'a ModuleState {
+        ModuleState::as_inner(self.module_state)
+    }
+
+    pub fn uri(&self) -> &'a str {
+        self.uri.into()
+    }
+
+    pub fn path(&self) -> &'a str {
+        split_uri(self.uri.into()).0
+    }
+
+    pub fn method(&self) -> &'a str {
+        self.method.into()
+    }
+
+    pub fn headers(&self) -> &'a [Header<'a>] {
+        self.headers.into()
+    }
+
+    pub fn authorization(&self) -> Option<&'a str> {
+        find_header(self.headers(), "Authorization")
+    }
+}
+
+impl<'a> Header<'a> {
+    pub fn new(name: &'a str, value: &'a [u8]) -> Self {
+        Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.name.into()
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.value.into()
+    }
+}
+
+/// The value of the first header with this (case-insensitive) name, if it is
+/// valid UTF-8.
+pub fn find_header<'a>(headers: &[Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name().eq_ignore_ascii_case(name))
+        .and_then(|header| std::str::from_utf8(header.value()).ok())
+}
+
+fn split_uri(uri: &str) -> (&str, Option<&str>) {
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
+}
+
 impl From<(u16, &'static str, Cow<'static, [u8]>)> for Response {
     fn from((status, content_type, body): (u16, &'static str, Cow<'static, [u8]>)) -> Self {
         Response {
             status,
             content_type: content_type.into(),
+            headers: RVec::new(),
             body: body.into(),
         }
     }
@@ -148,9 +384,23 @@ impl Response {
         (status, content_type, body).into()
     }
 
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push(ResponseHeader {
+            name: name.into(),
+            value: value.into(),
+        });
+        self
+    }
+
     pub fn status(&self) -> u16 {
         self.status
     }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|header| (header.name.as_str(), header.value.as_str()))
+    }
 }
 
 impl ResponseResult {
@@ -158,3 +408,86 @@ impl ResponseResult {
         self.response.into_result().map_err(Into::into)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same as for the real `ModuleEntryPoints`
+    #[allow(improper_ctypes_definitions)]
+    mod written_out {
+        use super::super::*;
+
+        /// `ModuleEntryPoints` with the signatures spelled out.
+        #[allow(dead_code)]
+        #[derive(StableAbi)]
+        #[repr(C)]
+        pub(super) struct ModuleEntryPoints {
+            process_request: for<'a> unsafe extern "C" fn(Request<'a>) -> ResponseResult,
+            check_request_head:
+                for<'a> unsafe extern "C" fn(RequestHead<'a>) -> RResult<u64, Response>,
+            create_state: unsafe extern "C" fn() -> RResult<OpaqueModuleState, RString>,
+            create_state_from_snapshot:
+                for<'a> unsafe extern "C" fn(RSlice<'a, u8>) -> RResult<OpaqueModuleState, RString>,
+            snapshot_state: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RVec<u8>,
+            destroy_state: unsafe extern "C" fn(OpaqueModuleState) -> bool,
+            self_test: unsafe extern "C" fn() -> RResult<(), RString>,
+            shutdown: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RResult<(), RString>,
+        }
+    }
+
+    // Same as for the real `ModuleEntryPoints`
+    #[allow(improper_ctypes_definitions)]
+    mod infallible_create_state {
+        use super::super::*;
+
+        /// `ModuleEntryPoints` from before creating the state could fail.
+        #[allow(dead_code)]
+        #[derive(StableAbi)]
+        #[repr(C)]
+        pub(super) struct ModuleEntryPoints {
+            process_request: for<'a> unsafe extern "C" fn(Request<'a>) -> ResponseResult,
+            check_request_head:
+                for<'a> unsafe extern "C" fn(RequestHead<'a>) -> RResult<u64, Response>,
+            create_state: unsafe extern "C" fn() -> OpaqueModuleState,
+            create_state_from_snapshot:
+                for<'a> unsafe extern "C" fn(RSlice<'a, u8>) -> OpaqueModuleState,
+            snapshot_state: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RVec<u8>,
+            destroy_state: unsafe extern "C" fn(OpaqueModuleState) -> bool,
+            self_test: unsafe extern "C" fn() -> RResult<(), RString>,
+            shutdown: for<'a> unsafe extern "C" fn(&'a OpaqueModuleState) -> RResult<(), RString>,
+        }
+    }
+
+    fn module_abi(version: u32, layout: &'static TypeLayout) -> ModuleABI {
+        ModuleABI { version, layout }
+    }
+
+    #[test]
+    fn same_interface_is_accepted() {
+        let layout = <written_out::ModuleEntryPoints as StableAbi>::LAYOUT;
+
+        assert_eq!(
+            module_abi(MODULE_ABI_VERSION, layout).check_compatible(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn other_abi_version_is_rejected() {
+        let layout = <ModuleEntryPoints as StableAbi>::LAYOUT;
+
+        for version in [MODULE_ABI_VERSION - 1, MODULE_ABI_VERSION + 1] {
+            assert!(module_abi(version, layout).check_compatible().is_err());
+        }
+    }
+
+    #[test]
+    fn changed_entry_point_is_rejected() {
+        let layout = <infallible_create_state::ModuleEntryPoints as StableAbi>::LAYOUT;
+
+        assert!(module_abi(MODULE_ABI_VERSION, layout)
+            .check_compatible()
+            .is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, io::Write};
 
 use serde::{
     de::Visitor,
@@ -39,14 +39,16 @@ pub(crate) fn verify_content_hash(json_blob: &str, print_canonical: bool) -> Res
     }
 
     if print_canonical {
-        let blob = serde_json::to_string(&value).expect("Serialization should always succeed");
+        let blob = value.to_canonical_string();
         eprintln!("Computed canonical hash: {}", blob);
 
         return Ok(());
     }
 
     let mut hasher = sha2::Sha256::new();
-    serde_json::to_writer(&mut hasher, &value).expect("Serialization should always succeed");
+    value
+        .write_canonical(&mut hasher)
+        .expect("Hashers should never fail to write");
     let sha256_hash = hasher.finalize();
 
     let mut scratch_buffer = SmallVec::<[u8; 64]>::new();
@@ -69,6 +71,39 @@ pub(crate) fn verify_content_hash(json_blob: &str, print_canonical: bool) -> Res
     Ok(())
 }
 
+/// Returns the canonical JSON of a signed object as it was before signing, i.e.
+/// without its `signatures` and `unsigned` keys.
+pub(crate) fn signable_canonical_json(json_blob: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    write_canonical_json_without(json_blob, &["signatures", "unsigned"], &mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Writes the canonical JSON of an object, leaving out the given top-level
+/// keys.
+pub(crate) fn write_canonical_json_without<W: Write>(
+    json_blob: &str,
+    removed_keys: &[&str],
+    writer: &mut W,
+) -> Result<(), String> {
+    let mut value: ValueRef = serde_json::from_str(json_blob)
+        .map_err(|err| format!("Could not deserialize signed JSON: {}", err))?;
+
+    if !matches!(value, ValueRef::Map(_)) {
+        return Err("Expected signed JSON to be an object".to_string());
+    }
+
+    for key in removed_keys {
+        value.pop_from_map(key);
+    }
+
+    value
+        .write_canonical(writer)
+        .map_err(|err| format!("Could not write canonical JSON: {}", err))
+}
+
+/// A borrowing version of serde_json::Value, with maps kept sorted by key.
 #[derive(PartialEq, Eq)]
 enum ValueRef<'a> {
     Null,
@@ -76,7 +111,7 @@ enum ValueRef<'a> {
     Number(i64),
     String(Cow<'a, str>),
     List(Vec<ValueRef<'a>>),
-    Map(Vec<(ValueRef<'a>, ValueRef<'a>)>),
+    Map(Vec<(Cow<'a, str>, ValueRef<'a>)>),
 }
 
 impl<'a> ValueRef<'a> {
@@ -86,18 +121,10 @@ impl<'a> ValueRef<'a> {
             _ => return None,
         };
 
-        for (map_key, value) in vec_map {
-            match map_key {
-                ValueRef::String(map_key) => {
-                    if map_key == key {
-                        return Some(value);
-                    }
-                }
-                _ => continue,
-            }
-        }
-
-        None
+        vec_map
+            .binary_search_by(|(map_key, _value)| (**map_key).cmp(key))
+            .ok()
+            .map(|index| &vec_map[index].1)
     }
 
     fn pop_from_map(&mut self, key: &str) -> Option<ValueRef<'a>> {
@@ -106,19 +133,612 @@ impl<'a> ValueRef<'a> {
             _ => return None,
         };
 
-        for (index, (map_key, _value)) in vec_map.iter().enumerate() {
-            match map_key {
-                ValueRef::String(map_key) => {
-                    if map_key == key {
-                        let (_key, value) = vec_map.remove(index);
-                        return Some(value);
+        let index = vec_map
+            .binary_search_by(|(map_key, _value)| (**map_key).cmp(key))
+            .ok()?;
+        let (_key, value) = vec_map.remove(index);
+
+        Some(value)
+    }
+
+    fn to_canonical_string(&self) -> String {
+        let mut bytes = Vec::new();
+        self.write_canonical(&mut bytes)
+            .expect("Writing to a Vec should always succeed");
+
+        String::from_utf8(bytes).expect("Canonical JSON is always valid UTF-8")
+    }
+
+    /// Writes the value as Canonical JSON: no insignificant whitespace, keys
+    /// sorted by code point, and strings escaped with the minimal escape set.
+    fn write_canonical<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            ValueRef::Null => writer.write_all(b"null"),
+            ValueRef::Boolean(true) => writer.write_all(b"true"),
+            ValueRef::Boolean(false) => writer.write_all(b"false"),
+            ValueRef::Number(v) => write!(writer, "{}", v),
+            ValueRef::String(v) => write_canonical_str(writer, v),
+            ValueRef::List(list) => {
+                writer.write_all(b"[")?;
+                for (index, value) in list.iter().enumerate() {
+                    if index != 0 {
+                        writer.write_all(b",")?;
                     }
+                    value.write_canonical(writer)?;
                 }
-                _ => continue,
+                writer.write_all(b"]")
+            }
+            ValueRef::Map(vecmap) => {
+                writer.write_all(b"{")?;
+                for (index, (key, value)) in vecmap.iter().enumerate() {
+                    if index != 0 {
+                        writer.write_all(b",")?;
+                    }
+                    write_canonical_str(writer, key)?;
+                    writer.write_all(b":")?;
+                    value.write_canonical(writer)?;
+                }
+                writer.write_all(b"}")
+            }
+        }
+    }
+}
+
+/// Writes a JSON string, escaping only what the spec requires.
+///
+/// Quotes and backslashes are backslash-escaped, control characters use their
+/// short form where one exists and `\u00XX` otherwise, and everything else
+/// (including `/` and non-ASCII characters) is written as raw UTF-8.
+fn write_canonical_str<W: Write>(writer: &mut W, string: &str) -> std::io::Result<()> {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    writer.write_all(b"\"")?;
+
+    let bytes = string.as_bytes();
+    let mut start = 0;
+
+    for (index, &byte) in bytes.iter().enumerate() {
+        let long_escape;
+        let escape: &[u8] = match byte {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\x08' => b"\\b",
+            b'\x0c' => b"\\f",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x00..=0x1f => {
+                long_escape = [
+                    b'\\',
+                    b'u',
+                    b'0',
+                    b'0',
+                    HEX_DIGITS[(byte >> 4) as usize],
+                    HEX_DIGITS[(byte & 0xf) as usize],
+                ];
+                &long_escape
             }
+            _ => continue,
+        };
+
+        writer.write_all(&bytes[start..index])?;
+        writer.write_all(escape)?;
+        start = index + 1;
+    }
+
+    writer.write_all(&bytes[start..])?;
+    writer.write_all(b"\"")
+}
+
+/// Writes the canonical JSON of any `Serialize` value straight into `writer`,
+/// leaving out the given top-level keys.
+///
+/// Only the entries of maps and structs are buffered, since they have to be
+/// sorted by key before they can be written.
+pub(crate) fn write_canonical_serialize<T, W>(
+    value: &T,
+    removed_keys: &[&str],
+    writer: &mut W,
+) -> Result<(), String>
+where
+    T: Serialize + ?Sized,
+    W: Write,
+{
+    value
+        .serialize(CanonicalSerializer {
+            writer,
+            removed_keys,
+        })
+        .map_err(|err| format!("Could not write canonical JSON: {}", err))
+}
+
+/// The struct name serde_json's `RawValue` serializes itself as, with the JSON
+/// text as its only field.
+const RAW_VALUE_TOKEN: &str = "$serde_json::private::RawValue";
+
+#[derive(Debug)]
+struct CanonicalError(String);
+
+impl std::fmt::Display for CanonicalError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CanonicalError {}
+
+impl serde::ser::Error for CanonicalError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        CanonicalError(msg.to_string())
+    }
+}
+
+impl From<std::io::Error> for CanonicalError {
+    fn from(err: std::io::Error) -> Self {
+        CanonicalError(err.to_string())
+    }
+}
+
+struct CanonicalSerializer<'w, 'k, W> {
+    writer: &'w mut W,
+    /// Only ever non-empty for the outermost value.
+    removed_keys: &'k [&'k str],
+}
+
+impl<'w, 'k, W: Write> CanonicalSerializer<'w, 'k, W> {
+    fn write_integer(self, v: i64) -> Result<(), CanonicalError> {
+        if !(-2i64.pow(53) + 1..=2i64.pow(53) - 1).contains(&v) {
+            return Err(CanonicalError(format!(
+                "Integers must be between -(2**53)+1 and (2**53)-1: got {} instead",
+                v
+            )));
         }
 
-        None
+        Ok(write!(self.writer, "{}", v)?)
+    }
+
+    fn into_map(self, suffix: &'static [u8]) -> MapWriter<'w, 'k, W> {
+        MapWriter {
+            writer: self.writer,
+            removed_keys: self.removed_keys,
+            entries: Vec::new(),
+            next_key: None,
+            raw: false,
+            suffix,
+        }
+    }
+
+    fn into_seq(self, closing: &'static [u8]) -> Result<SeqWriter<'w, W>, CanonicalError> {
+        self.writer.write_all(b"[")?;
+
+        Ok(SeqWriter {
+            writer: self.writer,
+            first: true,
+            closing,
+        })
+    }
+
+    /// Starts the single-entry object an enum variant with data is written as.
+    fn write_variant_key(&mut self, variant: &str) -> Result<(), CanonicalError> {
+        self.writer.write_all(b"{")?;
+        write_canonical_str(self.writer, variant)?;
+        Ok(self.writer.write_all(b":")?)
+    }
+}
+
+fn nested<W>(writer: &mut W) -> CanonicalSerializer<'_, 'static, W> {
+    CanonicalSerializer {
+        writer,
+        removed_keys: &[],
+    }
+}
+
+impl<'w, 'k, W: Write> serde::Serializer for CanonicalSerializer<'w, 'k, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    type SerializeSeq = SeqWriter<'w, W>;
+    type SerializeTuple = SeqWriter<'w, W>;
+    type SerializeTupleStruct = SeqWriter<'w, W>;
+    type SerializeTupleVariant = SeqWriter<'w, W>;
+    type SerializeMap = MapWriter<'w, 'k, W>;
+    type SerializeStruct = MapWriter<'w, 'k, W>;
+    type SerializeStructVariant = MapWriter<'w, 'k, W>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), CanonicalError> {
+        let literal: &[u8] = if v { b"true" } else { b"false" };
+        Ok(self.writer.write_all(literal)?)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), CanonicalError> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), CanonicalError> {
+        self.write_integer(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), CanonicalError> {
+        self.serialize_u128(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), CanonicalError> {
+        let v = i64::try_from(v).map_err(|_err| {
+            CanonicalError(format!(
+                "Integers must be between -(2**53)+1 and (2**53)-1: got {} instead",
+                v
+            ))
+        })?;
+        self.write_integer(v)
+    }
+
+    // Timestamps are kept as milliseconds in a u128
+    fn serialize_u128(self, v: u128) -> Result<(), CanonicalError> {
+        self.serialize_i128(i128::try_from(v).unwrap_or(i128::MAX))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), CanonicalError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), CanonicalError> {
+        // Same as when parsing: whole numbers are fine, whatever their type
+        if v.fract() != 0.0 || !v.is_finite() {
+            return Err(CanonicalError(
+                "Floating point values are not allowed in Canonical JSON".to_string(),
+            ));
+        }
+        if v.abs() > (2i64.pow(53) - 1) as f64 {
+            return Err(CanonicalError(format!(
+                "Integers must be between -(2**53)+1 and (2**53)-1: got {} instead",
+                v
+            )));
+        }
+
+        self.write_integer(v as i64)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), CanonicalError> {
+        Ok(write_canonical_str(
+            self.writer,
+            v.encode_utf8(&mut [0; 4]),
+        )?)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), CanonicalError> {
+        Ok(write_canonical_str(self.writer, v)?)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), CanonicalError> {
+        // Like serde_json, as a list of numbers
+        let mut seq = self.into_seq(b"]")?;
+        for byte in v {
+            SerializeSeq::serialize_element(&mut seq, byte)?;
+        }
+        SerializeSeq::end(seq)
+    }
+
+    fn serialize_none(self) -> Result<(), CanonicalError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), CanonicalError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), CanonicalError> {
+        Ok(self.writer.write_all(b"null")?)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), CanonicalError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), CanonicalError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), CanonicalError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), CanonicalError> {
+        self.write_variant_key(variant)?;
+        value.serialize(nested(&mut *self.writer))?;
+        Ok(self.writer.write_all(b"}")?)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqWriter<'w, W>, CanonicalError> {
+        self.into_seq(b"]")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SeqWriter<'w, W>, CanonicalError> {
+        self.into_seq(b"]")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<SeqWriter<'w, W>, CanonicalError> {
+        self.into_seq(b"]")
+    }
+
+    fn serialize_tuple_variant(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SeqWriter<'w, W>, CanonicalError> {
+        self.write_variant_key(variant)?;
+        self.into_seq(b"]}")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapWriter<'w, 'k, W>, CanonicalError> {
+        Ok(self.into_map(b""))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<MapWriter<'w, 'k, W>, CanonicalError> {
+        let mut map = self.into_map(b"");
+        map.raw = name == RAW_VALUE_TOKEN;
+
+        Ok(map)
+    }
+
+    fn serialize_struct_variant(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapWriter<'w, 'k, W>, CanonicalError> {
+        self.write_variant_key(variant)?;
+        Ok(nested(self.writer).into_map(b"}"))
+    }
+}
+
+struct SeqWriter<'w, W> {
+    writer: &'w mut W,
+    first: bool,
+    closing: &'static [u8],
+}
+
+impl<'w, W: Write> SerializeSeq for SeqWriter<'w, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if !self.first {
+            self.writer.write_all(b",")?;
+        }
+        self.first = false;
+
+        value.serialize(nested(&mut *self.writer))
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        Ok(self.writer.write_all(self.closing)?)
+    }
+}
+
+impl<'w, W: Write> serde::ser::SerializeTuple for SeqWriter<'w, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl<'w, W: Write> serde::ser::SerializeTupleStruct for SeqWriter<'w, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl<'w, W: Write> serde::ser::SerializeTupleVariant for SeqWriter<'w, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        SerializeSeq::end(self)
+    }
+}
+
+/// Collects the entries of a map or struct, each already written out
+/// canonically, until they can be sorted.
+struct MapWriter<'w, 'k, W> {
+    writer: &'w mut W,
+    removed_keys: &'k [&'k str],
+    entries: Vec<(String, Vec<u8>)>,
+    next_key: Option<String>,
+    /// Whether this is a `RawValue`, which gets written as soon as its text
+    /// comes in.
+    raw: bool,
+    /// Closes the object of an enum variant around this one, if any.
+    suffix: &'static [u8],
+}
+
+impl<'w, 'k, W: Write> MapWriter<'w, 'k, W> {
+    fn add_entry<T: Serialize + ?Sized>(
+        &mut self,
+        key: String,
+        value: &T,
+    ) -> Result<(), CanonicalError> {
+        if self.removed_keys.contains(&key.as_str()) {
+            return Ok(());
+        }
+
+        let mut bytes = Vec::new();
+        value.serialize(nested(&mut bytes))?;
+        self.entries.push((key, bytes));
+
+        Ok(())
+    }
+
+    /// Raw JSON is the one place where there is no way around parsing.
+    fn write_raw<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), CanonicalError> {
+        let json = match serde_json::to_value(value) {
+            Ok(serde_json::Value::String(json)) => json,
+            _ => return Err(CanonicalError("Expected raw JSON text".to_string())),
+        };
+
+        let mut value: ValueRef = serde_json::from_str(&json)
+            .map_err(|err| CanonicalError(format!("Could not parse raw JSON: {}", err)))?;
+        for key in self.removed_keys {
+            value.pop_from_map(key);
+        }
+
+        Ok(value.write_canonical(self.writer)?)
+    }
+}
+
+impl<'w, 'k, W: Write> SerializeMap for MapWriter<'w, 'k, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        let key = match serde_json::to_value(key) {
+            Ok(serde_json::Value::String(key)) => key,
+            // serde_json quotes integer keys, so do the same
+            Ok(serde_json::Value::Number(key)) => key.to_string(),
+            _ => return Err(CanonicalError("Map keys must be strings".to_string())),
+        };
+        self.next_key = Some(key);
+
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| CanonicalError("Map value without a key".to_string()))?;
+
+        self.add_entry(key, value)
+    }
+
+    fn end(mut self) -> Result<(), CanonicalError> {
+        if self.raw {
+            return Ok(());
+        }
+
+        // Comparing UTF-8 bytes is the same as comparing Unicode code points.
+        self.entries
+            .sort_unstable_by(|(key1, _value1), (key2, _value2)| key1.cmp(key2));
+
+        if let Some(pair) = self.entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(CanonicalError(format!(
+                "Duplicate keys are not allowed in Canonical JSON: {}",
+                pair[0].0
+            )));
+        }
+
+        self.writer.write_all(b"{")?;
+        for (index, (key, value)) in self.entries.iter().enumerate() {
+            if index != 0 {
+                self.writer.write_all(b",")?;
+            }
+            write_canonical_str(self.writer, key)?;
+            self.writer.write_all(b":")?;
+            self.writer.write_all(value)?;
+        }
+        self.writer.write_all(b"}")?;
+
+        Ok(self.writer.write_all(self.suffix)?)
+    }
+}
+
+impl<'w, 'k, W: Write> serde::ser::SerializeStruct for MapWriter<'w, 'k, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        if self.raw {
+            return self.write_raw(value);
+        }
+
+        self.add_entry(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        SerializeMap::end(self)
+    }
+}
+
+impl<'w, 'k, W: Write> serde::ser::SerializeStructVariant for MapWriter<'w, 'k, W> {
+    type Ok = ();
+    type Error = CanonicalError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.add_entry(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<(), CanonicalError> {
+        SerializeMap::end(self)
     }
 }
 
@@ -168,24 +788,55 @@ impl<'de> Visitor<'de> for ValueRefVisitor {
         }
     }
 
-    fn visit_f64<E>(self, _v: f64) -> Result<Self::Value, E>
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        Err(E::custom(
-            "Floating point values are not allowed in Canonical JSON",
-        ))
+        // Exponent notation (e.g. 1e10) still parses as a float, but the spec
+        // treats it as an integer as long as it has no fractional part.
+        if v.fract() != 0.0 || !v.is_finite() {
+            return Err(E::custom(
+                "Floating point values are not allowed in Canonical JSON",
+            ));
+        }
+
+        if v.abs() > (2i64.pow(53) - 1) as f64 {
+            return Err(E::custom(format!(
+                "Integers must be between -(2**53)+1 and (2**53)-1: got {} instead",
+                v
+            )));
+        }
+
+        Ok(ValueRef::Number(v as i64))
     }
 
     fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        // FIXME: bytes
-        // FIXME: encodings
+        // Note: serde_json already rejects invalid UTF-8 and unpaired
+        // surrogates in \u escapes, so any string that gets here is valid.
         Ok(ValueRef::String(Cow::Borrowed(v)))
     }
 
+    fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        let v = std::str::from_utf8(v)
+            .map_err(|err| E::custom(format!("Strings must be valid UTF-8: {}", err)))?;
+        self.visit_borrowed_str(v)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        let v = std::str::from_utf8(v)
+            .map_err(|err| E::custom(format!("Strings must be valid UTF-8: {}", err)))?;
+        self.visit_str(v)
+    }
+
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
@@ -218,19 +869,22 @@ impl<'de> Visitor<'de> for ValueRefVisitor {
     {
         let mut vecmap = Vec::new();
         while let Some((key, value)) = map.next_entry()? {
+            let key = match key {
+                ValueRef::String(key) => key,
+                _ => return Err(serde::de::Error::custom("Map keys must be strings")),
+            };
             vecmap.push((key, value));
         }
-        vecmap.sort_unstable_by(|(key1, _value1), (key2, _value2)| {
-            let key1 = match key1 {
-                ValueRef::String(ref v) => v,
-                _ => "",
-            };
-            let key2 = match key2 {
-                ValueRef::String(ref v) => v,
-                _ => "",
-            };
-            key1.cmp(key2)
-        });
+
+        // Comparing UTF-8 bytes is the same as comparing Unicode code points.
+        vecmap.sort_unstable_by(|(key1, _value1), (key2, _value2)| key1.cmp(key2));
+
+        if let Some(pair) = vecmap.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(serde::de::Error::custom(format!(
+                "Duplicate keys are not allowed in Canonical JSON: {}",
+                pair[0].0
+            )));
+        }
 
         Ok(ValueRef::Map(vecmap))
     }
@@ -270,10 +924,181 @@ impl<'de> Serialize for ValueRef<'de> {
             ValueRef::Map(vecmap) => {
                 let mut map = serializer.serialize_map(Some(vecmap.len()))?;
                 for (key, value) in vecmap {
-                    map.serialize_entry(key, value)?;
+                    map.serialize_entry(&**key, value)?;
                 }
                 map.end()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical(json: &str) -> Result<String, String> {
+        serde_json::from_str::<ValueRef>(json)
+            .map(|value| value.to_canonical_string())
+            .map_err(|err| err.to_string())
+    }
+
+    // The examples from the spec's appendix on Canonical JSON
+    #[test]
+    fn spec_examples() {
+        let examples = [
+            (r#"{}"#, r#"{}"#),
+            (r#"{"one": 1, "two": "Two"}"#, r#"{"one":1,"two":"Two"}"#),
+            (r#"{"b": "2", "a": "1"}"#, r#"{"a":"1","b":"2"}"#),
+            (r#"{"b":"2","a":"1"}"#, r#"{"a":"1","b":"2"}"#),
+            (
+                r#"{
+                    "auth": {
+                        "success": true,
+                        "mxid": "@john.doe:example.com",
+                        "profile": {
+                            "display_name": "John Doe",
+                            "three_pids": [
+                                {
+                                    "medium": "email",
+                                    "address": "john.doe@example.org"
+                                },
+                                {
+                                    "medium": "msisdn",
+                                    "address": "123456789"
+                                }
+                            ]
+                        }
+                    }
+                }"#,
+                r#"{"auth":{"mxid":"@john.doe:example.com","profile":{"display_name":"John Doe","three_pids":[{"address":"john.doe@example.org","medium":"email"},{"address":"123456789","medium":"msisdn"}]},"success":true}}"#,
+            ),
+            (r#"{"a": "日本語"}"#, r#"{"a":"日本語"}"#),
+            (r#"{"本": 2, "日": 1}"#, r#"{"日":1,"本":2}"#),
+            (r#"{"a": "日"}"#, r#"{"a":"日"}"#),
+            (r#"{"a": null}"#, r#"{"a":null}"#),
+            (r#"{"a": -0, "b": 1e10}"#, r#"{"a":0,"b":10000000000}"#),
+        ];
+
+        for (input, expected) in examples {
+            assert_eq!(canonical(input).as_deref(), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn keys_sort_by_code_point() {
+        assert_eq!(
+            canonical(r#"{"b": 1, "B": 2, "é": 3, "a": 4, "😀": 5}"#).as_deref(),
+            Ok("{\"B\":2,\"a\":4,\"b\":1,\"é\":3,\"😀\":5}")
+        );
+    }
+
+    #[test]
+    fn strings_use_minimal_escapes() {
+        assert_eq!(
+            canonical(r#"["\"\\\/", "\b\f\n\r\t", "\u0001\u001f", "\u007f\u2028"]"#).as_deref(),
+            Ok("[\"\\\"\\\\/\",\"\\b\\f\\n\\r\\t\",\"\\u0001\\u001f\",\"\u{7f}\u{2028}\"]")
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        assert!(canonical(r#"{"a": 1, "a": 2}"#).is_err());
+        assert!(canonical(r#"{"b": {"a": 1, "a": 1}}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_floats_and_out_of_range_integers() {
+        assert!(canonical(r#"{"a": 1.5}"#).is_err());
+        assert!(canonical(r#"{"a": 1e-1}"#).is_err());
+        assert!(canonical(r#"{"a": 9007199254740992}"#).is_err());
+        assert!(canonical(r#"{"a": -9007199254740992}"#).is_err());
+        assert_eq!(
+            canonical(r#"[9007199254740991, -9007199254740991, 1.0]"#).as_deref(),
+            Ok("[9007199254740991,-9007199254740991,1]")
+        );
+    }
+
+    #[test]
+    fn signable_json_leaves_out_signatures_and_unsigned() {
+        let signed = r#"{"b": 1, "signatures": {"x": {}}, "unsigned": {"age": 5}, "a": 2}"#;
+
+        assert_eq!(
+            signable_canonical_json(signed).as_deref(),
+            Ok(br#"{"a":2,"b":1}"#.as_slice())
+        );
+    }
+
+    fn canonical_serialize<T: Serialize>(
+        value: &T,
+        removed_keys: &[&str],
+    ) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        write_canonical_serialize(value, removed_keys, &mut bytes)?;
+
+        Ok(String::from_utf8(bytes).expect("Canonical JSON is always valid UTF-8"))
+    }
+
+    #[test]
+    fn serializing_matches_parsing() {
+        #[derive(Serialize)]
+        enum Tagged {
+            Unit,
+            Newtype(u8),
+            Tuple(u8, u8),
+            Struct { b: bool, a: char },
+        }
+
+        #[derive(Serialize)]
+        struct Signed<'a> {
+            zebra: Vec<Option<&'a str>>,
+            apple: std::collections::HashMap<&'a str, i64>,
+            content: &'a serde_json::value::RawValue,
+            signatures: (),
+            tagged: Vec<Tagged>,
+            whole_float: f64,
+            timestamp: u128,
+        }
+
+        let content = serde_json::from_str(r#"{"y": [1, "\u00e9"], "x": null}"#).unwrap();
+        let signed = Signed {
+            zebra: vec![Some("\"\n/"), None],
+            apple: [("b", -1), ("é", 2), ("a", 9007199254740991)]
+                .into_iter()
+                .collect(),
+            content,
+            signatures: (),
+            tagged: vec![
+                Tagged::Unit,
+                Tagged::Newtype(1),
+                Tagged::Tuple(2, 3),
+                Tagged::Struct { b: true, a: 'z' },
+            ],
+            whole_float: 2.0,
+            timestamp: 1_700_000_000_000,
+        };
+
+        let json_blob = serde_json::to_string(&signed).unwrap();
+        let mut parsed = Vec::new();
+        write_canonical_json_without(&json_blob, &["signatures"], &mut parsed).unwrap();
+
+        assert_eq!(
+            canonical_serialize(&signed, &["signatures"]).map(String::into_bytes),
+            Ok(parsed)
+        );
+    }
+
+    #[test]
+    fn serializing_rejects_what_parsing_rejects() {
+        assert!(canonical_serialize(&[1.5], &[]).is_err());
+        assert!(canonical_serialize(&[9007199254740992u64], &[]).is_err());
+        assert!(canonical_serialize(&[-9007199254740992i64], &[]).is_err());
+
+        #[derive(Serialize)]
+        struct Duplicate {
+            a: u8,
+            #[serde(rename = "a")]
+            also_a: u8,
+        }
+        assert!(canonical_serialize(&Duplicate { a: 1, also_a: 2 }, &[]).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    matrix_types::{Id, ServerName},
+    room_store::RoomStoreKind,
+};
+
+/// Settings loaded from `config.json` in the data directory.
+///
+/// Every field has a default, so the file (and any of its keys) is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub server_name: Box<Id<ServerName>>,
+    /// Servers to ask for other servers' keys when they cannot be reached
+    /// directly (`trusted_key_servers` in Synapse's terms).
+    pub notary_servers: Vec<Box<Id<ServerName>>>,
+    /// How rooms are stored: `files` (default), `memory` or `sled`.
+    pub room_store: RoomStoreKind,
+    /// Memory use (in MiB) above which the least recently used rooms get
+    /// evicted from memory; unlimited if not set.
+    pub memory_budget_mb: Option<usize>,
+    /// Where to reach some servers (as `host:port`), instead of looking them
+    /// up; e.g. for federating with other instances on the same machine.
+    pub federation_addresses: BTreeMap<Box<Id<ServerName>>, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_name: Id::try_boxed_from_str("fluctlight-dev.demi.ro")
+                .expect("Default server name is valid"),
+            notary_servers: Vec::new(),
+            room_store: RoomStoreKind::default(),
+            memory_budget_mb: None,
+            federation_addresses: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load(data_dir: &Path) -> Result<Self, String> {
+        let config_path = data_dir.join("config.json");
+
+        if !config_path.exists() {
+            eprintln!("No config.json found, using defaults...");
+            return Ok(Config::default());
+        }
+
+        let config_file = std::fs::File::open(&config_path)
+            .map_err(|err| format!("Could not open {}: {}", config_path.display(), err))?;
+
+        serde_json::from_reader(std::io::BufReader::new(config_file))
+            .map_err(|err| format!("Could not parse {}: {}", config_path.display(), err))
+    }
+}
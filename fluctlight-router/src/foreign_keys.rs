@@ -0,0 +1,534 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ed25519_compact::PublicKey;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::{
+    canonical_hash::signable_canonical_json,
+    matrix_types::{Id, Key, ServerName},
+    persistence::sync_directory,
+    rendered_json::RenderedJson,
+    server_keys::ServerKeys,
+    signed_request::federation_url,
+    signing::{decode_public_key, sign_bytes, verify_bytes},
+    state::{State, TimeStamp},
+};
+
+// Don't hammer unreachable servers; one attempt per server every 5 minutes.
+const FETCH_RETRY_INTERVAL: u128 = 1000 * 60 * 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+type KeyId = (Box<Id<ServerName>>, Box<Id<Key>>);
+type VerifiedServerKeys = (ServerKeys, RenderedJson<'static, ServerKeys>);
+
+/// Public keys of other servers, fetched either from the servers themselves
+/// or from notaries, and persisted in `foreign_keys.json`.
+#[derive(Default)]
+pub(crate) struct ForeignKeyCache {
+    server_keys: BTreeMap<Box<Id<ServerName>>, Vec<CachedServerKeys>>,
+    public_keys: BTreeMap<KeyId, CachedKey>,
+    last_fetch_attempts: BTreeMap<Box<Id<ServerName>>, TimeStamp>,
+    path: PathBuf,
+}
+
+/// A server's key response, kept exactly as received so that it can be
+/// served again (with its original signatures) to other servers.
+pub(crate) struct CachedServerKeys {
+    pub server_keys: ServerKeys,
+    pub rendered: RenderedJson<'static, ServerKeys>,
+}
+
+#[derive(Clone, Copy)]
+struct CachedKey {
+    public_key: PublicKey,
+    // For old keys, this is their `expired_ts` instead.
+    valid_until_ts: TimeStamp,
+}
+
+impl ForeignKeyCache {
+    pub(crate) fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let mut cache = ForeignKeyCache {
+            path: data_dir.join("foreign_keys.json"),
+            ..ForeignKeyCache::default()
+        };
+
+        if !cache.path.exists() {
+            return Ok(cache);
+        }
+
+        let key_file = std::io::BufReader::new(std::fs::File::open(&cache.path)?);
+        let foreign_keys: BTreeMap<Box<Id<ServerName>>, Vec<Box<RawValue>>> =
+            serde_json::from_reader(key_file)?;
+
+        for (server_name, server_keys_list) in foreign_keys {
+            for server_keys_json in server_keys_list {
+                match verify_server_keys(server_keys_json.get(), &server_name) {
+                    Ok(server_keys) => {
+                        let rendered = RenderedJson::from_trusted(server_keys_json.to_string());
+                        cache.insert(server_keys, rendered);
+                    }
+                    Err(err) => {
+                        eprintln!("Dropping stored keys for {}: {}", server_name, err);
+                    }
+                }
+            }
+        }
+
+        Ok(cache)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let foreign_keys: BTreeMap<&Id<ServerName>, Vec<&RenderedJson<ServerKeys>>> = self
+            .server_keys
+            .iter()
+            .map(|(server_name, server_keys_list)| {
+                let rendered_list = server_keys_list
+                    .iter()
+                    .map(|cached| &cached.rendered)
+                    .collect();
+                (server_name.as_id(), rendered_list)
+            })
+            .collect();
+
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut file, &foreign_keys)?;
+        file.write_all(b"\n")?;
+
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        match self.path.parent() {
+            Some(data_dir) => sync_directory(data_dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds an already verified key response to the cache.
+    pub(crate) fn insert(
+        &mut self,
+        server_keys: ServerKeys,
+        rendered: RenderedJson<'static, ServerKeys>,
+    ) {
+        let server_name = server_keys.server_name.clone();
+        let valid_until_ts = server_keys.valid_until_ts.unwrap_or_else(TimeStamp::now);
+
+        let mut add_key = |key_name: &Id<Key>, key: &str, valid_until_ts: TimeStamp| {
+            let public_key = match decode_public_key(key) {
+                Ok(public_key) => public_key,
+                Err(err) => {
+                    eprintln!("Ignoring key {} of {}: {}", key_name, server_name, err);
+                    return;
+                }
+            };
+
+            let key_id = (server_name.clone(), key_name.to_owned());
+            let cached_key = self.public_keys.entry(key_id).or_insert(CachedKey {
+                public_key,
+                valid_until_ts,
+            });
+
+            if cached_key.public_key != public_key {
+                eprintln!("Warning: key {} of {} has changed", key_name, server_name);
+                cached_key.public_key = public_key;
+            }
+            cached_key.valid_until_ts = cached_key.valid_until_ts.max(valid_until_ts);
+        };
+
+        for (key_name, verify_key) in &server_keys.verify_keys {
+            add_key(key_name, &verify_key.key, valid_until_ts);
+        }
+
+        for (key_name, old_verify_key) in server_keys.old_verify_keys.iter().flatten() {
+            add_key(key_name, &old_verify_key.key, old_verify_key.expired_ts);
+        }
+
+        // Older responses are only worth keeping if they mention keys that the
+        // new one no longer does.
+        let server_keys_list = self.server_keys.entry(server_name).or_default();
+        server_keys_list.retain(|cached| !cached.is_superseded_by(&server_keys));
+        server_keys_list.push(CachedServerKeys {
+            server_keys,
+            rendered,
+        });
+    }
+
+    /// Returns the key if it is known to be valid at the given time.
+    pub(crate) fn get_public_key(
+        &self,
+        server_name: &Id<ServerName>,
+        key_name: &Id<Key>,
+        valid_at: TimeStamp,
+    ) -> Option<PublicKey> {
+        let cached_key = self
+            .public_keys
+            .get(&(server_name.to_owned(), key_name.to_owned()))?;
+
+        if cached_key.valid_until_ts < valid_at {
+            return None;
+        }
+
+        Some(cached_key.public_key)
+    }
+
+    pub(crate) fn server_keys(&self, server_name: &Id<ServerName>) -> &[CachedServerKeys] {
+        self.server_keys
+            .get(server_name)
+            .map(|server_keys_list| server_keys_list.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn should_fetch(&mut self, server_name: &Id<ServerName>) -> bool {
+        let now = TimeStamp::now();
+
+        if let Some(last_attempt) = self.last_fetch_attempts.get(server_name) {
+            if now.as_millis() < last_attempt.as_millis() + FETCH_RETRY_INTERVAL {
+                return false;
+            }
+        }
+
+        self.last_fetch_attempts.insert(server_name.to_owned(), now);
+        true
+    }
+}
+
+impl CachedServerKeys {
+    fn is_superseded_by(&self, newer: &ServerKeys) -> bool {
+        let newer_mentions = |key_name: &Id<Key>| {
+            newer.verify_keys.contains_key(key_name)
+                || newer
+                    .old_verify_keys
+                    .as_ref()
+                    .map(|old_keys| old_keys.contains_key(key_name))
+                    .unwrap_or(false)
+        };
+
+        let old_keys = self.server_keys.old_verify_keys.iter().flatten();
+
+        self.server_keys
+            .verify_keys
+            .keys()
+            .chain(old_keys.map(|(key_name, _)| key_name))
+            .all(|key_name| newer_mentions(key_name))
+    }
+}
+
+impl State {
+    /// Looks up a server's public key, fetching it over the network if it is
+    /// missing or not valid at the requested time.
+    ///
+    /// Fetching can take as long as the network timeouts, so this must not be
+    /// called while holding any state lock; see `cached_server_key` for that.
+    pub(crate) fn get_server_key(
+        &self,
+        server_name: &Id<ServerName>,
+        key_name: &Id<Key>,
+        valid_at: TimeStamp,
+    ) -> Option<PublicKey> {
+        if server_name == &*self.server_name {
+            return self.own_server_key(key_name, valid_at);
+        }
+
+        let public_key = self
+            .foreign_keys()
+            .get_public_key(server_name, key_name, valid_at);
+
+        if public_key.is_some() {
+            return public_key;
+        }
+
+        if !self.with_foreign_keys_mut(|cache| cache.should_fetch(server_name)) {
+            return None;
+        }
+
+        if let Err(err) = fetch_server_keys(self, server_name) {
+            eprintln!("Could not fetch keys for {}: {}", server_name, err);
+            return None;
+        }
+
+        self.foreign_keys()
+            .get_public_key(server_name, key_name, valid_at)
+    }
+
+    /// Like `get_server_key`, but only looks at what is already known, so
+    /// that it never waits on the network.
+    pub(crate) fn cached_server_key(
+        &self,
+        server_name: &Id<ServerName>,
+        key_name: &Id<Key>,
+        valid_at: TimeStamp,
+    ) -> Option<PublicKey> {
+        if server_name == &*self.server_name {
+            return self.own_server_key(key_name, valid_at);
+        }
+
+        self.foreign_keys()
+            .get_public_key(server_name, key_name, valid_at)
+    }
+}
+
+impl State {
+    /// Returns a server's cached key responses that are valid until at least
+    /// the given time, countersigned by us, as served by the notary endpoints.
+    ///
+    /// Stale responses cause the keys to be refetched from the origin first.
+    pub(crate) fn notary_server_keys(
+        &self,
+        server_name: &Id<ServerName>,
+        minimum_valid_until_ts: TimeStamp,
+    ) -> Vec<String> {
+        if server_name == &*self.server_name {
+            let rendered = self.render_own_server_keys();
+            if self.ephemeral().own_server_keys.valid_until_ts < Some(minimum_valid_until_ts) {
+                return Vec::new();
+            }
+            return vec![rendered.get().to_string()];
+        }
+
+        // Kept as the origin sent them, as re-serializing `ServerKeys` would
+        // drop any fields it doesn't know about and void the origin's
+        // signature
+        let find_valid_keys = || -> Vec<String> {
+            self.foreign_keys()
+                .server_keys(server_name)
+                .iter()
+                .filter(|cached| cached.server_keys.valid_until_ts >= Some(minimum_valid_until_ts))
+                .map(|cached| cached.rendered.as_str().to_owned())
+                .collect()
+        };
+
+        let mut server_keys_list = find_valid_keys();
+
+        if server_keys_list.is_empty()
+            && self.with_foreign_keys_mut(|cache| cache.should_fetch(server_name))
+        {
+            if let Err(err) = fetch_server_keys(self, server_name) {
+                eprintln!("Could not fetch keys for {}: {}", server_name, err);
+            }
+            server_keys_list = find_valid_keys();
+        }
+
+        server_keys_list
+            .iter()
+            .filter_map(|server_keys| match self.countersign(server_keys) {
+                Ok(countersigned) => Some(countersigned),
+                Err(err) => {
+                    eprintln!("Could not countersign keys for {}: {}", server_name, err);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Adds our signatures to a JSON object, leaving everything else in it
+    /// untouched.
+    fn countersign(&self, json: &str) -> Result<String, String> {
+        let signable_bytes = signable_canonical_json(json)?;
+        let signatures = sign_bytes(&signable_bytes, self.server_key_pairs().signing_keys());
+
+        let mut value: serde_json::Value =
+            serde_json::from_str(json).map_err(|err| err.to_string())?;
+        if !value.is_object() {
+            return Err("Not a JSON object".to_owned());
+        }
+
+        let own_signatures = &mut value["signatures"][self.server_name.as_str()];
+        for (key_name, signature) in signatures {
+            own_signatures[key_name.as_str()] = signature.into();
+        }
+
+        Ok(serde_json::to_string(&value).expect("Serialization should always succeed"))
+    }
+}
+
+// The keys stay cached in memory either way, so this is only worth a warning
+fn save_foreign_keys(cache: &ForeignKeyCache) {
+    if let Err(err) = cache.save() {
+        eprintln!("Could not save foreign keys: {}", err);
+    }
+}
+
+/// Fetches a server's keys directly, falling back to the configured notaries.
+pub(crate) fn fetch_server_keys(
+    state: &State,
+    server_name: &Id<ServerName>,
+) -> Result<(), Box<dyn Error>> {
+    let direct_error = match fetch_server_keys_directly(state, server_name) {
+        Ok((server_keys, rendered)) => {
+            state.with_foreign_keys_mut(|cache| {
+                cache.insert(server_keys, rendered);
+                save_foreign_keys(cache);
+            });
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+
+    eprintln!(
+        "Could not fetch keys directly from {}: {}",
+        server_name, direct_error
+    );
+
+    for notary in &state.config.notary_servers {
+        if &**notary == server_name {
+            continue;
+        }
+
+        match fetch_server_keys_from_notary(state, notary, server_name) {
+            Ok(server_keys_list) if !server_keys_list.is_empty() => {
+                state.with_foreign_keys_mut(|cache| {
+                    for (server_keys, rendered) in server_keys_list {
+                        cache.insert(server_keys, rendered);
+                    }
+                    save_foreign_keys(cache);
+                });
+                return Ok(());
+            }
+            Ok(_) => {
+                eprintln!("Notary {} had no keys for {}", notary, server_name);
+            }
+            Err(err) => {
+                eprintln!(
+                    "Could not fetch keys for {} from notary {}: {}",
+                    server_name, notary, err
+                );
+            }
+        }
+    }
+
+    Err(direct_error)
+}
+
+fn fetch_server_keys_directly(
+    state: &State,
+    server_name: &Id<ServerName>,
+) -> Result<VerifiedServerKeys, Box<dyn Error>> {
+    let url = federation_url(state, server_name.as_str(), "/_matrix/key/v2/server");
+    let response = ureq::get(&url)
+        .timeout(FETCH_TIMEOUT)
+        .call()?
+        .into_string()?;
+
+    let server_keys = verify_server_keys(&response, server_name)?;
+
+    Ok((server_keys, RenderedJson::from_trusted(response)))
+}
+
+#[derive(Deserialize)]
+struct NotaryResponse {
+    server_keys: Vec<Box<RawValue>>,
+}
+
+fn fetch_server_keys_from_notary(
+    state: &State,
+    notary: &Id<ServerName>,
+    server_name: &Id<ServerName>,
+) -> Result<Vec<VerifiedServerKeys>, Box<dyn Error>> {
+    let url = federation_url(state, notary.as_str(), "/_matrix/key/v2/query");
+    let request_body = serde_json::json!({
+        "server_keys": {
+            server_name.as_str(): {},
+        },
+    });
+
+    let response = ureq::post(&url)
+        .timeout(FETCH_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_bytes(&serde_json::to_vec(&request_body)?)?
+        .into_string()?;
+    let response: NotaryResponse = serde_json::from_str(&response)?;
+
+    let mut server_keys_list = Vec::new();
+
+    for server_keys_json in response.server_keys {
+        let server_keys_json = server_keys_json.get();
+        let server_keys = verify_server_keys(server_keys_json, server_name)?;
+        verify_notary_signature(state, server_keys_json, &server_keys, notary)?;
+
+        server_keys_list.push((
+            server_keys,
+            RenderedJson::from_trusted(server_keys_json.to_string()),
+        ));
+    }
+
+    Ok(server_keys_list)
+}
+
+/// Parses a key response and checks that every key in `verify_keys` signed it.
+fn verify_server_keys(
+    server_keys_json: &str,
+    server_name: &Id<ServerName>,
+) -> Result<ServerKeys, String> {
+    let server_keys: ServerKeys = serde_json::from_str(server_keys_json)
+        .map_err(|err| format!("Could not deserialize server keys: {}", err))?;
+
+    if &*server_keys.server_name != server_name {
+        return Err(format!(
+            "Expected keys for {}, got keys for {}",
+            server_name, server_keys.server_name
+        ));
+    }
+
+    if server_keys.verify_keys.is_empty() {
+        return Err("Server keys have no verify_keys".to_string());
+    }
+
+    let signable_bytes = signable_canonical_json(server_keys_json)?;
+
+    let server_signatures = server_keys
+        .signatures
+        .as_ref()
+        .and_then(|signatures| signatures.signatures.get(server_name))
+        .ok_or_else(|| "Server keys are not self-signed".to_string())?;
+
+    for (key_name, verify_key) in &server_keys.verify_keys {
+        let signature = server_signatures
+            .get(key_name)
+            .ok_or_else(|| format!("Server keys are not signed with {}", key_name))?;
+
+        let public_key = decode_public_key(&verify_key.key)?;
+
+        verify_bytes(&signable_bytes, &public_key, signature)
+            .map_err(|err| format!("Key {}: {}", key_name, err))?;
+    }
+
+    Ok(server_keys)
+}
+
+fn verify_notary_signature(
+    state: &State,
+    server_keys_json: &str,
+    server_keys: &ServerKeys,
+    notary: &Id<ServerName>,
+) -> Result<(), String> {
+    let signable_bytes = signable_canonical_json(server_keys_json)?;
+
+    let notary_signatures = server_keys
+        .signatures
+        .as_ref()
+        .and_then(|signatures| signatures.signatures.get(notary))
+        .ok_or_else(|| format!("Server keys are not signed by notary {}", notary))?;
+
+    for (key_name, signature) in notary_signatures {
+        let public_key = match state.get_server_key(notary, key_name, TimeStamp::now()) {
+            Some(public_key) => public_key,
+            None => continue,
+        };
+
+        if verify_bytes(&signable_bytes, &public_key, signature).is_ok() {
+            return Ok(());
+        }
+    }
+
+    Err(format!("No valid signature from notary {}", notary))
+}
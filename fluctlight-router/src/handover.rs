@@ -0,0 +1,329 @@
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    matrix_types::{Event, Id, Room, ServerName},
+    pdu_arc::PDUArc,
+    pdu_ref::parse_pdu_ref,
+    playground::ParsedPDU,
+    signing::VERIFY_ERRORS,
+    state::{EphemeralRoomState, State},
+};
+
+const SNAPSHOT_FORMAT: &str = "fluctlight-state";
+/// Bumped whenever the meaning of a snapshot changes, not just its shape;
+/// mismatching snapshots are ignored, and rooms get loaded from disk instead.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Checked on its own first, so that incompatible snapshots don't even get
+/// parsed.
+#[derive(Deserialize)]
+struct SnapshotHeader<'a> {
+    #[serde(borrow)]
+    format: Cow<'a, str>,
+    version: u32,
+}
+
+/// The in-memory state handed over from one module to the next on reload.
+///
+/// Only what is expensive to rebuild goes in here, i.e. the parsed and
+/// verified PDUs of resident rooms; everything else is reloaded from disk.
+#[derive(Serialize, Deserialize)]
+struct StateSnapshot<'a> {
+    #[serde(borrow)]
+    format: Cow<'a, str>,
+    version: u32,
+    rooms: Vec<RoomSnapshot<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoomSnapshot<'a> {
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+    idle_seconds: Option<u64>,
+    pdus: Vec<PDUSnapshot<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PDUSnapshot<'a> {
+    #[serde(borrow)]
+    event_id: &'a Id<Event>,
+    origin: Option<&'a Id<ServerName>>,
+    pdu_blob: &'a RawValue,
+    signature_check: Option<Result<(), Cow<'a, str>>>,
+    hash_check: Option<Result<(), Cow<'a, str>>>,
+}
+
+/// Serializes the resident rooms, for the next module to take over.
+pub(crate) fn snapshot_state(state: &State) -> Result<Vec<u8>, String> {
+    let ephemeral_state = state.ephemeral();
+
+    let rooms = ephemeral_state
+        .rooms
+        .iter()
+        .filter(|(_room_id, room)| room.resident)
+        .map(|(room_id, room)| RoomSnapshot {
+            room_id,
+            idle_seconds: room.idle_seconds(),
+            pdus: room
+                .pdus
+                .values()
+                .map(|parsed_pdu| PDUSnapshot {
+                    event_id: &parsed_pdu.event_id,
+                    origin: parsed_pdu.real_origin.as_ref().map(|origin| origin.as_id()),
+                    pdu_blob: &parsed_pdu.blob,
+                    signature_check: parsed_pdu
+                        .signature_check
+                        .map(|check| check.map_err(Cow::Borrowed)),
+                    hash_check: parsed_pdu
+                        .hash_check
+                        .as_ref()
+                        .map(|check| check.clone().map_err(Cow::Owned)),
+                })
+                .collect(),
+        })
+        .collect();
+
+    let snapshot = StateSnapshot {
+        format: Cow::Borrowed(SNAPSHOT_FORMAT),
+        version: SNAPSHOT_VERSION,
+        rooms,
+    };
+
+    serde_json::to_vec(&snapshot).map_err(|err| format!("Could not serialize state: {}", err))
+}
+
+/// Makes the rooms of a previous module's snapshot resident again, without
+/// reading or verifying anything.
+pub(crate) fn restore_snapshot(state: &State, snapshot: &[u8]) -> Result<(), String> {
+    let header: SnapshotHeader =
+        serde_json::from_slice(snapshot).map_err(|err| format!("Unreadable snapshot: {}", err))?;
+
+    if header.format != SNAPSHOT_FORMAT || header.version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "Incompatible snapshot: {} version {} (expected {} version {})",
+            header.format, header.version, SNAPSHOT_FORMAT, SNAPSHOT_VERSION
+        ));
+    }
+
+    let snapshot: StateSnapshot =
+        serde_json::from_slice(snapshot).map_err(|err| format!("Unreadable snapshot: {}", err))?;
+
+    for room_snapshot in snapshot.rooms {
+        let room_id = room_snapshot.room_id;
+        let allocated_before = crate::ALLOCATOR.allocated();
+
+        state.with_ephemeral_mut(|ephemeral_state| {
+            let room = ephemeral_state.rooms.entry(room_id.to_owned()).or_default();
+
+            if let Err(err) = restore_room(room, room_snapshot) {
+                // It still has everything on disk
+                eprintln!("Could not restore room {room_id}: {err}");
+                room.evict();
+                return;
+            }
+
+            room.resident_size = crate::ALLOCATOR
+                .allocated()
+                .saturating_sub(allocated_before);
+        });
+
+        eprintln!("Restored room from snapshot: {room_id}");
+    }
+
+    Ok(())
+}
+
+fn restore_room(room: &mut EphemeralRoomState, snapshot: RoomSnapshot) -> Result<(), String> {
+    let interner = &mut room.interner;
+
+    for pdu in snapshot.pdus {
+        let pdu_ref = parse_pdu_ref(pdu.pdu_blob).map_err(|err| err.to_string())?;
+        let pdu_arc = PDUArc::from_pdu_ref(&pdu_ref, interner);
+        let arc_event_id = interner.get_or_insert(pdu.event_id);
+        let real_origin = pdu.origin.map(|origin| interner.get_or_insert(origin));
+
+        let parsed_pdu = ParsedPDU {
+            event_id: pdu.event_id.to_owned(),
+            arc_event_id: Some(arc_event_id.clone()),
+            real_origin,
+            pdu: pdu_arc,
+            blob: pdu.pdu_blob.to_owned(),
+            signature_check: pdu
+                .signature_check
+                .map(|check| check.map_err(|err| static_verify_error(&err))),
+            hash_check: pdu.hash_check.map(|check| check.map_err(Cow::into_owned)),
+        };
+
+        room.pdus_by_timestamp
+            .insert(parsed_pdu.pdu.origin_server_ts, arc_event_id.clone());
+        room.pdus.insert(arc_event_id, parsed_pdu);
+    }
+
+    room.resident = true;
+    room.last_access = snapshot
+        .idle_seconds
+        .and_then(|idle_seconds| Instant::now().checked_sub(Duration::from_secs(idle_seconds)));
+
+    Ok(())
+}
+
+/// Signature checks only ever fail with one of a few static errors.
+fn static_verify_error(err: &str) -> &'static str {
+    VERIFY_ERRORS
+        .iter()
+        .find(|verify_error| **verify_error == err)
+        .copied()
+        .unwrap_or("Signature check failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// A data directory with just a config, unique to the test.
+    fn data_dir(test_name: &str) -> PathBuf {
+        let data_dir = std::env::temp_dir().join(format!(
+            "fluctlight-handover-{}-{}",
+            std::process::id(),
+            test_name
+        ));
+
+        if data_dir.exists() {
+            std::fs::remove_dir_all(&data_dir).unwrap();
+        }
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::write(
+            data_dir.join("config.json"),
+            r#"{"server_name":"handover.example"}"#,
+        )
+        .unwrap();
+
+        data_dir
+    }
+
+    fn message_pdu(body: &str, origin_server_ts: u64) -> serde_json::Value {
+        json!({
+            "auth_events": [],
+            "content": { "body": body, "msgtype": "m.text" },
+            "depth": 1,
+            "hashes": { "sha256": "not checked here" },
+            "origin": "a.example",
+            "origin_server_ts": origin_server_ts,
+            "prev_events": [],
+            "room_id": "!room:a.example",
+            "sender": "@alice:a.example",
+            "signatures": { "a.example": { "ed25519:1": "not checked either" } },
+            "type": "m.room.message",
+        })
+    }
+
+    /// Everything a snapshot is supposed to carry over, per event ID.
+    fn resident_pdus(state: &State, room_id: &str) -> Vec<String> {
+        let ephemeral_state = state.ephemeral();
+        let room = &ephemeral_state.rooms[Id::try_from_str(room_id).unwrap()];
+        assert!(room.resident);
+
+        room.pdus
+            .values()
+            .map(|parsed_pdu| {
+                format!(
+                    "{} {:?} {} {:?} {:?}",
+                    parsed_pdu.event_id,
+                    parsed_pdu.real_origin.as_ref().map(|origin| origin.as_id()),
+                    parsed_pdu.blob,
+                    parsed_pdu.signature_check,
+                    parsed_pdu.hash_check,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let data_dir = data_dir("snapshot_round_trips");
+        let snapshot = json!({
+            "format": SNAPSHOT_FORMAT,
+            "version": SNAPSHOT_VERSION,
+            "rooms": [{
+                "room_id": "!room:a.example",
+                "idle_seconds": 60,
+                "pdus": [
+                    {
+                        "event_id": "$verified",
+                        "origin": "b.example",
+                        "pdu_blob": message_pdu("Verified", 1),
+                        "signature_check": { "Ok": null },
+                        "hash_check": { "Ok": null },
+                    },
+                    {
+                        "event_id": "$rejected",
+                        "origin": null,
+                        "pdu_blob": message_pdu("Rejected", 2),
+                        "signature_check": { "Err": VERIFY_ERRORS[0] },
+                        "hash_check": { "Err": "Content hash mismatch" },
+                    },
+                    {
+                        "event_id": "$unchecked",
+                        "origin": null,
+                        "pdu_blob": message_pdu("Unchecked", 3),
+                        "signature_check": null,
+                        "hash_check": null,
+                    },
+                ],
+            }],
+        });
+
+        let state = State::new(&data_dir).unwrap();
+        restore_snapshot(&state, &serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        let pdus = resident_pdus(&state, "!room:a.example");
+        assert_eq!(pdus.len(), 3);
+
+        let next_snapshot = snapshot_state(&state).unwrap();
+        state.shutdown().unwrap();
+        drop(state);
+
+        let state = State::new(&data_dir).unwrap();
+        restore_snapshot(&state, &next_snapshot).unwrap();
+        assert_eq!(resident_pdus(&state, "!room:a.example"), pdus);
+
+        let ephemeral_state = state.ephemeral();
+        let room = &ephemeral_state.rooms[Id::try_from_str("!room:a.example").unwrap()];
+        assert!(matches!(room.idle_seconds(), Some(60..=61)));
+        assert_eq!(room.pdus_by_timestamp.len(), 3);
+
+        let verified = room
+            .pdus
+            .values()
+            .find(|parsed_pdu| parsed_pdu.event_id.as_str() == "$verified")
+            .unwrap();
+        let real_origin = verified.real_origin.as_ref().unwrap();
+        assert_eq!(real_origin.as_id().as_str(), "b.example");
+        assert_eq!(verified.signature_check, Some(Ok(())));
+    }
+
+    #[test]
+    fn incompatible_snapshot_is_refused() {
+        let data_dir = data_dir("incompatible_snapshot_is_refused");
+        let snapshot = json!({
+            "format": SNAPSHOT_FORMAT,
+            "version": SNAPSHOT_VERSION + 1,
+            "rooms": [{ "room_id": "!room:a.example", "idle_seconds": null, "pdus": [] }],
+        });
+
+        let state = State::new(&data_dir).unwrap();
+        let result = restore_snapshot(&state, &serde_json::to_vec(&snapshot).unwrap());
+
+        assert!(result.unwrap_err().starts_with("Incompatible snapshot"));
+        assert!(state.ephemeral().rooms.is_empty());
+    }
+}
@@ -1,9 +1,15 @@
-use std::panic::catch_unwind;
+use std::{panic::catch_unwind, path::Path};
 
-use fluctlight_mod_interface::{ModuleState, OpaqueModuleState, Request, ResponseResult, Response};
+use fluctlight_mod_interface::{
+    ModuleABI, ModuleState, OpaqueModuleState, RResult, RSlice, RString, RVec, Request,
+    RequestHead, Response, ResponseResult,
+};
 
 mod canonical_hash;
+mod config;
 mod edu_ref;
+mod foreign_keys;
+mod handover;
 mod interner;
 mod matrix_types;
 mod net_log;
@@ -13,18 +19,28 @@ mod persistence;
 mod playground;
 mod rendered_json;
 mod request;
+mod room_store;
 mod routes_admin;
 mod routes_federation;
 mod server_keys;
 mod signed_request;
+mod signing;
 mod state;
+mod transaction_log;
 
 use cap::Cap;
-use playground::load_persistent_rooms;
+use playground::open_persistent_rooms;
+use transaction_log::replay_pending_transactions;
 
 #[global_allocator]
 static ALLOCATOR: Cap<std::alloc::System> = Cap::new(std::alloc::System, usize::max_value());
 
+/// Checked by fluctlight-main before using any of the other symbols.
+#[no_mangle]
+pub extern "C" fn module_abi() -> ModuleABI {
+    ModuleABI::current()
+}
+
 #[no_mangle]
 pub extern "C" fn process_request<'a>(request: Request<'a>) -> ResponseResult {
     eprintln!("{} {}", request.method(), request.uri());
@@ -57,19 +73,139 @@ pub extern "C" fn process_request<'a>(request: Request<'a>) -> ResponseResult {
     Ok(response).into()
 }
 
+// TODO: Same as for destroy_state below.
 #[no_mangle]
-pub extern "C" fn create_state() -> OpaqueModuleState {
-    let state = Box::new(state::State::new());
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn check_request_head<'a>(head: RequestHead<'a>) -> RResult<u64, Response> {
+    let result = match catch_unwind(|| request::check_request_head(&head)) {
+        Ok(result) => result,
+        Err(_panic_payload) => Err(Response::new(
+            500,
+            "text/plain",
+            "Internal server error (request head check panicked)"
+                .as_bytes()
+                .into(),
+        )),
+    };
+
+    result.into()
+}
+
+// TODO: Same as for destroy_state below.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn self_test() -> RResult<(), RString> {
+    let result = catch_unwind(|| state::State::self_test(Path::new(".")))
+        .unwrap_or_else(|_panic_payload| Err("Module panicked while loading its state".to_owned()));
+
+    result.map_err(RString::from).into()
+}
+
+// TODO: Same as for destroy_state below.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn create_state() -> RResult<OpaqueModuleState, RString> {
+    try_new_module_state(Path::new("."), None)
+}
+
+/// Like `create_state`, but with all of the state's files (`config.json`
+/// included) in `data_dir` instead of the working directory, so that several
+/// servers can run in one process; only for linking the router in directly.
+pub fn create_state_in(data_dir: &Path) -> Result<OpaqueModuleState, String> {
+    new_module_state(data_dir, None)
+}
+
+// TODO: Same as for destroy_state below.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn create_state_from_snapshot(
+    snapshot: RSlice<'_, u8>,
+) -> RResult<OpaqueModuleState, RString> {
+    try_new_module_state(Path::new("."), Some(snapshot.as_slice()))
+}
+
+/// Keeps panics (e.g. from a room store that is still locked) from crossing
+/// the FFI boundary.
+fn try_new_module_state(
+    data_dir: &Path,
+    snapshot: Option<&[u8]>,
+) -> RResult<OpaqueModuleState, RString> {
+    let result = match catch_unwind(|| new_module_state(data_dir, snapshot)) {
+        Ok(result) => result,
+        Err(_panic_payload) => Err("Module panicked while creating its state".to_owned()),
+    };
+
+    result.map_err(RString::from).into()
+}
+
+fn new_module_state(data_dir: &Path, snapshot: Option<&[u8]>) -> Result<OpaqueModuleState, String> {
+    let state = Box::new(state::State::new(data_dir)?);
 
     // println!("Usage before: {}MB", ALLOCATOR.allocated() / 1024 / 1024);
     // load_room(&state).expect("Could not load state.");
     // println!("Usage after: {}MB", ALLOCATOR.allocated() / 1024 / 1024);
 
-    load_persistent_rooms(&state);
+    open_persistent_rooms(&state)?;
+
+    if let Some(snapshot) = snapshot {
+        if let Err(err) = handover::restore_snapshot(&state, snapshot) {
+            eprintln!(
+                "Not using state snapshot, rooms will load from disk: {}",
+                err
+            );
+        }
+    }
+
+    replay_pending_transactions(&state);
 
     let module_state = ModuleState { state };
 
-    module_state.into_opaque()
+    Ok(module_state.into_opaque())
+}
+
+// TODO: Same as for destroy_state below.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn snapshot_state(module_state: &OpaqueModuleState) -> RVec<u8> {
+    let result = catch_unwind(|| {
+        let state = ModuleState::as_inner(module_state)
+            .state
+            .downcast_ref::<state::State>()
+            .expect("Unexpected kind of module state.");
+
+        handover::snapshot_state(state)
+    });
+
+    match result {
+        Ok(Ok(snapshot)) => snapshot.into(),
+        Ok(Err(err)) => {
+            eprintln!("{}", err);
+            RVec::new()
+        }
+        Err(_panic_payload) => {
+            eprintln!("Failed to snapshot module state");
+            RVec::new()
+        }
+    }
+}
+
+// TODO: Same as for destroy_state below.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn shutdown(module_state: &OpaqueModuleState) -> RResult<(), RString> {
+    let result = catch_unwind(|| {
+        let state = ModuleState::as_inner(module_state)
+            .state
+            .downcast_ref::<state::State>()
+            .expect("Unexpected kind of module state.");
+
+        state.shutdown()
+    });
+
+    let result = result
+        .unwrap_or_else(|_panic_payload| Err("Module panicked while shutting down".to_owned()));
+
+    result.map_err(RString::from).into()
 }
 
 // TODO: improper_ctypes_definitions complains about the () from RBox<()>, which
@@ -21,13 +21,19 @@ struct ResponseEntry<'a> {
     status_code: u16,
 }
 
-pub(crate) fn log_network_request(log_index: usize, request: &Request<&[u8]>, direction: &str) {
-    if !Path::new("net_log").is_dir() {
+pub(crate) fn log_network_request(
+    data_dir: &Path,
+    log_index: usize,
+    request: &Request<&[u8]>,
+    direction: &str,
+) {
+    let net_log_dir = data_dir.join("net_log");
+    if !net_log_dir.is_dir() {
         // FIXME: handle errors
-        std::fs::create_dir("net_log").unwrap();
+        std::fs::create_dir(&net_log_dir).unwrap();
     }
 
-    let file_name = format!("net_log/net.{log_index:08}.request_{direction}.json");
+    let file_name = net_log_dir.join(format!("net.{log_index:08}.request_{direction}.json"));
     let file = std::fs::File::create(&file_name).unwrap();
 
     let json = serde_json::from_slice(request.body()).ok();
@@ -50,16 +56,18 @@ pub(crate) fn log_network_request(log_index: usize, request: &Request<&[u8]>, di
 }
 
 pub(crate) fn log_network_response(
+    data_dir: &Path,
     log_index: usize,
     response: &Response<Vec<u8>>,
     direction: &str,
 ) {
-    if !Path::new("net_log").is_dir() {
+    let net_log_dir = data_dir.join("net_log");
+    if !net_log_dir.is_dir() {
         // FIXME: handle errors
-        std::fs::create_dir("net_log").unwrap();
+        std::fs::create_dir(&net_log_dir).unwrap();
     }
 
-    let file_name = format!("net_log/net.{log_index:08}.response_{direction}.json");
+    let file_name = net_log_dir.join(format!("net.{log_index:08}.response_{direction}.json"));
     let file = std::fs::File::create(&file_name).unwrap();
 
     let json = serde_json::from_slice(response.body()).ok();
@@ -245,6 +245,16 @@ pub(crate) enum AnyState {
 }
 
 impl AnyState {
+    /// The state key as sent, or `None` for events that aren't state.
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            AnyState::UserId(user_id) => Some(user_id.as_str()),
+            AnyState::ServerName(server_name) => Some(server_name.as_str()),
+            AnyState::Empty(EmptyStateKey) => Some(""),
+            AnyState::Other(other) => other.as_deref(),
+        }
+    }
+
     fn from_ref(state_ref: &AnyStateRef, interner: &mut Interner) -> Self {
         match state_ref {
             AnyStateRef::UserId(user_id) => {
@@ -7,7 +7,7 @@ use vec_collections::VecMap1;
 
 use crate::{
     matrix_types::{Event, Id, Key, Room, ServerName, User},
-    server_keys::{EventHashable, Verifiable, Hashable2, Signable2},
+    signing::Verifiable,
     state::TimeStamp,
 };
 
@@ -120,27 +120,9 @@ where
     C: PDUContentType<'a> + Serialize,
     C::StateKey: Serialize,
 {
-}
-
-impl<'a, C> EventHashable for PDURef<'a, C>
-where
-    C: PDUContentType<'a> + Serialize,
-    C::StateKey: Serialize,
-{
-}
-
-impl<'a, C> Hashable2 for PDURef<'a, C>
-where
-    C: PDUContentType<'a> + Serialize,
-    C::StateKey: Serialize,
-{
-}
-
-impl<'a, C> Signable2 for PDURef<'a, C>
-where
-    C: PDUContentType<'a> + Serialize,
-    C::StateKey: Serialize,
-{
+    fn signing_time(&self) -> TimeStamp {
+        self.origin_server_ts
+    }
 }
 
 impl<'a, C: PDUContentType<'a>> PDURef<'a, C> {
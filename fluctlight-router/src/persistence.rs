@@ -1,154 +1,358 @@
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Display,
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use file_lock::{FileLock, FileOptions};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 
-use crate::matrix_types::{Event, Id, ServerName};
+use crate::{
+    matrix_types::{Event, Id, ServerName},
+    room_store::RoomStore,
+};
 
 pub(crate) struct RoomPersistence {
     pub state_pdu_file: PDUFile,
     pub other_pdu_file: PDUFile,
+    index: PDUIndex,
     // Planned:
-    // index: BlockVec<PDULocation> (by IntStr)
-    // Maybe BlockMap (that auto-grows with IntStr ID)
-
-    // File: append-only
-    // <event_id:44> <file:4> <offset:4> <length:4> =======\n
+    // Maybe BlockMap (that auto-grows with IntStr ID) instead of a BTreeMap
 
     // Figure out:
     // Simple storage files
     // For now just store everything
 }
 
-// struct PDULocation {
-//     file: usize,
-//     offset: usize,
-//     length: usize,
-// }
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum PDUFileId {
+    State = 0,
+    Other = 1,
+}
+
+impl PDUFileId {
+    fn from_u32(file_id: u32) -> Option<Self> {
+        match file_id {
+            0 => Some(PDUFileId::State),
+            1 => Some(PDUFileId::Other),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PDULocation {
+    pub file: PDUFileId,
+    pub block: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+/// Maps event IDs to where their PDU is stored.
+///
+/// Backed by an append-only file of fixed-size records:
+/// `<event_id:44> <file:4> <block:4> <offset:4> <length:4> =======\n`, with the numbers
+/// as little-endian u32s. Event IDs shorter than 44 bytes are padded with
+/// spaces; longer ones (pre-v4 rooms) are not indexed.
+struct PDUIndex {
+    file: File,
+    file_path: PathBuf,
+    locations: BTreeMap<Box<Id<Event>>, PDULocation>,
+    /// Offset and length of each PDU by file and block, in order, so that
+    /// pages can be read without going through the blocks from the start.
+    positions: [Vec<Vec<(u32, u32)>>; 2],
+}
+
+const INDEX_EVENT_ID_LENGTH: usize = 44;
+const INDEX_TRAILER: &[u8; 8] = b"=======\n";
+const INDEX_RECORD_LENGTH: usize = INDEX_EVENT_ID_LENGTH + 4 * (1 + 4) + 1 + 8;
 
+/// An append-only series of PDU blocks.
+///
+/// New PDUs go into the hot block (`<name>.<block>.json`), which gets
+/// compressed into a frozen block (`<name>.<block>.json.gz`) once it grows past
+/// `FREEZE_BLOCK_SIZE`. Offsets always refer to the uncompressed contents, so
+/// they stay valid across freezing.
 pub(crate) struct PDUFile {
-    // gz_file: Option<GzEncoder<File>>,
+    storage_path: PathBuf,
+    name: &'static str,
+    hot_block: u32,
     file: File,
     file_path: PathBuf,
     file_lock: FileLock,
+    // Paginating usually reads the same frozen block several times in a row
+    cached_block: Option<(u32, Vec<u8>)>,
 }
 
+const FREEZE_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
 impl PDUFile {
-    fn new(file_path: PathBuf) -> Result<Self, std::io::Error> {
-        let file_lock;
-        // let gz_file;
+    fn new(storage_path: &Path, name: &'static str) -> Result<Self, std::io::Error> {
+        let lock_path = storage_path.join(format!("{}.lock", name));
+        if !lock_path.exists() {
+            std::fs::write(&lock_path, b"")?;
+        }
+        let file_lock = lock_storage(&lock_path)?;
 
-        if !file_path.exists() {
-            std::fs::write(&file_path, b"")?;
+        // Rooms stored before blocks existed only have a single hot file
+        let legacy_path = storage_path.join(format!("{}.json", name));
+        let first_block_path = block_path(storage_path, name, 0, false);
+        if legacy_path.exists() && !first_block_path.exists() {
+            std::fs::rename(&legacy_path, &first_block_path)?;
+            sync_directory(storage_path)?;
         }
-        file_lock = lock_storage(&file_path)?;
-        // gz_file = gzip_writer(&file_path)?;
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(&file_path)?;
+
+        let mut hot_block = 0;
+        while block_path(storage_path, name, hot_block, true).exists() {
+            // The hot file is only removed after its frozen copy is complete
+            let stale_hot_path = block_path(storage_path, name, hot_block, false);
+            if stale_hot_path.exists() {
+                std::fs::remove_file(stale_hot_path)?;
+            }
+            hot_block += 1;
+        }
+
+        let file_path = block_path(storage_path, name, hot_block, false);
+        let file = open_hot_block(&file_path)?;
 
         Ok(PDUFile {
-            // gz_file: Some(gz_file),
+            storage_path: storage_path.to_owned(),
+            name,
+            hot_block,
             file,
             file_path,
             file_lock,
+            cached_block: None,
         })
     }
 
-    // pub(crate) fn read_contents(&mut self) -> Result<Vec<u8>, std::io::Error> {
-    //     let file_encoder = self
-    //         .gz_file
-    //         .take()
-    //         .expect("PDUFile should always have an opened gz_file");
-    //     let mut file = file_encoder.finish()?;
-
-    //     file.seek(SeekFrom::Start(0))?;
-
-    //     eprintln!("Loading {}", self.file_path.display());
-    //     let mut file_decoder = MultiGzDecoder::new(file);
-    //     let mut contents = Vec::new();
-    //     // FIXME: Figure out capacity (e.g. guesstimate based on compressed size)
-    //     file_decoder.read_to_end(&mut contents).unwrap();
-
-    //     let mut file = file_decoder.into_inner();
-    //     file.seek(SeekFrom::End(0))?;
-
-    //     let file_encoder = GzEncoder::new(file, Compression::default());
-    //     self.gz_file = Some(file_encoder);
-
-    //     Ok(contents)
-    // }
-
+    /// Reads the uncompressed contents of all blocks, in order.
     pub(crate) fn read_contents(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        self.file.seek(SeekFrom::Start(0))?;
-
         eprintln!("Loading {}", self.file_path.display());
         let mut contents = Vec::new();
-        // FIXME: Figure out capacity (e.g. guesstimate based on compressed size)
-        self.file.read_to_end(&mut contents).unwrap();
 
-        self.file.seek(SeekFrom::End(0))?;
+        for block in 0..=self.hot_block {
+            // FIXME: Figure out capacity (e.g. guesstimate based on compressed size)
+            contents.extend_from_slice(&self.read_block(block)?);
+        }
 
         Ok(contents)
     }
 
-    pub(crate) fn read_pdus(&mut self, mut f: impl FnMut(PDUBlob)) {
-        let pdu_file_contents = self.read_contents().unwrap();
-
-        let json_stream = serde_json::Deserializer::from_slice(&pdu_file_contents);
-
-        for pdu_blob in json_stream.into_iter::<PDUBlob>() {
-            f(pdu_blob.unwrap())
-        }
-    }
-
-    pub(crate) fn write_pdu(
+    /// Appends a PDU, returning the block, offset and length it was written at.
+    ///
+    /// The hot block is left as is; see `freeze_if_full`.
+    fn write_pdu(
         &mut self,
         event_id: &Id<Event>,
         origin: Option<&Id<ServerName>>,
         pdu_blob: &RawValue,
-    ) {
+    ) -> Result<(u32, u32, u32), std::io::Error> {
         let pdu_blob = PDUBlob {
             event_id,
             origin,
             pdu_blob,
         };
-        // FIXME: error
-        // let gz_file = self
-        //     .gz_file
-        //     .as_mut()
-        //     .expect("PDUFile should always have an opened gz_file");
-        let file = &mut self.file;
-        serde_json::to_writer(&mut *file, &pdu_blob)
-            .expect("Could not write to persistent room storage");
-        file
-            .write(b"\n")
-            .expect("Could not write to persistent room storage");
-        file
-            .flush()
-            .expect("Could not flush room persistence store");
+        let mut bytes = serde_json::to_vec(&pdu_blob)?;
+        let length = bytes.len();
+        bytes.push(b'\n');
+
+        let block = self.hot_block;
+        let offset = self.file.seek(SeekFrom::End(0))?;
+
+        let too_large = || std::io::Error::new(std::io::ErrorKind::Other, "PDU too large to index");
+        let end = u32::try_from(offset + bytes.len() as u64).map_err(|_| too_large())?;
+        let length = u32::try_from(length).map_err(|_| too_large())?;
+        let offset = end - length - 1;
+
+        self.file.write_all(&bytes)?;
+        self.file.flush()?;
+
+        Ok((block, offset, length))
+    }
+
+    fn freeze_if_full(&mut self) {
+        let full = match self.hot_block_len() {
+            Ok(length) => length >= FREEZE_BLOCK_SIZE,
+            Err(err) => {
+                eprintln!("Could not stat {}: {}", self.file_path.display(), err);
+                false
+            }
+        };
+
+        if full {
+            if let Err(err) = self.freeze_hot_block() {
+                // Not fatal; the hot block just keeps growing until next time
+                eprintln!("Could not freeze {}: {}", self.file_path.display(), err);
+            }
+        }
+    }
+
+    fn freeze_hot_block(&mut self) -> Result<(), std::io::Error> {
+        let frozen_path = block_path(&self.storage_path, self.name, self.hot_block, true);
+        let tmp_path = frozen_path.with_extension("gz.tmp");
+
+        // Streamed, since the hot block may have grown well past its size
+        let mut hot_block = File::open(&self.file_path)?;
+        let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::default());
+        std::io::copy(&mut hot_block, &mut encoder)?;
+        let tmp_file = encoder.finish()?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        std::fs::rename(&tmp_path, &frozen_path)?;
+        // Before the hot block goes, as it is all there is until then
+        sync_directory(&self.storage_path)?;
+
+        let file_path = block_path(&self.storage_path, self.name, self.hot_block + 1, false);
+        self.file = open_hot_block(&file_path)?;
+        std::fs::remove_file(std::mem::replace(&mut self.file_path, file_path))?;
+        self.hot_block += 1;
+
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Cow<'_, [u8]>, std::io::Error> {
+        if block == self.hot_block {
+            let mut contents = Vec::new();
+
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.read_to_end(&mut contents)?;
+            self.file.seek(SeekFrom::End(0))?;
+
+            return Ok(Cow::Owned(contents));
+        }
+
+        Ok(Cow::Borrowed(self.frozen_block(block)?))
+    }
+
+    /// Decompresses a frozen block, unless it is the one already cached.
+    fn frozen_block(&mut self, block: u32) -> Result<&[u8], std::io::Error> {
+        let cached =
+            matches!(&self.cached_block, Some((cached_block, _)) if *cached_block == block);
+
+        if !cached {
+            let frozen_path = block_path(&self.storage_path, self.name, block, true);
+            let mut decoder = GzDecoder::new(File::open(frozen_path)?);
+            let mut contents = Vec::new();
+            decoder.read_to_end(&mut contents)?;
+
+            self.cached_block = Some((block, contents));
+        }
+
+        let (_block, contents) = self.cached_block.as_ref().expect("Block was just cached");
+
+        Ok(contents)
+    }
+
+    fn read_at(&mut self, block: u32, offset: u32, length: u32) -> Result<Vec<u8>, std::io::Error> {
+        let (offset, length) = (offset as usize, length as usize);
+
+        if block == self.hot_block {
+            let mut bytes = vec![0; length];
+
+            self.file.seek(SeekFrom::Start(offset as u64))?;
+            self.file.read_exact(&mut bytes)?;
+            self.file.seek(SeekFrom::End(0))?;
+
+            return Ok(bytes);
+        }
+
+        self.frozen_block(block)?
+            .get(offset..offset + length)
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("PDU is past the end of block {}", block),
+                )
+            })
+    }
+
+    fn hot_block_len(&self) -> Result<u64, std::io::Error> {
+        Ok(self.file.metadata()?.len())
     }
 }
 
 impl Drop for PDUFile {
     fn drop(&mut self) {
-        // if let Some(mut gz_file) = self.gz_file.take() {
-        //     // Attempt to flush before unlocking
-        //     // FIXME: log issues
-        //     gz_file.flush().ok();
-        // }
         self.file.flush().ok();
         self.file_lock.unlock().ok();
     }
 }
 
+fn block_path(storage_path: &Path, name: &str, block: u32, frozen: bool) -> PathBuf {
+    let extension = if frozen { "json.gz" } else { "json" };
+
+    storage_path.join(format!("{}.{}.{}", name, block, extension))
+}
+
+fn open_hot_block(file_path: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(file_path)
+}
+
+/// Position of a PDU as block ID and index inside the block, usable as a
+/// pagination token.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct PDUToken {
+    pub file: PDUFileId,
+    pub block: u32,
+    pub index: u32,
+}
+
+impl PDUToken {
+    /// The token of the first PDU in a stream.
+    pub(crate) fn start(file: PDUFileId) -> Self {
+        PDUToken {
+            file,
+            block: 0,
+            index: 0,
+        }
+    }
+}
+
+impl Display for PDUToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = match self.file {
+            PDUFileId::State => 's',
+            PDUFileId::Other => 'o',
+        };
+
+        write!(f, "{}{}_{}", file, self.block, self.index)
+    }
+}
+
+impl FromStr for PDUToken {
+    type Err = String;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid_token = || format!("Invalid PDU token: {}", token);
+
+        let file = match token.chars().next() {
+            Some('s') => PDUFileId::State,
+            Some('o') => PDUFileId::Other,
+            _ => return Err(invalid_token()),
+        };
+
+        let (block, index) = token[1..].split_once('_').ok_or_else(invalid_token)?;
+
+        Ok(PDUToken {
+            file,
+            block: block.parse().map_err(|_| invalid_token())?,
+            index: index.parse().map_err(|_| invalid_token())?,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct PDUBlob<'a> {
     #[serde(borrow)]
@@ -157,9 +361,10 @@ pub(crate) struct PDUBlob<'a> {
     pub pdu_blob: &'a RawValue,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct OwnedPDUBlob {
     pub event_id: Box<Id<Event>>,
+    pub origin: Option<Box<Id<ServerName>>>,
     pub pdu_blob: Box<RawValue>,
 }
 
@@ -173,11 +378,427 @@ impl RoomPersistence {
         }
 
         // FIXME: Better error reporting.
+        let mut state_pdu_file = PDUFile::new(&storage_path, "state_pdus")?;
+        let mut other_pdu_file = PDUFile::new(&storage_path, "other_pdus")?;
+
+        let index_path = storage_path.join("pdu_index.bin");
+        let index = match PDUIndex::load(&index_path, &state_pdu_file, &other_pdu_file) {
+            Ok(index) => index,
+            Err(err) => {
+                eprintln!("Rebuilding {}: {}", index_path.display(), err);
+                PDUIndex::rebuild(index_path, &mut state_pdu_file, &mut other_pdu_file)?
+            }
+        };
+
         Ok(RoomPersistence {
-            state_pdu_file: PDUFile::new(storage_path.join("state_pdus.json").to_owned())?,
-            other_pdu_file: PDUFile::new(storage_path.join("other_pdus.json").to_owned())?,
+            state_pdu_file,
+            other_pdu_file,
+            index,
         })
     }
+
+    pub(crate) fn write_pdu(
+        &mut self,
+        file: PDUFileId,
+        event_id: &Id<Event>,
+        origin: Option<&Id<ServerName>>,
+        pdu_blob: &RawValue,
+    ) -> Result<(), std::io::Error> {
+        let (block, offset, length) = self.pdu_file(file).write_pdu(event_id, origin, pdu_blob)?;
+
+        let location = PDULocation {
+            file,
+            block,
+            offset,
+            length,
+        };
+
+        // The data is already safely on disk. As long as the PDU stays in the
+        // hot block, `PDUIndex::load` notices the missing entry and rebuilds,
+        // so only freeze once it is indexed.
+        if let Err(err) = self.index.append(event_id, location) {
+            eprintln!("Could not index {}: {}", event_id, err);
+            return Ok(());
+        }
+
+        self.pdu_file(file).freeze_if_full();
+
+        Ok(())
+    }
+
+    pub(crate) fn location(&self, event_id: &Id<Event>) -> Option<PDULocation> {
+        self.index.locations.get(event_id).copied()
+    }
+
+    fn pdu_file(&mut self, file: PDUFileId) -> &mut PDUFile {
+        match file {
+            PDUFileId::State => &mut self.state_pdu_file,
+            PDUFileId::Other => &mut self.other_pdu_file,
+        }
+    }
+}
+
+impl RoomStore for RoomPersistence {
+    fn append_pdu(
+        &mut self,
+        file: PDUFileId,
+        event_id: &Id<Event>,
+        origin: Option<&Id<ServerName>>,
+        pdu_blob: &RawValue,
+    ) -> Result<(), std::io::Error> {
+        if self.location(event_id).is_some() {
+            return Ok(());
+        }
+
+        self.write_pdu(file, event_id, origin, pdu_blob)
+    }
+
+    fn contains_pdu(&self, event_id: &Id<Event>) -> bool {
+        self.location(event_id).is_some()
+    }
+
+    fn read_pdu(&mut self, event_id: &Id<Event>) -> Result<Option<OwnedPDUBlob>, std::io::Error> {
+        let location = match self.location(event_id) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        let bytes = self.pdu_file(location.file).read_at(
+            location.block,
+            location.offset,
+            location.length,
+        )?;
+        let pdu_blob: OwnedPDUBlob = serde_json::from_slice(&bytes)?;
+
+        if &*pdu_blob.event_id != event_id {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Index points to {} instead of {}",
+                    pdu_blob.event_id, event_id
+                ),
+            ));
+        }
+
+        Ok(Some(pdu_blob))
+    }
+
+    fn read_page(
+        &mut self,
+        from: PDUToken,
+        limit: usize,
+    ) -> Result<(Vec<OwnedPDUBlob>, PDUToken), std::io::Error> {
+        // Not through `pdu_file`, as the index is needed alongside
+        let pdu_file = match from.file {
+            PDUFileId::State => &mut self.state_pdu_file,
+            PDUFileId::Other => &mut self.other_pdu_file,
+        };
+        let blocks = &self.index.positions[from.file as usize];
+        let mut pdu_blobs = Vec::new();
+        let mut token = from;
+
+        while pdu_blobs.len() < limit && token.block <= pdu_file.hot_block {
+            let positions = blocks.get(token.block as usize).map(Vec::as_slice);
+            let positions = positions
+                .and_then(|positions| positions.get(token.index as usize..))
+                .unwrap_or_default();
+            let positions = &positions[..positions.len().min(limit - pdu_blobs.len())];
+
+            if let (Some(&(start, _)), Some(&(last_offset, last_length))) =
+                (positions.first(), positions.last())
+            {
+                // The PDUs of a block are stored back to back, in order
+                let length = last_offset + last_length - start;
+                let bytes = pdu_file.read_at(token.block, start, length)?;
+
+                for &(offset, length) in positions {
+                    let offset = (offset - start) as usize;
+                    let pdu_blob = &bytes[offset..offset + length as usize];
+
+                    pdu_blobs.push(serde_json::from_slice(pdu_blob)?);
+                    token.index += 1;
+                }
+            }
+
+            // Stay on the hot block, since it may still grow
+            if pdu_blobs.len() < limit && token.block < pdu_file.hot_block {
+                token.block += 1;
+                token.index = 0;
+            } else {
+                break;
+            }
+        }
+
+        Ok((pdu_blobs, token))
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        self.state_pdu_file.file.sync_data()?;
+        self.other_pdu_file.file.sync_data()?;
+        self.index.file.sync_data()
+    }
+}
+
+impl PDUIndex {
+    fn new(
+        file: File,
+        file_path: PathBuf,
+        locations: BTreeMap<Box<Id<Event>>, PDULocation>,
+    ) -> Self {
+        let mut index = PDUIndex {
+            file,
+            file_path,
+            locations: BTreeMap::new(),
+            positions: Default::default(),
+        };
+
+        for location in locations.values() {
+            index.add_position(*location);
+        }
+        for block_positions in index.positions.iter_mut().flatten() {
+            block_positions.sort_unstable();
+        }
+        index.locations = locations;
+
+        index
+    }
+
+    /// Loads the index, failing if it is corrupt or doesn't cover all PDUs.
+    fn load(
+        file_path: &Path,
+        state_pdu_file: &PDUFile,
+        other_pdu_file: &PDUFile,
+    ) -> Result<Self, std::io::Error> {
+        let invalid_data =
+            |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string());
+
+        let mut file = OpenOptions::new().append(true).read(true).open(file_path)?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        if contents.len() % INDEX_RECORD_LENGTH != 0 {
+            return Err(invalid_data("truncated record"));
+        }
+
+        let mut locations = BTreeMap::new();
+        let pdu_files = [state_pdu_file, other_pdu_file];
+        let mut indexed_lengths = [0u64; 2];
+
+        for record in contents.chunks_exact(INDEX_RECORD_LENGTH) {
+            let (event_id, location) =
+                decode_index_record(record).ok_or_else(|| invalid_data("corrupt record"))?;
+
+            let hot_block = pdu_files[location.file as usize].hot_block;
+            if location.block > hot_block {
+                return Err(invalid_data("points past the hot block"));
+            } else if location.block == hot_block {
+                let end = location.offset as u64 + location.length as u64 + 1;
+                let indexed_length = &mut indexed_lengths[location.file as usize];
+                *indexed_length = (*indexed_length).max(end);
+            }
+
+            locations.insert(event_id, location);
+        }
+
+        // Frozen blocks never change, so only the hot ones need checking.
+        // Unindexable event IDs at the end of a block also end up here, which
+        // only costs a rebuild.
+        if indexed_lengths[0] != state_pdu_file.hot_block_len()?
+            || indexed_lengths[1] != other_pdu_file.hot_block_len()?
+        {
+            return Err(invalid_data("does not match the PDU files"));
+        }
+
+        Ok(PDUIndex::new(file, file_path.to_owned(), locations))
+    }
+
+    /// Recreates the index by scanning all PDU files, cutting off a PDU that
+    /// was only partly written to a hot block.
+    fn rebuild(
+        file_path: PathBuf,
+        state_pdu_file: &mut PDUFile,
+        other_pdu_file: &mut PDUFile,
+    ) -> Result<Self, std::io::Error> {
+        let too_large = |what: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} too large to index", what),
+            )
+        };
+
+        let mut locations = BTreeMap::new();
+        let mut records = Vec::new();
+
+        for (file_id, pdu_file) in [
+            (PDUFileId::State, state_pdu_file),
+            (PDUFileId::Other, other_pdu_file),
+        ] {
+            for block in 0..=pdu_file.hot_block {
+                let is_hot_block = block == pdu_file.hot_block;
+                let contents = pdu_file.read_block(block)?;
+                let mut json_stream =
+                    serde_json::Deserializer::from_slice(&contents).into_iter::<PDUBlob>();
+                let mut previous_end = 0;
+                let mut torn_end = None;
+
+                while let Some(pdu_blob) = json_stream.next() {
+                    let pdu_blob = match pdu_blob {
+                        Ok(pdu_blob) => pdu_blob,
+                        // A torn write from a crash; frozen blocks were
+                        // complete when written, so only the hot one has them
+                        Err(err) if is_hot_block => {
+                            torn_end = Some((previous_end as u64, err));
+                            break;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    let end = json_stream.byte_offset();
+                    let start = previous_end
+                        + contents[previous_end..end]
+                            .iter()
+                            .take_while(|byte| byte.is_ascii_whitespace())
+                            .count();
+                    previous_end = end;
+
+                    let location = PDULocation {
+                        file: file_id,
+                        block,
+                        offset: u32::try_from(start).map_err(|_| too_large("PDU block"))?,
+                        length: u32::try_from(end - start).map_err(|_| too_large("PDU"))?,
+                    };
+
+                    if let Some(record) = encode_index_record(pdu_blob.event_id, location) {
+                        records.extend_from_slice(&record);
+                    }
+                    locations.insert(pdu_blob.event_id.to_owned(), location);
+                }
+
+                drop(contents);
+
+                // Cut off so that new PDUs don't get appended to it
+                if let Some((length, err)) = torn_end {
+                    eprintln!("Truncating {}: {}", pdu_file.file_path.display(), err);
+                    pdu_file.file.set_len(length)?;
+                }
+            }
+        }
+
+        let tmp_path = file_path.with_extension("bin.tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&records)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        std::fs::rename(&tmp_path, &file_path)?;
+        if let Some(storage_path) = file_path.parent() {
+            sync_directory(storage_path)?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .read(true)
+            .open(&file_path)?;
+
+        Ok(PDUIndex::new(file, file_path, locations))
+    }
+
+    fn append(&mut self, event_id: &Id<Event>, location: PDULocation) -> Result<(), String> {
+        self.locations.insert(event_id.to_owned(), location);
+        self.add_position(location);
+
+        // Long (pre-v4) event IDs are only kept in memory, so that they don't
+        // keep their block from freezing
+        let record = match encode_index_record(event_id, location) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        self.file
+            .write_all(&record)
+            .and_then(|()| self.file.flush())
+            .map_err(|err| format!("{}: {}", self.file_path.display(), err))
+    }
+
+    fn add_position(&mut self, location: PDULocation) {
+        let blocks = &mut self.positions[location.file as usize];
+        let block = location.block as usize;
+
+        if blocks.len() <= block {
+            blocks.resize_with(block + 1, Vec::new);
+        }
+
+        blocks[block].push((location.offset, location.length));
+    }
+}
+
+fn encode_index_record(
+    event_id: &Id<Event>,
+    location: PDULocation,
+) -> Option<[u8; INDEX_RECORD_LENGTH]> {
+    let event_id = event_id.as_str().as_bytes();
+
+    if event_id.len() > INDEX_EVENT_ID_LENGTH {
+        return None;
+    }
+
+    let mut record = [b' '; INDEX_RECORD_LENGTH];
+    let mut position = INDEX_EVENT_ID_LENGTH + 1;
+
+    record[..event_id.len()].copy_from_slice(event_id);
+    for number in [
+        location.file as u32,
+        location.block,
+        location.offset,
+        location.length,
+    ] {
+        record[position..position + 4].copy_from_slice(&number.to_le_bytes());
+        position += 5;
+    }
+    record[position..].copy_from_slice(INDEX_TRAILER);
+
+    Some(record)
+}
+
+fn decode_index_record(record: &[u8]) -> Option<(Box<Id<Event>>, PDULocation)> {
+    if &record[INDEX_RECORD_LENGTH - INDEX_TRAILER.len()..] != INDEX_TRAILER {
+        return None;
+    }
+
+    let event_id = std::str::from_utf8(&record[..INDEX_EVENT_ID_LENGTH]).ok()?;
+    let event_id = Id::<Event>::try_boxed_from_str(event_id.trim_end_matches(' ')).ok()?;
+
+    let mut numbers = [0u32; 4];
+    let mut position = INDEX_EVENT_ID_LENGTH;
+    for number in &mut numbers {
+        if record[position] != b' ' {
+            return None;
+        }
+        let bytes = record[position + 1..position + 5].try_into().ok()?;
+        *number = u32::from_le_bytes(bytes);
+        position += 5;
+    }
+
+    let location = PDULocation {
+        file: PDUFileId::from_u32(numbers[0])?,
+        block: numbers[1],
+        offset: numbers[2],
+        length: numbers[3],
+    };
+
+    Some((event_id, location))
+}
+
+/// Makes renames and new files in a directory survive a power loss, which
+/// syncing the files themselves doesn't.
+pub(crate) fn sync_directory(path: &Path) -> Result<(), std::io::Error> {
+    // Directories can't be opened like this elsewhere, nor synced
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
 }
 
 fn lock_storage(file_path: &Path) -> Result<FileLock, std::io::Error> {
@@ -188,12 +809,226 @@ fn lock_storage(file_path: &Path) -> Result<FileLock, std::io::Error> {
     FileLock::lock(file_path, is_blocking, file_options)
 }
 
-// fn gzip_writer(file_path: &Path) -> Result<GzEncoder<File>, std::io::Error> {
-//     let file = OpenOptions::new()
-//         .create(true)
-//         .append(true)
-//         .read(true)
-//         .open(file_path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for a room store, unique to the test.
+    fn storage_path(test_name: &str) -> PathBuf {
+        let storage_path = std::env::temp_dir().join(format!(
+            "fluctlight-persistence-{}-{}",
+            std::process::id(),
+            test_name
+        ));
+
+        if storage_path.exists() {
+            std::fs::remove_dir_all(&storage_path).unwrap();
+        }
+
+        storage_path
+    }
+
+    fn event_id(event_id: &str) -> Box<Id<Event>> {
+        Id::try_boxed_from_str(event_id).unwrap()
+    }
+
+    fn pdu(event_id: &str) -> Box<RawValue> {
+        RawValue::from_string(format!(r#"{{"event_id":"{}"}}"#, event_id)).unwrap()
+    }
+
+    fn append(room: &mut RoomPersistence, file: PDUFileId, event_ids: &[&str]) {
+        for id in event_ids {
+            room.append_pdu(file, &event_id(id), None, &pdu(id))
+                .unwrap();
+        }
+    }
+
+    fn read_event_ids(room: &mut RoomPersistence, file: PDUFileId) -> Vec<String> {
+        let (page, _token) = room.read_page(PDUToken::start(file), 100).unwrap();
+
+        page.into_iter()
+            .map(|pdu_blob| pdu_blob.event_id.to_string())
+            .collect()
+    }
+
+    fn load_index(storage_path: &Path) -> Result<PDUIndex, std::io::Error> {
+        let state_pdu_file = PDUFile::new(storage_path, "state_pdus")?;
+        let other_pdu_file = PDUFile::new(storage_path, "other_pdus")?;
+
+        PDUIndex::load(
+            &storage_path.join("pdu_index.bin"),
+            &state_pdu_file,
+            &other_pdu_file,
+        )
+    }
+
+    #[test]
+    fn index_is_loaded_back() {
+        let storage_path = storage_path("index_is_loaded_back");
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        append(&mut room, PDUFileId::State, &["$create", "$member"]);
+        append(&mut room, PDUFileId::Other, &["$message"]);
+        drop(room);
+
+        let index = load_index(&storage_path).unwrap();
+        assert_eq!(index.locations.len(), 3);
+        assert_eq!(
+            index.locations[&*event_id("$message")].file,
+            PDUFileId::Other
+        );
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        let pdu_blob = room.read_pdu(&event_id("$member")).unwrap().unwrap();
+        assert_eq!(pdu_blob.pdu_blob.get(), pdu("$member").get());
+    }
+
+    #[test]
+    fn missing_index_is_rebuilt() {
+        let storage_path = storage_path("missing_index_is_rebuilt");
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        append(&mut room, PDUFileId::State, &["$create"]);
+        append(&mut room, PDUFileId::Other, &["$first", "$second"]);
+        drop(room);
+
+        std::fs::remove_file(storage_path.join("pdu_index.bin")).unwrap();
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        for id in ["$create", "$first", "$second"] {
+            assert!(room.contains_pdu(&event_id(id)), "{} not indexed", id);
+        }
+        let pdu_blob = room.read_pdu(&event_id("$second")).unwrap().unwrap();
+        assert_eq!(pdu_blob.pdu_blob.get(), pdu("$second").get());
+        drop(room);
+
+        // Written out again, so the next start doesn't have to rebuild
+        assert_eq!(load_index(&storage_path).unwrap().locations.len(), 3);
+    }
+
+    #[test]
+    fn torn_pdu_is_cut_off_on_rebuild() {
+        let storage_path = storage_path("torn_pdu_is_cut_off_on_rebuild");
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        append(&mut room, PDUFileId::Other, &["$first", "$second"]);
+        drop(room);
+
+        // As if the process died halfway through writing a PDU, before
+        // indexing it
+        let hot_block_path = block_path(&storage_path, "other_pdus", 0, false);
+        let mut hot_block = OpenOptions::new()
+            .append(true)
+            .open(&hot_block_path)
+            .unwrap();
+        hot_block.write_all(br#"{"event_id":"$torn","pdu"#).unwrap();
+        drop(hot_block);
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        assert!(!room.contains_pdu(&event_id("$torn")));
+        append(&mut room, PDUFileId::Other, &["$third"]);
+
+        assert_eq!(
+            read_event_ids(&mut room, PDUFileId::Other),
+            ["$first", "$second", "$third"]
+        );
+        drop(room);
+
+        load_index(&storage_path).unwrap();
+    }
+
+    #[test]
+    fn long_event_ids_are_indexed_by_rebuilding() {
+        let storage_path = storage_path("long_event_ids_are_indexed_by_rebuilding");
+        let long_event_id = "$a-pre-v4-event-id-with-a-server-name:example.org";
+        assert!(long_event_id.len() > INDEX_EVENT_ID_LENGTH);
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        append(&mut room, PDUFileId::Other, &["$short", long_event_id]);
+        assert!(room.contains_pdu(&event_id(long_event_id)));
+        drop(room);
 
-//     Ok(GzEncoder::new(file, Compression::default()))
-// }
+        // Only in memory, so the index on disk falls short of the PDU file
+        assert!(load_index(&storage_path).is_err());
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        let pdu_blob = room.read_pdu(&event_id(long_event_id)).unwrap().unwrap();
+        assert_eq!(pdu_blob.pdu_blob.get(), pdu(long_event_id).get());
+        assert!(room.contains_pdu(&event_id("$short")));
+    }
+
+    #[test]
+    fn frozen_blocks_are_read_back() {
+        let storage_path = storage_path("frozen_blocks_are_read_back");
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        append(
+            &mut room,
+            PDUFileId::Other,
+            &["$first", "$second", "$third"],
+        );
+        room.other_pdu_file.freeze_hot_block().unwrap();
+        append(&mut room, PDUFileId::Other, &["$fourth", "$fifth"]);
+
+        assert!(block_path(&storage_path, "other_pdus", 0, true).exists());
+        assert!(!block_path(&storage_path, "other_pdus", 0, false).exists());
+        assert_eq!(room.location(&event_id("$fourth")).unwrap().block, 1);
+
+        // Pages that end inside a block, and that span both
+        let mut event_ids = Vec::new();
+        let mut token = PDUToken::start(PDUFileId::Other);
+        loop {
+            let (page, next_token) = room.read_page(token, 2).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            event_ids.extend(
+                page.into_iter()
+                    .map(|pdu_blob| pdu_blob.event_id.to_string()),
+            );
+            token = next_token;
+        }
+        assert_eq!(
+            event_ids,
+            ["$first", "$second", "$third", "$fourth", "$fifth"]
+        );
+        assert_eq!(token.to_string(), "o1_2");
+        drop(room);
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+        let pdu_blob = room.read_pdu(&event_id("$second")).unwrap().unwrap();
+        assert_eq!(pdu_blob.pdu_blob.get(), pdu("$second").get());
+        assert_eq!(read_event_ids(&mut room, PDUFileId::Other).len(), 5);
+    }
+
+    #[test]
+    fn legacy_hot_file_is_migrated() {
+        let storage_path = storage_path("legacy_hot_file_is_migrated");
+        std::fs::create_dir(&storage_path).unwrap();
+
+        let mut contents = Vec::new();
+        for id in ["$create", "$member"] {
+            let pdu_blob = PDUBlob {
+                event_id: &event_id(id),
+                origin: None,
+                pdu_blob: &pdu(id),
+            };
+            serde_json::to_writer(&mut contents, &pdu_blob).unwrap();
+            contents.push(b'\n');
+        }
+        std::fs::write(storage_path.join("state_pdus.json"), contents).unwrap();
+
+        let mut room = RoomPersistence::new(&storage_path).unwrap();
+
+        assert!(!storage_path.join("state_pdus.json").exists());
+        assert!(block_path(&storage_path, "state_pdus", 0, false).exists());
+        assert_eq!(
+            read_event_ids(&mut room, PDUFileId::State),
+            ["$create", "$member"]
+        );
+
+        append(&mut room, PDUFileId::State, &["$name"]);
+        let pdu_blob = room.read_pdu(&event_id("$member")).unwrap().unwrap();
+        assert_eq!(pdu_blob.pdu_blob.get(), pdu("$member").get());
+    }
+}
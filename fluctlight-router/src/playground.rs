@@ -1,11 +1,13 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     error::Error,
+    time::Instant,
 };
 
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use serde::Deserialize;
 use serde_json::{value::RawValue, Value};
+use sha2::Digest;
 use vec_collections::VecMap1;
 
 use crate::{
@@ -15,10 +17,11 @@ use crate::{
     matrix_types::{Event, Id, Key, Room, ServerName, User},
     pdu_arc::{AnyContent, PDUArc},
     pdu_ref::{parse_pdu_ref, AnyContentRef, MemberContent, PDUContentType, PDURef, SignaturesRef},
-    persistence::{PDUBlob, RoomPersistence},
-    server_keys::{EventHashable, Hashable2, Signable2, Verifiable},
+    persistence::{PDUBlob, PDUFileId, PDUToken, RoomPersistence},
+    room_store::{open_room_store, RoomStore, RoomStoreKind, SharedRoomStore},
     signed_request::SignedRequestBuilder,
-    state::{State, TimeStamp},
+    signing::{content_hash, event_id, sign_detached, Verifiable},
+    state::{EphemeralRoomState, State, StoredPDU, TimeStamp},
 };
 
 pub(crate) struct ParsedPDU {
@@ -32,6 +35,14 @@ pub(crate) struct ParsedPDU {
 }
 
 impl ParsedPDU {
+    /// The membership an `m.room.member` PDU sets its state key to.
+    pub(crate) fn membership(&self) -> Option<&str> {
+        match &self.pdu.content {
+            AnyContent::Member(member) => Some(&member.membership),
+            _ => None,
+        }
+    }
+
     pub(crate) fn render_contents(&self) -> String {
         match &self.pdu.content {
             AnyContent::Member(member) => {
@@ -90,26 +101,96 @@ struct BackfillResponse {
     pdus: Vec<Box<RawValue>>,
 }
 
-pub(crate) fn send_backfill_request(state: &State) -> Result<(), Box<dyn Error>> {
-    let room_id = Id::<Room>::try_from_str("!jhTIqlwlxKKoPPHIgH:synapse-dev.demi.ro").unwrap();
-    let event_id = "$By7ZDI3wONJDXly1um6f1NqimBdqS_1g3kxeNYjhnBA";
+/// The server to ask for events around `event_id`: the one that sent it to
+/// us if known, since it is the one most likely to still have its history.
+fn backfill_destination(
+    state: &State,
+    room_id: &Id<Room>,
+    event_id: &Id<Event>,
+) -> Box<Id<ServerName>> {
+    let real_origin = state.with_ephemeral_mut(|ephemeral_state| {
+        let room = ephemeral_state.rooms.get_mut(room_id)?;
+
+        if let Some(parsed_pdu) = room.pdus.get(event_id) {
+            return Some(parsed_pdu.real_origin.as_ref()?.as_id().to_owned());
+        }
+
+        // Evicted rooms still have it in their store
+        room.room_store
+            .as_ref()?
+            .lock()
+            .read_pdu(event_id)
+            .ok()??
+            .origin
+    });
+
+    // The room's own server, as a last resort
+    real_origin.unwrap_or_else(|| room_id.server_name().to_owned())
+}
 
+/// Fetches the PDUs before (and including) `event_id` and stores them,
+/// returning how many there were.
+pub(crate) fn send_backfill_request(
+    state: &State,
+    room_id: &Id<Room>,
+    event_id: &Id<Event>,
+) -> Result<usize, Box<dyn Error>> {
+    let destination = backfill_destination(state, room_id, event_id);
     let uri = format!("/_matrix/federation/v1/backfill/{room_id}?limit=50&v={event_id}");
     let response_bytes = SignedRequestBuilder::get(state, &uri)
-        .destination("synapse-dev.demi.ro")
+        .destination(destination.as_str())
         .send()?;
     let response: BackfillResponse = serde_json::from_slice(&response_bytes)?;
 
     eprintln!("Got {} backfill PDUs.", response.pdus.len());
-    state.with_persistent_mut(|persistent_state| {
-        let room = persistent_state.rooms.get_mut(room_id).unwrap();
 
-        for pdu in response.pdus {
-            room.pdu_blobs.push(pdu.to_string());
+    // Rooms with their own storage keep the origin; the others can't
+    let written_to_room_storage = state.with_ephemeral_mut(|ephemeral_state| {
+        let mut room_store = match ephemeral_state
+            .rooms
+            .get(room_id)
+            .and_then(|room| room.room_store.as_ref())
+        {
+            Some(room_store) => room_store.lock(),
+            None => return Ok(false),
+        };
+
+        for pdu in &response.pdus {
+            let pdu_ref = parse_pdu_ref(pdu)?;
+            let pdu_event_id = crate::signing::event_id(&pdu_ref);
+
+            let file = if AnyContentRef::has_state(&pdu_ref.state_key) {
+                PDUFileId::State
+            } else {
+                PDUFileId::Other
+            };
+            room_store.append_pdu(file, &pdu_event_id, Some(&destination), pdu)?;
         }
-    });
 
-    Ok(())
+        Ok::<_, Box<dyn Error>>(true)
+    })?;
+
+    if !written_to_room_storage {
+        state.with_persistent_mut(|persistent_state| {
+            let room = persistent_state
+                .rooms
+                .get_mut(room_id)
+                .ok_or_else(|| format!("Unknown room {}", room_id))?;
+
+            for pdu in &response.pdus {
+                match parse_pdu_ref(pdu) {
+                    Ok(pdu_ref) => {
+                        room.push_pdu_blob(&crate::signing::event_id(&pdu_ref), pdu.get())
+                    }
+                    Err(err) => eprintln!("Skipping invalid backfill PDU: {}", err),
+                }
+            }
+
+            Ok::<_, String>(())
+        })??;
+    }
+
+    Ok(response.pdus.len())
 }
 
 #[derive(Deserialize)]
@@ -123,22 +204,24 @@ struct MakeJoinResponse<'a> {
 struct SendJoinResponse<'a> {
     #[serde(borrow)]
     auth_chain: Vec<&'a RawValue>,
-    event: &'a RawValue,
+    /// Only sent back when the resident server had to sign it too.
+    event: Option<&'a RawValue>,
     state: Vec<&'a RawValue>,
 }
 
-pub(crate) fn send_join_request(state: &State) -> Result<(), Box<dyn Error>> {
-    // let room_id = Id::<Room>::try_from_str("!jhTIqlwlxKKoPPHIgH:synapse-dev.demi.ro").unwrap();
-    // let foreign_server_name = room_id.server_name();
-    // let user_id = Id::<User>::try_from_str("@whyte:fluctlight-dev.demi.ro").unwrap();
-    // let room_version = 6;
-
-    let room_id = Id::<Room>::try_from_str("!MmYfpbopGTdEQTHqlr:matrix.org").unwrap();
+/// Joins one of our users to a room through the server the room ID names,
+/// storing the room's state as that server sent it.
+pub(crate) fn send_join_request(
+    state: &State,
+    room_id: &Id<Room>,
+    user_id: &Id<User>,
+) -> Result<(), Box<dyn Error>> {
     let foreign_server_name = room_id.server_name();
-    let user_id = Id::<User>::try_from_str("@whyte:fluctlight-dev.demi.ro").unwrap();
     let room_version = 6;
 
-    assert_eq!(user_id.server_name(), state.server_name.as_id());
+    if user_id.server_name() != state.server_name.as_id() {
+        return Err(format!("{} is not one of our users", user_id).into());
+    }
 
     let make_join_uri = format!(
         "/_matrix/federation/v1/make_join/{}/{}?ver={}",
@@ -170,16 +253,17 @@ pub(crate) fn send_join_request(state: &State) -> Result<(), Box<dyn Error>> {
 
     join_template.origin = Some(state.server_name.as_id());
     join_template.origin_server_ts = TimeStamp::now();
-    owned_sha256_hash = join_template.generate_minimal_content_hash();
+    owned_sha256_hash = content_hash(&join_template);
 
     let hashes: VecMap1<&str, &str> = [("sha256", owned_sha256_hash.as_str())]
         .into_iter()
         .collect();
 
     join_template.hashes = Some(hashes);
-    let event_id = join_template.generate_event_id();
+    let event_id = event_id(&join_template);
 
-    owned_server_signatures = join_template.sign(state);
+    owned_server_signatures =
+        sign_detached(&join_template, state.server_key_pairs().signing_keys());
     let server_signatures: VecMap1<&Id<Key>, &str> = owned_server_signatures
         .iter()
         .map(|(key_name, key_signature)| (key_name.as_id(), key_signature.as_str()))
@@ -201,7 +285,7 @@ pub(crate) fn send_join_request(state: &State) -> Result<(), Box<dyn Error>> {
 
     join_template
         .verify(state, &state.server_name, &signatures_ref)
-        .unwrap();
+        .map_err(|err| format!("Could not sign join PDU: {}", err))?;
 
     join_template.signatures = Some(signatures_ref);
 
@@ -211,7 +295,7 @@ pub(crate) fn send_join_request(state: &State) -> Result<(), Box<dyn Error>> {
     let uri = format!("/_matrix/federation/v2/send_join/{}/{}", room_id, event_id);
     let send_join_response_bytes = SignedRequestBuilder::put(state, &uri)
         .destination(foreign_server_name.as_str())
-        .send_body(body_content)?;
+        .send_body(body_content.clone())?;
 
     eprintln!(
         "Join response: {}",
@@ -223,12 +307,21 @@ pub(crate) fn send_join_request(state: &State) -> Result<(), Box<dyn Error>> {
     eprintln!("Auth events: {:?}", send_join_response.auth_chain.len());
     eprintln!("State events: {:?}", send_join_response.state.len());
 
+    // PDUs of rooms we don't know are dropped
+    state.with_persistent_mut(|persistent_state| {
+        persistent_state.rooms.entry(room_id.to_owned()).or_default();
+    })?;
+    state.with_ephemeral_mut(|ephemeral_state| {
+        ephemeral_state.rooms.entry(room_id.to_owned()).or_default();
+    });
+
     eprintln!("Ingesting auth events...");
     ingest_transaction(state, None, &send_join_response.auth_chain, &[]);
     eprintln!("Ingesting state events...");
     ingest_transaction(state, None, &send_join_response.state, &[]);
     eprintln!("Ingesting join event...");
-    ingest_transaction(state, None, &[send_join_response.event], &[]);
+    let join_event = send_join_response.event.unwrap_or(&body_content);
+    ingest_transaction(state, None, &[join_event], &[]);
 
     Ok(())
 }
@@ -265,7 +358,7 @@ pub(crate) fn load_join_event() -> Result<(), Box<dyn Error>> {
     {
         // let pdu = parse_pdu(event).unwrap();
         let pdu_ref = parse_pdu_ref(event)?;
-        let event_id = pdu_ref.generate_event_id();
+        let event_id = event_id(&pdu_ref);
         let pdu_arc = PDUArc::from_pdu_ref(&pdu_ref, &mut interner);
         // Note: this origin may be missing, and be forever lost, according to:
         // https://github.com/matrix-org/matrix-spec/issues/374#issuecomment-1276072011
@@ -295,11 +388,12 @@ pub(crate) fn load_join_event() -> Result<(), Box<dyn Error>> {
         // TODO: Is this correct handling of missing origins?
         // Or perhaps should assert instead?
         let real_origin = parsed_pdu.real_origin.as_ref().map(|origin| origin.as_id());
-        room_persistence.state_pdu_file.write_pdu(
+        room_persistence.write_pdu(
+            PDUFileId::State,
             &parsed_pdu.event_id,
             real_origin,
             &parsed_pdu.blob,
-        );
+        )?;
     }
     drop(room_persistence);
     timer.stop("persist events");
@@ -341,6 +435,7 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
         pdu_ref: PDURef<'a, AnyContentRef<'a>>,
         signature_check: Result<(), &'static str>,
         event_id: Box<Id<Event>>,
+        origin: Option<&'a Id<ServerName>>,
         pdu_blob: Box<RawValue>,
     }
     println!(
@@ -356,6 +451,7 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
             let signatures = pdu_ref.signatures.as_ref().unwrap();
             let sender_name = pdu_ref.sender.server_name();
 
+            pdu_ref.fetch_signing_keys(state, sender_name, signatures);
             let signature_check = pdu_ref.verify(state, sender_name, signatures);
 
             // if let AnyStateRef::UserId(UserStateKey { user_id }) = &pdu_ref.state_key {
@@ -371,6 +467,7 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
                 signature_check,
                 pdu_blob: pdu_blob.pdu_blob.to_owned(),
                 event_id: pdu_blob.event_id.to_owned(),
+                origin: pdu_blob.origin,
             }
         })
         .collect();
@@ -391,11 +488,14 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
 
     for partial_pdu in partial_pdus {
         let pdu_arc = PDUArc::from_pdu_ref(&partial_pdu.pdu_ref, &mut interner);
+        let real_origin = partial_pdu
+            .origin
+            .map(|origin| interner.get_or_insert(origin));
 
         pdus.push(ParsedPDU {
             event_id: partial_pdu.event_id,
             arc_event_id: None,
-            real_origin: None, // FIXME
+            real_origin,
             pdu: pdu_arc,
             blob: partial_pdu.pdu_blob,
             signature_check: Some(partial_pdu.signature_check),
@@ -475,6 +575,8 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
         room.pdus = room_pdus;
         room.pdus_by_timestamp = room_pdus_by_timestamp;
         room.interner = interner;
+        room.resident = true;
+        room.last_access = Some(Instant::now());
     });
     println!(
         "Allocated after storing: {}MB",
@@ -495,100 +597,486 @@ pub(crate) fn load_room(state: &State) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub(crate) fn load_persistent_rooms(state: &crate::state::State) {
-    let mut rooms = BTreeSet::new();
+/// Opens the store of every persistent room, without loading any PDUs; see
+/// `load_persistent_room` for that.
+pub(crate) fn open_persistent_rooms(state: &crate::state::State) -> Result<(), String> {
+    let mut rooms = BTreeMap::new();
 
-    state.with_persistent_mut(|persistent_state| {
-        for room_id in persistent_state.rooms.keys() {
-            rooms.insert(room_id.to_box());
+    for (room_id, room) in &state.persistent().rooms {
+        rooms.insert(room_id.to_box(), room.room_db.clone());
+    }
+
+    for (room_id, room_db) in rooms {
+        eprintln!("Opening persistent room: {room_id}");
+
+        let room_store = match room_db {
+            Some(room_db) => {
+                let room_store =
+                    open_room_store(state.config.room_store, &state.data_dir.join(&room_db))
+                        .map_err(|err| format!("Could not open {}: {}", room_db, err))?;
+                Some(SharedRoomStore::new(room_store))
+            }
+            None => None,
+        };
+
+        state.with_ephemeral_mut(|ephemeral_state| {
+            let room = ephemeral_state.rooms.entry(room_id).or_default();
+            room.room_store = room_store;
+        });
+    }
+
+    Ok(())
+}
+
+/// Parses all PDUs of a room into memory, unless already there, and marks
+/// the room as just used. Returns `false` for unknown rooms.
+///
+/// PDUs that can't be parsed are skipped with a warning; only failing to read
+/// the room's store is an error.
+pub(crate) fn load_persistent_room(
+    state: &crate::state::State,
+    room_id: &Id<Room>,
+) -> Result<bool, String> {
+    if !state.persistent().rooms.contains_key(room_id) {
+        return Ok(state.ephemeral().rooms.contains_key(room_id));
+    }
+
+    // Only the store is taken under the lock, as reading, parsing and
+    // checking all PDUs of a big room takes a while; what comes in from here
+    // on is kept aside for the load instead
+    let room_store = state.with_ephemeral_mut(|ephemeral_state| {
+        let room = ephemeral_state.rooms.entry(room_id.to_owned()).or_default();
+        room.last_access = Some(Instant::now());
+
+        if room.resident {
+            return None;
         }
+
+        room.loading += 1;
+        Some(room.room_store.clone())
     });
 
-    for room_id in rooms {
-        eprintln!("Loading persistent room: {room_id}");
-        load_persistent_room(state, &room_id);
+    let room_store = match room_store {
+        Some(room_store) => room_store,
+        None => return Ok(true),
+    };
+
+    let allocated_before = crate::ALLOCATOR.allocated();
+
+    let pdu_blobs = match read_room_pdus(state, room_id, room_store.as_ref()) {
+        Ok(pdu_blobs) => pdu_blobs,
+        Err(err) => {
+            state.with_ephemeral_mut(|ephemeral_state| {
+                if let Some(room) = ephemeral_state.rooms.get_mut(room_id) {
+                    stop_loading(room);
+                }
+            });
+
+            return Err(format!("Could not read PDUs of {room_id}: {err}"));
+        }
+    };
+
+    let mut interner = Interner::default();
+    let parsed_pdus: Vec<_> = pdu_blobs
+        .into_iter()
+        .filter_map(|(pdu_blob, origin)| {
+            parse_stored_pdu(state, room_id, pdu_blob, origin, &mut interner, true)
+        })
+        .collect();
+
+    state.with_ephemeral_mut(|ephemeral_state| {
+        let room = ephemeral_state.rooms.entry(room_id.to_owned()).or_default();
+        let pending_pdus = stop_loading(room);
+
+        // Someone else got there first
+        if room.resident {
+            return;
+        }
+
+        // Nothing in the room refers to the old one while it isn't resident
+        room.interner = interner;
+
+        for parsed_pdu in parsed_pdus {
+            insert_parsed_pdu(room, parsed_pdu);
+        }
+
+        // Already in there if the load read them as well
+        for (pdu_blob, origin) in pending_pdus {
+            let interner = &mut room.interner;
+            if let Some(parsed_pdu) =
+                parse_stored_pdu(state, room_id, pdu_blob, origin, interner, false)
+            {
+                insert_parsed_pdu(room, parsed_pdu);
+            }
+        }
+
+        room.resident = true;
+        room.resident_size = crate::ALLOCATOR
+            .allocated()
+            .saturating_sub(allocated_before);
+    });
+
+    evict_cold_rooms(state, Some(room_id));
+
+    Ok(true)
+}
+
+/// Ends one load of a room, returning the PDUs that came in during it; the
+/// last one to finish clears them.
+fn stop_loading(room: &mut EphemeralRoomState) -> Vec<StoredPDU> {
+    room.loading = room.loading.saturating_sub(1);
+
+    if room.loading == 0 {
+        std::mem::take(&mut room.pending_pdus)
+    } else {
+        room.pending_pdus.clone()
     }
 }
 
-pub(crate) fn load_persistent_room(state: &crate::state::State, room_id: &Id<Room>) {
+/// Reads all PDUs of a room, from `persistent.json` and its store.
+fn read_room_pdus(
+    state: &State,
+    room_id: &Id<Room>,
+    room_store: Option<&SharedRoomStore>,
+) -> Result<Vec<StoredPDU>, std::io::Error> {
     let mut pdu_blobs = Vec::new();
-    let mut room_db = None;
-
-    state.with_persistent_mut(|persistent_state| {
-        let room = persistent_state.rooms.get(room_id).unwrap();
 
+    if let Some(room) = state.persistent().rooms.get(room_id) {
         for pdu_blob in &room.pdu_blobs {
-            let pdu_blob: Box<RawValue> = serde_json::from_str(pdu_blob).unwrap();
-            pdu_blobs.push(pdu_blob);
+            match serde_json::from_str(pdu_blob) {
+                // Never recorded for these; see `migrate_json_room`
+                Ok(pdu_blob) => pdu_blobs.push((pdu_blob, None)),
+                Err(err) => eprintln!("Warning: Skipping unreadable PDU of {room_id}: {err}"),
+            }
         }
+    }
 
-        room_db = room.room_db.clone();
-    });
+    if let Some(room_store) = room_store {
+        let mut state_token = PDUToken::start(PDUFileId::State);
+        let mut other_token = PDUToken::start(PDUFileId::Other);
+        let state_pdu_count = read_new_pdus(room_store, &mut state_token, &mut pdu_blobs)?;
+        let other_pdu_count = read_new_pdus(room_store, &mut other_token, &mut pdu_blobs)?;
 
-    let mut room_persistence = None;
+        eprintln!("Loaded PDUs of {room_id}: {state_pdu_count} (state), {other_pdu_count} (other)");
+    }
 
-    if let Some(room_db) = room_db {
-        room_persistence = Some(RoomPersistence::new(room_db).unwrap());
+    Ok(pdu_blobs)
+}
+
+/// Reads everything appended to a stream since `token`, moving it along.
+/// Returns the number of PDUs read.
+fn read_new_pdus(
+    room_store: &SharedRoomStore,
+    token: &mut PDUToken,
+    pdu_blobs: &mut Vec<StoredPDU>,
+) -> Result<usize, std::io::Error> {
+    let mut pdu_count = 0;
+
+    loop {
+        // Not held across pages, so PDUs can still be written in between
+        let (page, next_token) = room_store.lock().read_page(*token, 1000)?;
+        if page.is_empty() {
+            return Ok(pdu_count);
+        }
+
+        pdu_count += page.len();
+        pdu_blobs.extend(
+            page.into_iter()
+                .map(|pdu_blob| (pdu_blob.pdu_blob, pdu_blob.origin)),
+        );
+        *token = next_token;
     }
+}
+
+/// Parses a stored PDU of a room being loaded and checks its hashes and
+/// signatures. Missing signing keys are only fetched if `fetch_keys` is set,
+/// which must not be done under the state locks.
+fn parse_stored_pdu(
+    state: &State,
+    room_id: &Id<Room>,
+    pdu_blob: Box<RawValue>,
+    origin: Option<Box<Id<ServerName>>>,
+    interner: &mut Interner,
+    fetch_keys: bool,
+) -> Option<ParsedPDU> {
+    let pdu_ref = match parse_pdu_ref(&pdu_blob) {
+        Ok(pdu_ref) => pdu_ref,
+        Err(err) => {
+            eprintln!("Warning: Skipping unparseable PDU of {room_id}: {err}");
+            return None;
+        }
+    };
+    let event_id = event_id(&pdu_ref);
+
+    if pdu_ref.room_id != room_id {
+        eprintln!("Warning: PDU {event_id} is not in room {room_id}");
+        return None;
+    }
+
+    let signatures = match &pdu_ref.signatures {
+        Some(signatures) => signatures,
+        None => {
+            eprintln!("Warning: Skipping unsigned PDU {event_id}");
+            return None;
+        }
+    };
+    let sender_name = pdu_ref.sender.server_name();
+
+    if fetch_keys {
+        pdu_ref.fetch_signing_keys(state, sender_name, signatures);
+    }
+
+    let signature_check = pdu_ref.verify(state, sender_name, signatures);
+    let hash_check = verify_content_hash(pdu_blob.get(), false);
+
+    let pdu_arc = PDUArc::from_pdu_ref(&pdu_ref, interner);
+    let arc_event_id = interner.get_or_insert(event_id.as_id());
+    let real_origin = origin.map(|origin| interner.get_or_insert(&*origin));
+    drop(pdu_ref);
+
+    Some(ParsedPDU {
+        event_id,
+        arc_event_id: Some(arc_event_id),
+        real_origin,
+        pdu: pdu_arc,
+        blob: pdu_blob,
+        signature_check: Some(signature_check),
+        hash_check: Some(hash_check),
+    })
+}
+
+fn insert_parsed_pdu(room: &mut EphemeralRoomState, parsed_pdu: ParsedPDU) {
+    let arc_event_id = match &parsed_pdu.arc_event_id {
+        Some(arc_event_id) => arc_event_id.clone(),
+        None => return,
+    };
+
+    if room.pdus.contains_key(&arc_event_id) {
+        return;
+    }
+
+    room.pdus_by_timestamp
+        .insert(parsed_pdu.pdu.origin_server_ts, arc_event_id.clone());
+    room.pdus.insert(arc_event_id, parsed_pdu);
+}
+
+/// Evicts the least recently used rooms (except `keep`) until memory use is
+/// back under the configured budget.
+///
+/// Only rooms that can be loaded again are evicted, i.e. those with a store
+/// or in `persistent.json`; the others only live in memory.
+pub(crate) fn evict_cold_rooms(state: &crate::state::State, keep: Option<&Id<Room>>) {
+    let memory_budget = match state.config.memory_budget_mb {
+        Some(memory_budget_mb) => memory_budget_mb * 1024 * 1024,
+        None => return,
+    };
 
-    let mut state_pdu_count = 0;
-    let mut other_pdu_count = 0;
+    if crate::ALLOCATOR.allocated() <= memory_budget {
+        return;
+    }
 
-    if let Some(room_persistence) = &mut room_persistence {
-        room_persistence.state_pdu_file.read_pdus(|pdu_blob| {
-            pdu_blobs.push(pdu_blob.pdu_blob.to_owned());
-            state_pdu_count += 1;
+    let persistent_rooms: BTreeSet<Box<Id<Room>>> =
+        state.persistent().rooms.keys().cloned().collect();
+
+    while crate::ALLOCATOR.allocated() > memory_budget {
+        let evicted = state.with_ephemeral_mut(|ephemeral_state| {
+            let (room_id, room) = ephemeral_state
+                .rooms
+                .iter_mut()
+                .filter(|(room_id, room)| {
+                    room.resident
+                        && (room.room_store.is_some() || persistent_rooms.contains(*room_id))
+                        && Some(room_id.as_id()) != keep
+                })
+                .min_by_key(|(_room_id, room)| room.last_access)?;
+
+            room.evict();
+            Some(room_id.clone())
         });
 
-        room_persistence.other_pdu_file.read_pdus(|pdu_blob| {
-            pdu_blobs.push(pdu_blob.pdu_blob.to_owned());
-            other_pdu_count += 1;
+        match evicted {
+            Some(room_id) => eprintln!("Evicted room from memory: {room_id}"),
+            None => break,
+        }
+    }
+}
+
+/// Moves a room's PDUs out of `persistent.json` into its own `RoomStore`,
+/// returning the number of PDUs moved.
+///
+/// Origins are taken from the PDUs received since startup where known, and
+/// from the `origin` field of the PDU otherwise.
+pub(crate) fn migrate_json_room(state: &State, room_id: &Id<Room>) -> Result<usize, String> {
+    // Its PDUs would be gone after a restart, while `room_db` says they aren't
+    // in `persistent.json` anymore
+    if let RoomStoreKind::Memory = state.config.room_store {
+        return Err("Rooms can't be migrated to the memory room store".to_string());
+    }
+
+    let pdu_blobs = {
+        let persistent_state = state.persistent();
+        let room = persistent_state
+            .rooms
+            .get(room_id)
+            .ok_or_else(|| format!("Unknown room {}", room_id))?;
+
+        if let Some(room_db) = &room.room_db {
+            return Err(format!("Room already stored in {}", room_db));
+        }
+
+        room.pdu_blobs.clone()
+    };
+
+    // Room IDs can contain anything, and any mapping that just replaces the
+    // characters that aren't safe in file names could give two rooms the same
+    // directory
+    let room_id_hash = sha2::Sha256::digest(room_id.as_str().as_bytes());
+    let room_db = format!(
+        "db.room.{}",
+        base64::encode_config(room_id_hash, base64::URL_SAFE_NO_PAD)
+    );
+    let storage_path = state.data_dir.join(&room_db);
+
+    if storage_path.exists() {
+        return Err(format!("{} already exists", room_db));
+    }
+
+    let mut room_store = open_room_store(state.config.room_store, &storage_path)
+        .map_err(|err| format!("Could not open {}: {}", room_db, err))?;
+
+    // Leaves the room in `persistent.json`, where all of its PDUs still are
+    let abandon = |err: String| {
+        state.with_ephemeral_mut(|ephemeral_state| {
+            if let Some(room) = ephemeral_state.rooms.get_mut(room_id) {
+                room.room_store = None;
+            }
         });
+
+        if storage_path.exists() {
+            if let Err(remove_err) = std::fs::remove_dir_all(&storage_path) {
+                eprintln!(
+                    "Could not remove {}: {}",
+                    storage_path.display(),
+                    remove_err
+                );
+            }
+        }
+
+        err
+    };
+
+    let real_origins: BTreeMap<Box<Id<Event>>, Box<Id<ServerName>>> = state
+        .ephemeral()
+        .rooms
+        .get(room_id)
+        .map(|room| {
+            room.pdus
+                .values()
+                .filter_map(|parsed_pdu| {
+                    let real_origin = parsed_pdu.real_origin.as_ref()?;
+                    Some((parsed_pdu.event_id.clone(), real_origin.as_id().to_owned()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for pdu_blob in &pdu_blobs {
+        if let Err(err) = migrate_pdu(&mut *room_store, &real_origins, pdu_blob) {
+            drop(room_store);
+            return Err(abandon(err));
+        }
     }
 
-    eprintln!("Loaded PDUs: {state_pdu_count} (state), {other_pdu_count} (other)");
+    let mut migrated_count = pdu_blobs.len();
 
+    // New PDUs for the room go into the store from here on, as well as into
+    // `persistent.json` until the room is switched over
     state.with_ephemeral_mut(|ephemeral_state| {
         let room = ephemeral_state.rooms.entry(room_id.to_owned()).or_default();
-        let interner = &mut room.interner;
+        room.room_store = Some(SharedRoomStore::new(room_store));
+    });
+
+    // The two locks are never held at once, so PDUs that arrived while the
+    // bulk was being copied get caught up with until there are none left
+    loop {
+        let catch_up = state.with_persistent_mut(|persistent_state| {
+            let room = persistent_state
+                .rooms
+                .get_mut(room_id)
+                .ok_or_else(|| format!("Room {} disappeared", room_id))?;
+
+            if room.pdu_blobs.len() > migrated_count {
+                return Ok(Some(room.pdu_blobs[migrated_count..].to_vec()));
+            }
 
-        room.room_persistence = room_persistence;
+            room.pdu_blobs.clear();
+            room.room_db = Some(room_db.clone());
 
-        for pdu_blob in pdu_blobs {
-            let pdu_ref = parse_pdu_ref(&pdu_blob).unwrap();
-            let event_id = pdu_ref.generate_event_id();
+            Ok::<_, String>(None)
+        });
+
+        let pdu_blobs = match catch_up {
+            Ok(Ok(None)) => return Ok(migrated_count),
+            Ok(Ok(Some(pdu_blobs))) => pdu_blobs,
+            Ok(Err(err)) => return Err(abandon(err)),
+            Err(err) => {
+                let err = format!("Could not save persistent state: {}", err);
 
-            if pdu_ref.room_id != room_id {
-                eprintln!("Warning: PDU {event_id} is not in room {room_id}");
-                continue;
+                // Only written to disk later, but already switched over
+                let switched = state
+                    .persistent()
+                    .rooms
+                    .get(room_id)
+                    .is_some_and(|room| room.room_db.is_some());
+
+                return Err(if switched { err } else { abandon(err) });
             }
+        };
 
-            let signatures = pdu_ref.signatures.as_ref().unwrap();
-            let sender_name = pdu_ref.sender.server_name();
+        let catch_up_result = state.with_ephemeral_mut(|ephemeral_state| {
+            let mut room_store = ephemeral_state
+                .rooms
+                .get(room_id)
+                .and_then(|room| room.room_store.as_ref())
+                .ok_or_else(|| format!("Room {} lost its store", room_id))?
+                .lock();
 
-            let signature_check = pdu_ref.verify(state, sender_name, signatures);
-            let hash_check = verify_content_hash(pdu_blob.get(), false);
+            for pdu_blob in &pdu_blobs {
+                migrate_pdu(&mut **room_store, &real_origins, pdu_blob)?;
+            }
 
-            let pdu_arc = PDUArc::from_pdu_ref(&pdu_ref, interner);
-            let arc_event_id = interner.get_or_insert(event_id.as_id());
-            drop(pdu_ref);
-            let timestamp = pdu_arc.origin_server_ts;
-
-            let parsed_pdu = ParsedPDU {
-                event_id,
-                arc_event_id: Some(arc_event_id.clone()),
-                real_origin: None, // FIXME
-                pdu: pdu_arc,
-                blob: pdu_blob,
-                signature_check: Some(signature_check),
-                hash_check: Some(hash_check),
-            };
+            Ok(())
+        });
 
-            room.pdus_by_timestamp
-                .insert(timestamp, arc_event_id.clone());
-            room.pdus.insert(arc_event_id, parsed_pdu);
+        if let Err(err) = catch_up_result {
+            return Err(abandon(err));
         }
-    });
+
+        migrated_count += pdu_blobs.len();
+    }
+}
+
+fn migrate_pdu(
+    room_store: &mut dyn RoomStore,
+    real_origins: &BTreeMap<Box<Id<Event>>, Box<Id<ServerName>>>,
+    pdu_blob: &str,
+) -> Result<(), String> {
+    let pdu_blob: &RawValue =
+        serde_json::from_str(pdu_blob).map_err(|err| format!("Invalid PDU: {}", err))?;
+    let pdu_ref = parse_pdu_ref(pdu_blob).map_err(|err| format!("Invalid PDU: {}", err))?;
+    let event_id = event_id(&pdu_ref);
+
+    let real_origin = real_origins.get(&event_id).map(|origin| &**origin);
+    let origin = real_origin.or(pdu_ref.origin);
+    let file = if AnyContentRef::has_state(&pdu_ref.state_key) {
+        PDUFileId::State
+    } else {
+        PDUFileId::Other
+    };
+
+    room_store
+        .append_pdu(file, &event_id, origin, pdu_blob)
+        .map_err(|err| format!("Could not write {}: {}", event_id, err))
 }
 
 pub(crate) struct Transaction<'a> {
@@ -650,16 +1138,25 @@ pub(crate) fn ingest_transaction(
         }
     }
 
+    // Signatures get checked under the ephemeral lock below, where waiting on
+    // a slow server would hold up every other request
+    for (pdu_ref, _pdu_blob) in &parsed_pdus {
+        if let Some(signatures) = &pdu_ref.signatures {
+            pdu_ref.fetch_signing_keys(state, pdu_ref.sender.server_name(), signatures);
+        }
+    }
+
     let mut pdu_results = BTreeMap::new();
 
-    state.with_persistent_mut(|persistent_state| {
+    let persist_result = state.with_persistent_mut(|persistent_state| {
         for (pdu_ref, pdu_blob) in &parsed_pdus {
-            let event_id = pdu_ref.generate_event_id();
+            let event_id = event_id(&pdu_ref);
             if let Some(room) = persistent_state.rooms.get_mut(pdu_ref.room_id.as_id()) {
                 if room.room_db.is_none() {
                     // Warning: this loses the real origin
                     eprintln!("* Got json-persistent room PDU: {event_id}");
-                    room.pdu_blobs.push(pdu_blob.get().to_owned());
+                    // Replayed transactions may have gotten this far before
+                    room.push_pdu_blob(&event_id, pdu_blob.get());
                 } else {
                     eprintln!("* Got gz-persistent room PDU: {event_id}");
                 }
@@ -672,33 +1169,75 @@ pub(crate) fn ingest_transaction(
         }
     });
 
+    if let Err(err) = persist_result {
+        // The PDUs are still in memory, so this only matters after a restart
+        eprintln!("* Could not save persistent state: {}", err);
+    }
+
     state.with_ephemeral_mut(|ephemeral_state| {
+        // The room stores are closed by now
+        if state.is_shutting_down() {
+            for (pdu_ref, _pdu_blob) in parsed_pdus {
+                let err = "Server is shutting down".to_string();
+                pdu_results.insert(event_id(&pdu_ref), Err(err));
+            }
+
+            return;
+        }
+
         for (pdu_ref, pdu_blob) in parsed_pdus {
             let server_name = pdu_ref.sender.server_name();
             let signatures = pdu_ref.signatures.as_ref().unwrap();
             let signature_check = pdu_ref.verify(state, server_name, signatures);
             let hash_check = verify_content_hash(pdu_blob.get(), false);
-            let event_id = pdu_ref.generate_event_id();
+            let event_id = event_id(&pdu_ref);
 
             if let Some(room) = ephemeral_state.rooms.get_mut(pdu_ref.room_id) {
-                if let Some(room_persistence) = &mut room.room_persistence {
-                    if AnyContentRef::has_state(&pdu_ref.state_key) {
+                let write_result = if let Some(room_store) = &room.room_store {
+                    let mut room_store = room_store.lock();
+
+                    if room_store.contains_pdu(&event_id) {
+                        eprintln!("* Got already persisted PDU: {event_id}");
+                        Ok(())
+                    } else if AnyContentRef::has_state(&pdu_ref.state_key) {
                         eprintln!("* Got persisted state PDU: {event_id}");
-                        room_persistence.state_pdu_file.write_pdu(
+                        room_store.append_pdu(
+                            PDUFileId::State,
                             &event_id,
                             origin,
                             pdu_blob,
-                        );
+                        )
                     } else {
                         eprintln!("* Got persisted non-state PDU: {event_id}");
-                        room_persistence.other_pdu_file.write_pdu(
+                        room_store.append_pdu(
+                            PDUFileId::Other,
                             &event_id,
                             origin,
                             pdu_blob,
-                        );
+                        )
                     }
                 } else {
                     eprintln!("* Got ephemeral PDU: {event_id}");
+                    Ok(())
+                };
+
+                if let Err(err) = write_result {
+                    eprintln!("* Could not persist PDU {event_id}: {err}");
+                    let err = format!("Could not persist PDU: {}", err);
+                    pdu_results.insert(event_id, Err(err));
+                    continue;
+                }
+
+                // Cold rooms pick it up from their store once loaded again,
+                // unless a load may have read past it already
+                if !room.resident {
+                    if room.loading > 0 {
+                        let origin = origin.map(ToOwned::to_owned);
+                        room.pending_pdus.push((pdu_blob.to_owned(), origin));
+                    }
+
+                    pdu_results.insert(event_id, Ok(()));
+                    continue;
                 }
 
                 let interner = &mut room.interner;
@@ -729,6 +1268,7 @@ pub(crate) fn ingest_transaction(
                 room.pdus_by_timestamp
                     .insert(parsed_pdu.pdu.origin_server_ts, arc_event_id.clone());
                 room.pdus.insert(arc_event_id, parsed_pdu);
+                room.last_access = Some(Instant::now());
                 pdu_results.insert(event_id, Ok(()));
             } else {
                 eprintln!("* Alien PDU dropped: {event_id} (room {})", pdu_ref.room_id);
@@ -736,5 +1276,7 @@ pub(crate) fn ingest_transaction(
         }
     });
 
+    evict_cold_rooms(state, None);
+
     pdu_results
 }
@@ -21,3 +21,18 @@ impl<T> RenderedJson<'static, T> {
         }
     }
 }
+
+impl<'a, T> RenderedJson<'a, T> {
+    pub(crate) fn from_trusted_str(json_str: &'a str) -> Self {
+        let raw_value: &serde_json::value::RawValue =
+            serde_json::from_str(json_str).expect("Trusted JSON source");
+        RenderedJson {
+            phantom: Default::default(),
+            bytes: Cow::Borrowed(raw_value),
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        self.bytes.get()
+    }
+}
@@ -2,18 +2,29 @@ use std::borrow::Cow;
 
 use askama::Template;
 use bumpalo::{collections::CollectIn, Bump};
-use fluctlight_mod_interface::{Request, Response};
+use fluctlight_mod_interface::{Request, RequestHead, Response};
 use percent_encoding::percent_decode_str;
-use serde::{de::MapAccess, forward_to_deserialize_any, Deserialize, Deserializer, Serialize};
+use serde::{
+    de::{value::BorrowedStrDeserializer, MapAccess},
+    forward_to_deserialize_any, Deserialize, Deserializer, Serialize,
+};
+use serde_json::value::RawValue;
 use smallvec::SmallVec;
 
 use crate::{
+    matrix_types::{Id, MatrixId},
     net_log::{log_network_request, log_network_response},
     routes_admin::admin_api_handler,
     routes_federation::federation_api_handler,
+    signed_request::parse_x_matrix,
     state::State,
 };
 
+/// Requests other than transactions carry at most a few events or a query.
+const DEFAULT_BODY_LIMIT: u64 = 1024 * 1024;
+/// Up to 50 PDUs and 100 EDUs, each of them at most 64 KiB.
+const TRANSACTION_BODY_LIMIT: u64 = 150 * 64 * 1024;
+
 pub(crate) struct RequestData<'a> {
     pub memory_pool: &'a Bump,
     pub state: &'a State,
@@ -27,6 +38,14 @@ impl<'r> RequestData<'r> {
         BumpString::from_str_in(s, self.memory_pool).into_bump_str()
     }
 
+    pub fn new_id<T: MatrixId>(&self, id: &Id<T>) -> &'r Id<T> {
+        Id::try_from_str(self.new_str(id.as_str())).expect("Already validated")
+    }
+
+    pub fn new_raw_value(&self, raw_value: &RawValue) -> &'r RawValue {
+        serde_json::from_str(self.new_str(raw_value.get())).expect("Already valid JSON")
+    }
+
     pub fn handle_with<F, Path, QueryString, Body>(
         &'r self,
         handler: F,
@@ -42,42 +61,38 @@ impl<'r> RequestData<'r> {
         Body: Deserialize<'r>,
         <GenericRequest<Path, QueryString, Body> as MatrixRequest>::Response: Serialize,
     {
-        let body = if self.http_request.method() == "GET" {
-            b"{}".as_slice()
-        } else {
-            self.http_request.body()
-        };
-
-        type BumpVec<'b, T> = bumpalo::collections::Vec<'b, T>;
-
-        let path = self.http_request.uri().path();
-        let path_segments: BumpVec<_> = path.split('/').collect_in(self.memory_pool);
-        let spec = <GenericRequest<Path, QueryString, Body> as MatrixRequest>::PATH_SPEC;
-        let spec_segments: BumpVec<_> = spec.split('/').collect_in(self.memory_pool);
+        let request = self.deserialize_request()?;
+        let response = handler(self, request);
 
-        let mut path_deserializer = RequestPathDeserializer {
-            path_segments: &path_segments,
-            spec_segments: &spec_segments,
-            next_value: None,
-        };
+        json_response(200, &response)
+    }
 
-        let request_path = Path::deserialize(&mut path_deserializer)
-            .map_err(|err| format!("Could not deserialize the request path: {}", err))?;
-        let request_qs = serde_json::from_slice(b"{}")
-            .map_err(|err| format!("Could not deserialize the request's query string: {}", err))?;
-        let request_body = serde_json::from_slice(body)
-            .map_err(|err| format!("Could not deserialize the request body: {}", err))?;
+    /// Like `handle_with`, for handlers that can turn the request down with
+    /// a Matrix error.
+    pub fn try_handle_with<F, Path, QueryString, Body>(
+        &'r self,
+        handler: F,
+    ) -> Result<http::Response<Vec<u8>>, String>
+    where
+        F: Fn(
+            &RequestData<'r>,
+            GenericRequest<Path, QueryString, Body>,
+        ) -> Result<
+            <GenericRequest<Path, QueryString, Body> as MatrixRequest>::Response,
+            MatrixError,
+        >,
+        GenericRequest<Path, QueryString, Body>: MatrixRequest,
+        Path: Deserialize<'r>,
+        QueryString: Deserialize<'r>,
+        Body: Deserialize<'r>,
+        <GenericRequest<Path, QueryString, Body> as MatrixRequest>::Response: Serialize,
+    {
+        let request = self.deserialize_request()?;
 
-        let request = GenericRequest::new(request_path, request_qs, request_body);
-        let response = handler(self, request);
-        let mut response_bytes = serde_json::to_vec(&response)
-            .map_err(|err| format!("Could not serialize response: {}", err))?;
-        response_bytes.push(b'\n');
-        let http_response = http::Response::builder()
-            .header("Content-Type", "application/json")
-            .body(response_bytes)
-            .expect("Response should always be valid");
-        Ok(http_response)
+        match handler(self, request) {
+            Ok(response) => json_response(200, &response),
+            Err(matrix_error) => json_response(matrix_error.status, &matrix_error),
+        }
     }
 
     pub fn render_template_with<F, Path, QueryString, Body>(
@@ -94,6 +109,39 @@ impl<'r> RequestData<'r> {
         QueryString: Deserialize<'r>,
         Body: Deserialize<'r>,
         <GenericRequest<Path, QueryString, Body> as MatrixRequest>::Response: Template,
+    {
+        let request = self.deserialize_request()?;
+        let response = handler(self, request);
+
+        let response_bytes = response
+            .render()
+            .map_err(|err| format!("Could not render response from template: {}", err))?;
+        let http_response = http::Response::builder()
+            .header("Content-Type", "text/html")
+            .body(response_bytes.into_bytes())
+            .expect("Response should always be valid");
+        Ok(http_response)
+    }
+
+    /// Every value given for `name` in the query string, for the parameters
+    /// that may be repeated (which `QueryString` types can't hold).
+    pub fn query_values(&'r self, name: &str) -> bumpalo::collections::Vec<'r, &'r str> {
+        let query = self.http_request.uri().query().unwrap_or("");
+
+        url::form_urlencoded::parse(query.as_bytes())
+            .filter(|(pair_name, _value)| pair_name == name)
+            .map(|(_name, value)| self.bump_cow(value))
+            .collect_in(self.memory_pool)
+    }
+
+    fn deserialize_request<Path, QueryString, Body>(
+        &'r self,
+    ) -> Result<GenericRequest<Path, QueryString, Body>, String>
+    where
+        GenericRequest<Path, QueryString, Body>: MatrixRequest,
+        Path: Deserialize<'r>,
+        QueryString: Deserialize<'r>,
+        Body: Deserialize<'r>,
     {
         let body = if self.http_request.method() == "GET" {
             b"{}".as_slice()
@@ -116,49 +164,113 @@ impl<'r> RequestData<'r> {
 
         let request_path = Path::deserialize(&mut path_deserializer)
             .map_err(|err| format!("Could not deserialize the request path: {}", err))?;
-        let request_qs = serde_json::from_slice(b"{}")
-            .map_err(|err| format!("Could not deserialize the request's query string: {}", err))?;
+        let request_qs = self.deserialize_query_string()?;
         let request_body = serde_json::from_slice(body)
             .map_err(|err| format!("Could not deserialize the request body: {}", err))?;
 
-        let request = GenericRequest::new(request_path, request_qs, request_body);
-        let response = handler(self, request);
+        Ok(GenericRequest::new(request_path, request_qs, request_body))
+    }
 
-        let response_bytes = response
-            .render()
-            .map_err(|err| format!("Could not render response from template: {}", err))?;
-        let http_response = http::Response::builder()
-            .header("Content-Type", "text/html")
-            .body(response_bytes.into_bytes())
-            .expect("Response should always be valid");
-        Ok(http_response)
+    fn deserialize_query_string<QueryString>(&'r self) -> Result<QueryString, String>
+    where
+        QueryString: Deserialize<'r>,
+    {
+        let query = self.http_request.uri().query().unwrap_or("");
+
+        // Only values that needed decoding get copied
+        let pairs: bumpalo::collections::Vec<_> = url::form_urlencoded::parse(query.as_bytes())
+            .map(|(name, value)| (self.bump_cow(name), self.bump_cow(value)))
+            .collect_in(self.memory_pool);
+
+        let mut query_deserializer = RequestQueryDeserializer {
+            pairs: &pairs,
+            next_value: None,
+        };
+
+        QueryString::deserialize(&mut query_deserializer)
+            .map_err(|err| format!("Could not deserialize the request's query string: {}", err))
+    }
+
+    fn bump_cow(&self, s: Cow<'r, str>) -> &'r str {
+        match s {
+            Cow::Borrowed(s) => s,
+            Cow::Owned(s) => self.new_str(&s),
+        }
+    }
+}
+
+/// Turns down unauthenticated federation requests before their body gets
+/// read, and otherwise tells how large the body may be for the route.
+///
+/// Signatures can only be verified along with the body, so this only checks
+/// that there is something to verify.
+pub(super) fn check_request_head(head: &RequestHead) -> Result<u64, Response> {
+    let mut uri_segments: SmallVec<[&str; 8]> = head.path().split('/').collect();
+    uri_segments[0] = head.method();
+
+    let needs_authentication = match uri_segments.as_slice() {
+        [_, "_matrix", "federation", _, "version"] => false,
+        [_, "_matrix", "federation", ..] => true,
+        _ => false,
+    };
+
+    if needs_authentication {
+        let authenticated = head
+            .authorization()
+            .and_then(parse_x_matrix)
+            .is_some_and(|auth| {
+                !auth.origin.is_empty() && auth.key.starts_with("ed25519:") && !auth.sig.is_empty()
+            });
+
+        if !authenticated {
+            return Err(Response::new(
+                401,
+                "application/json",
+                br#"{"errcode":"M_UNAUTHORIZED","error":"Missing or malformed X-Matrix authorization"}"#
+                    .as_slice()
+                    .into(),
+            ));
+        }
     }
+
+    let body_limit = match uri_segments.as_slice() {
+        ["GET", ..] => 0,
+        ["PUT", "_matrix", "federation", "v1", "send", _] => TRANSACTION_BODY_LIMIT,
+        _ => DEFAULT_BODY_LIMIT,
+    };
+
+    Ok(body_limit)
 }
 
 pub(super) fn try_process_request<'a>(
     state: &State,
     request: Request<'a>,
 ) -> Response {
-    let mut uri_segments: SmallVec<[&str; 8]> = request.uri().split('/').collect();
+    let mut uri_segments: SmallVec<[&str; 8]> = request.path().split('/').collect();
     uri_segments[0] = request.method();
 
-    let path = percent_decode_str(request.uri())
+    let mut uri = percent_decode_str(request.path())
         .decode_utf8_lossy()
         .to_string();
 
-    let http_request = http::Request::builder()
-        .method(request.method())
-        .uri(&path)
+    if let Some(query) = request.query() {
+        uri.push('?');
+        uri.push_str(query);
+    }
+
+    let mut http_request = http::Request::builder().method(request.method()).uri(&uri);
+
+    for header in request.headers() {
+        http_request = http_request.header(header.name(), header.value());
+    }
+
+    let http_request = http_request
         .body(request.body())
         .expect("Request should always be valid");
 
-    let log_index = state.with_persistent_mut(|state| {
-        let index = state.net_log_index;
-        state.net_log_index += 1;
-        index
-    });
+    let log_index = state.next_net_log_index();
 
-    log_network_request(log_index, &http_request, "in");
+    log_network_request(&state.data_dir, log_index, &http_request, "in");
 
     let memory_pool = bumpalo::Bump::with_capacity(256);
     let request_data = RequestData {
@@ -184,6 +296,7 @@ pub(super) fn try_process_request<'a>(
     if let Err(err) = &http_response {
         // FIXME: This should probably be done by the main code too
         log_network_response(
+            &state.data_dir,
             log_index,
             &http::Response::builder()
                 .status(501)
@@ -206,7 +319,7 @@ pub(super) fn try_process_request<'a>(
         },
     };
 
-    log_network_response(log_index, &http_response, "in");
+    log_network_response(&state.data_dir, log_index, &http_response, "in");
 
     let content_type = match http_response
         .headers()
@@ -218,16 +331,25 @@ pub(super) fn try_process_request<'a>(
         Some(_) | None => "text/plain",
     };
 
-    return Response::new(
-        http_response.status().as_u16(),
-        content_type,
-        http_response.into_body().into(),
-    );
+    let (parts, body) = http_response.into_parts();
+    let mut response = Response::new(parts.status.as_u16(), content_type, body.into());
+
+    for (name, value) in &parts.headers {
+        if name == http::header::CONTENT_TYPE {
+            continue;
+        }
+
+        if let Ok(value) = value.to_str() {
+            response = response.with_header(name.as_str(), value);
+        }
+    }
+
+    response
 }
 
 pub(crate) struct GenericRequest<Path, QueryString, Body> {
     pub path: Path,
-    pub _query_string: QueryString,
+    pub query_string: QueryString,
     pub body: Body,
 }
 
@@ -235,7 +357,7 @@ impl<'r, Path, QueryString, Body> GenericRequest<Path, QueryString, Body> {
     pub fn new(path: Path, query_string: QueryString, body: Body) -> Self {
         GenericRequest {
             path,
-            _query_string: query_string,
+            query_string,
             body,
         }
     }
@@ -246,6 +368,41 @@ pub(crate) trait MatrixRequest {
     const PATH_SPEC: &'static str;
 }
 
+/// An error as the Matrix APIs return it, along with its HTTP status.
+#[derive(Serialize)]
+pub(crate) struct MatrixError {
+    #[serde(skip)]
+    pub status: u16,
+    pub errcode: &'static str,
+    pub error: String,
+    /// Only for `M_INCOMPATIBLE_ROOM_VERSION`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_version: Option<String>,
+}
+
+impl MatrixError {
+    pub fn new(status: u16, errcode: &'static str, error: impl Into<String>) -> Self {
+        MatrixError {
+            status,
+            errcode,
+            error: error.into(),
+            room_version: None,
+        }
+    }
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Result<http::Response<Vec<u8>>, String> {
+    let mut response_bytes =
+        serde_json::to_vec(body).map_err(|err| format!("Could not serialize response: {}", err))?;
+    response_bytes.push(b'\n');
+    let http_response = http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(response_bytes)
+        .expect("Response should always be valid");
+    Ok(http_response)
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct EmptyPath<'a> {
     #[serde(skip)]
@@ -409,3 +566,119 @@ impl<'de, 'a> Deserializer<'de> for &'a mut RequestPathFieldDeserializer<'de> {
         tuple_struct map struct enum identifier ignored_any
     }
 }
+
+// Request query string deserializer
+struct RequestQueryDeserializer<'de, 'a> {
+    pairs: &'a [(&'de str, &'de str)],
+    next_value: Option<&'de str>,
+}
+
+impl<'de, 'a> Deserializer<'de> for &'a mut RequestQueryDeserializer<'de, 'a> {
+    type Error = RequestDeserializationError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_map(RequestQueryMapAccess(self))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct RequestQueryMapAccess<'de, 'a>(&'a mut RequestQueryDeserializer<'de, 'a>);
+
+impl<'de, 'a> MapAccess<'de> for RequestQueryMapAccess<'de, 'a> {
+    type Error = RequestDeserializationError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: serde::de::DeserializeSeed<'de>,
+    {
+        let ((name, value), rest) = match self.0.pairs.split_first() {
+            Some(pair_and_rest) => pair_and_rest,
+            None => return Ok(None),
+        };
+
+        self.0.pairs = rest;
+        self.0.next_value = Some(value);
+
+        seed.deserialize(BorrowedStrDeserializer::new(name))
+            .map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let value = self
+            .0
+            .next_value
+            .take()
+            .expect("Guaranteed by next_key_seed()");
+        seed.deserialize(RequestQueryValueDeserializer(value))
+    }
+}
+
+/// Query string values are all strings, so numbers and booleans get parsed
+/// from them when that is what the field expects.
+struct RequestQueryValueDeserializer<'de>(&'de str);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: serde::de::Visitor<'de>,
+            {
+                let value = self.0.parse().map_err(|_| {
+                    RequestDeserializationError(format!("Invalid query string value '{}'", self.0))
+                })?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for RequestQueryValueDeserializer<'de> {
+    type Error = RequestDeserializationError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct newtype_struct seq
+        tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
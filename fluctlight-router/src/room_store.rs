@@ -0,0 +1,484 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::{
+    matrix_types::{Event, Id, ServerName},
+    persistence::{OwnedPDUBlob, PDUFileId, PDUToken, RoomPersistence},
+};
+
+/// Which `RoomStore` implementation rooms are stored with.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RoomStoreKind {
+    /// Flat PDU files in a directory per room; see `RoomPersistence`.
+    #[default]
+    Files,
+    /// Nothing is written to disk; for tests and throwaway servers.
+    Memory,
+    /// An embedded `sled` database per room; needs the `sled` feature.
+    Sled,
+}
+
+/// Storage for everything known about a single room.
+///
+/// PDUs are kept in two streams (state and other), each readable in the
+/// order they were appended.
+pub(crate) trait RoomStore: Send + Sync {
+    /// Appends a PDU to the end of a stream, unless already stored.
+    fn append_pdu(
+        &mut self,
+        file: PDUFileId,
+        event_id: &Id<Event>,
+        origin: Option<&Id<ServerName>>,
+        pdu_blob: &RawValue,
+    ) -> Result<(), std::io::Error>;
+
+    fn contains_pdu(&self, event_id: &Id<Event>) -> bool;
+
+    fn read_pdu(&mut self, event_id: &Id<Event>) -> Result<Option<OwnedPDUBlob>, std::io::Error>;
+
+    /// Reads up to `limit` PDUs of a stream starting at `from`, returning
+    /// them along with the token to continue from.
+    fn read_page(
+        &mut self,
+        from: PDUToken,
+        limit: usize,
+    ) -> Result<(Vec<OwnedPDUBlob>, PDUToken), std::io::Error>;
+
+    /// Makes sure everything written so far would survive a power loss.
+    fn sync(&mut self) -> Result<(), std::io::Error>;
+}
+
+/// Opens the store of a room with the configured implementation.
+pub(crate) fn open_room_store(
+    kind: RoomStoreKind,
+    storage_path: &Path,
+) -> Result<Box<dyn RoomStore>, std::io::Error> {
+    match kind {
+        RoomStoreKind::Files => Ok(Box::new(RoomPersistence::new(storage_path)?)),
+        RoomStoreKind::Memory => Ok(Box::new(MemoryRoomStore::default())),
+        #[cfg(feature = "sled")]
+        RoomStoreKind::Sled => Ok(Box::new(sled_store::SledRoomStore::new(storage_path)?)),
+        #[cfg(not(feature = "sled"))]
+        RoomStoreKind::Sled => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Built without the sled feature",
+        )),
+    }
+}
+
+/// A room's store, shared so that it can be read without holding the lock of
+/// the whole ephemeral state, like when loading a room.
+#[derive(Clone)]
+pub(crate) struct SharedRoomStore(Arc<Mutex<Box<dyn RoomStore>>>);
+
+impl SharedRoomStore {
+    pub(crate) fn new(room_store: Box<dyn RoomStore>) -> Self {
+        SharedRoomStore(Arc::new(Mutex::new(room_store)))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Box<dyn RoomStore>> {
+        // Stores are only ever appended to, and a half-written PDU is dealt
+        // with by the store itself
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MemoryRoomStore {
+    streams: [Vec<OwnedPDUBlob>; 2],
+    positions: BTreeMap<Box<Id<Event>>, (PDUFileId, usize)>,
+}
+
+impl RoomStore for MemoryRoomStore {
+    fn append_pdu(
+        &mut self,
+        file: PDUFileId,
+        event_id: &Id<Event>,
+        origin: Option<&Id<ServerName>>,
+        pdu_blob: &RawValue,
+    ) -> Result<(), std::io::Error> {
+        if self.contains_pdu(event_id) {
+            return Ok(());
+        }
+
+        let stream = &mut self.streams[file as usize];
+        self.positions
+            .insert(event_id.to_owned(), (file, stream.len()));
+        stream.push(OwnedPDUBlob {
+            event_id: event_id.to_owned(),
+            origin: origin.map(|origin| origin.to_owned()),
+            pdu_blob: pdu_blob.to_owned(),
+        });
+
+        Ok(())
+    }
+
+    fn contains_pdu(&self, event_id: &Id<Event>) -> bool {
+        self.positions.contains_key(event_id)
+    }
+
+    fn read_pdu(&mut self, event_id: &Id<Event>) -> Result<Option<OwnedPDUBlob>, std::io::Error> {
+        Ok(self
+            .positions
+            .get(event_id)
+            .map(|&(file, index)| self.streams[file as usize][index].clone()))
+    }
+
+    fn read_page(
+        &mut self,
+        from: PDUToken,
+        limit: usize,
+    ) -> Result<(Vec<OwnedPDUBlob>, PDUToken), std::io::Error> {
+        let stream = &self.streams[from.file as usize];
+        let start = (from.index as usize).min(stream.len());
+        let end = start.saturating_add(limit).min(stream.len());
+
+        let token = PDUToken {
+            index: end as u32,
+            ..from
+        };
+
+        Ok((stream[start..end].to_vec(), token))
+    }
+
+    fn sync(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled")]
+mod sled_store {
+    use std::path::Path;
+
+    use serde_json::value::RawValue;
+    use sled::transaction::{TransactionError, Transactional};
+
+    use super::RoomStore;
+    use crate::{
+        matrix_types::{Event, Id, ServerName},
+        persistence::{OwnedPDUBlob, PDUBlob, PDUFileId, PDUToken},
+    };
+
+    /// A room stored in a `sled` database.
+    ///
+    /// Each stream is a tree of PDUs keyed by big-endian sequence numbers, so
+    /// that iterating the tree follows stream order. Sequence numbers are
+    /// split across the token's block (high bits) and index (low bits).
+    pub(crate) struct SledRoomStore {
+        db: sled::Db,
+        streams: [sled::Tree; 2],
+        /// Event ID to `<file:1><sequence:8>`.
+        positions: sled::Tree,
+    }
+
+    impl SledRoomStore {
+        pub(crate) fn new(storage_path: &Path) -> Result<Self, std::io::Error> {
+            let db = sled::open(storage_path.join("room.sled"))?;
+
+            Ok(SledRoomStore {
+                streams: [db.open_tree("state_pdus")?, db.open_tree("other_pdus")?],
+                positions: db.open_tree("positions")?,
+                db,
+            })
+        }
+    }
+
+    fn token_sequence(token: PDUToken) -> u64 {
+        (token.block as u64) << 32 | token.index as u64
+    }
+
+    fn sequence_token(file: PDUFileId, sequence: u64) -> PDUToken {
+        PDUToken {
+            file,
+            block: (sequence >> 32) as u32,
+            index: sequence as u32,
+        }
+    }
+
+    impl RoomStore for SledRoomStore {
+        fn append_pdu(
+            &mut self,
+            file: PDUFileId,
+            event_id: &Id<Event>,
+            origin: Option<&Id<ServerName>>,
+            pdu_blob: &RawValue,
+        ) -> Result<(), std::io::Error> {
+            if self.contains_pdu(event_id) {
+                return Ok(());
+            }
+
+            let stream = &self.streams[file as usize];
+            let sequence = match stream.last()? {
+                Some((key, _)) => {
+                    let key: [u8; 8] = key.as_ref().try_into().map_err(|_| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt PDU key")
+                    })?;
+                    u64::from_be_bytes(key) + 1
+                }
+                None => 0,
+            };
+
+            let bytes = serde_json::to_vec(&PDUBlob {
+                event_id,
+                origin,
+                pdu_blob,
+            })?;
+            let key = sequence.to_be_bytes();
+            let mut position = vec![file as u8];
+            position.extend_from_slice(&key);
+
+            (stream, &self.positions)
+                .transaction(|(stream, positions)| {
+                    stream.insert(&key[..], &bytes[..])?;
+                    positions.insert(event_id.as_str().as_bytes(), &position[..])?;
+                    Ok(())
+                })
+                .map_err(|err: TransactionError<()>| match err {
+                    TransactionError::Storage(err) => std::io::Error::from(err),
+                    TransactionError::Abort(()) => unreachable!("Never aborted"),
+                })?;
+
+            self.db.flush()?;
+
+            Ok(())
+        }
+
+        fn contains_pdu(&self, event_id: &Id<Event>) -> bool {
+            // A read error only means the PDU gets written again
+            matches!(self.positions.contains_key(event_id.as_str()), Ok(true))
+        }
+
+        fn read_pdu(
+            &mut self,
+            event_id: &Id<Event>,
+        ) -> Result<Option<OwnedPDUBlob>, std::io::Error> {
+            let position = match self.positions.get(event_id.as_str())? {
+                Some(position) if position.len() == 9 => position,
+                Some(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Corrupt PDU position",
+                    ))
+                }
+                None => return Ok(None),
+            };
+
+            let file = if position[0] == PDUFileId::State as u8 {
+                PDUFileId::State
+            } else {
+                PDUFileId::Other
+            };
+
+            match self.streams[file as usize].get(&position[1..])? {
+                Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+                None => Ok(None),
+            }
+        }
+
+        fn read_page(
+            &mut self,
+            from: PDUToken,
+            limit: usize,
+        ) -> Result<(Vec<OwnedPDUBlob>, PDUToken), std::io::Error> {
+            let start = token_sequence(from).to_be_bytes();
+            let mut pdu_blobs = Vec::new();
+            let mut token = from;
+
+            for entry in self.streams[from.file as usize]
+                .range(&start[..]..)
+                .take(limit)
+            {
+                let (key, bytes) = entry?;
+                pdu_blobs.push(serde_json::from_slice(&bytes)?);
+
+                let key: [u8; 8] = key.as_ref().try_into().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt PDU key")
+                })?;
+                token = sequence_token(from.file, u64::from_be_bytes(key) + 1);
+            }
+
+            Ok((pdu_blobs, token))
+        }
+
+        fn sync(&mut self) -> Result<(), std::io::Error> {
+            self.db.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// The same checks for every implementation, which should all behave alike.
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Opens a store in an empty directory unique to the test.
+    fn open(kind: RoomStoreKind, test_name: &str) -> Box<dyn RoomStore> {
+        let storage_path: PathBuf = std::env::temp_dir().join(format!(
+            "fluctlight-room-store-{}-{:?}-{}",
+            std::process::id(),
+            kind,
+            test_name
+        ));
+
+        if storage_path.exists() {
+            std::fs::remove_dir_all(&storage_path).unwrap();
+        }
+        std::fs::create_dir_all(&storage_path).unwrap();
+
+        open_room_store(kind, &storage_path).unwrap()
+    }
+
+    fn kinds() -> Vec<RoomStoreKind> {
+        let mut kinds = vec![RoomStoreKind::Files, RoomStoreKind::Memory];
+
+        if cfg!(feature = "sled") {
+            kinds.push(RoomStoreKind::Sled);
+        }
+
+        kinds
+    }
+
+    fn event_id(event_id: &str) -> Box<Id<Event>> {
+        Id::try_boxed_from_str(event_id).unwrap()
+    }
+
+    fn pdu(event_id: &str) -> Box<RawValue> {
+        RawValue::from_string(format!(r#"{{"event_id":"{}"}}"#, event_id)).unwrap()
+    }
+
+    fn append(room_store: &mut dyn RoomStore, file: PDUFileId, id: &str) {
+        let origin = Id::try_from_str("origin.example.org").unwrap();
+
+        room_store
+            .append_pdu(file, &event_id(id), Some(origin), &pdu(id))
+            .unwrap();
+    }
+
+    /// Reads a whole stream, `limit` PDUs at a time.
+    fn read_stream(room_store: &mut dyn RoomStore, file: PDUFileId, limit: usize) -> Vec<String> {
+        let mut event_ids = Vec::new();
+        let mut token = PDUToken::start(file);
+
+        loop {
+            let (page, next_token) = room_store.read_page(token, limit).unwrap();
+            assert!(page.len() <= limit);
+
+            if page.is_empty() {
+                assert_eq!(next_token, token, "moved past the end");
+                return event_ids;
+            }
+
+            event_ids.extend(
+                page.into_iter()
+                    .map(|pdu_blob| pdu_blob.event_id.to_string()),
+            );
+            token = next_token;
+        }
+    }
+
+    #[test]
+    fn pdus_are_read_back() {
+        for kind in kinds() {
+            let mut room_store = open(kind, "pdus_are_read_back");
+            let room_store = &mut *room_store;
+
+            append(room_store, PDUFileId::State, "$create");
+            append(room_store, PDUFileId::Other, "$message");
+
+            assert!(room_store.contains_pdu(&event_id("$create")), "{kind:?}");
+            assert!(!room_store.contains_pdu(&event_id("$unknown")), "{kind:?}");
+
+            let pdu_blob = room_store.read_pdu(&event_id("$message")).unwrap();
+            let pdu_blob = pdu_blob.unwrap_or_else(|| panic!("{kind:?}: not found"));
+            assert_eq!(pdu_blob.pdu_blob.get(), pdu("$message").get(), "{kind:?}");
+            assert_eq!(
+                pdu_blob.origin.as_deref().map(Id::as_str),
+                Some("origin.example.org"),
+                "{kind:?}"
+            );
+
+            let unknown = room_store.read_pdu(&event_id("$unknown")).unwrap();
+            assert!(unknown.is_none(), "{kind:?}");
+
+            room_store.sync().unwrap();
+        }
+    }
+
+    #[test]
+    fn streams_are_read_in_order() {
+        for kind in kinds() {
+            let mut room_store = open(kind, "streams_are_read_in_order");
+            let room_store = &mut *room_store;
+            let other_pdus = ["$1", "$2", "$3", "$4", "$5"];
+
+            for id in other_pdus {
+                append(room_store, PDUFileId::Other, id);
+            }
+            append(room_store, PDUFileId::State, "$create");
+
+            for limit in [1, 2, 5, 100] {
+                let event_ids = read_stream(room_store, PDUFileId::Other, limit);
+                assert_eq!(event_ids, other_pdus, "{kind:?} with limit {limit}");
+            }
+            assert_eq!(
+                read_stream(room_store, PDUFileId::State, 10),
+                ["$create"],
+                "{kind:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn pages_pick_up_new_pdus() {
+        for kind in kinds() {
+            let mut room_store = open(kind, "pages_pick_up_new_pdus");
+            let room_store = &mut *room_store;
+
+            append(room_store, PDUFileId::Other, "$first");
+            let start = PDUToken::start(PDUFileId::Other);
+            let (_page, token) = room_store.read_page(start, 10).unwrap();
+
+            append(room_store, PDUFileId::Other, "$second");
+            let (page, _token) = room_store.read_page(token, 10).unwrap();
+
+            let event_ids: Vec<_> = page
+                .into_iter()
+                .map(|pdu_blob| pdu_blob.event_id.to_string())
+                .collect();
+            assert_eq!(event_ids, ["$second"], "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn pdus_are_stored_once() {
+        for kind in kinds() {
+            let mut room_store = open(kind, "pdus_are_stored_once");
+            let room_store = &mut *room_store;
+
+            append(room_store, PDUFileId::Other, "$message");
+            append(room_store, PDUFileId::Other, "$message");
+            // Even if it claims to be in the other stream
+            append(room_store, PDUFileId::State, "$message");
+
+            assert_eq!(
+                read_stream(room_store, PDUFileId::Other, 10),
+                ["$message"],
+                "{kind:?}"
+            );
+            assert!(
+                read_stream(room_store, PDUFileId::State, 10).is_empty(),
+                "{kind:?}"
+            );
+        }
+    }
+}
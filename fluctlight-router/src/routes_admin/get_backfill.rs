@@ -1,7 +1,8 @@
-/// GET /_matrix/federation/v1/version
+/// GET /admin/backfill/:room_id/:event_id
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    matrix_types::{Event, Id, Room},
     playground::send_backfill_request,
     request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
 };
@@ -11,13 +12,15 @@ type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
 impl<'a> MatrixRequest for Request<'a> {
     type Response = Response<'a>;
 
-    const PATH_SPEC: &'static str = "/admin/backfill";
+    const PATH_SPEC: &'static str = "/admin/backfill/?room_id/?event_id";
 }
 
+/// Without these, the development room gets backfilled.
 #[derive(Serialize, Deserialize)]
 pub(super) struct RequestPath<'a> {
-    #[serde(skip)]
-    phantom: std::marker::PhantomData<&'a ()>,
+    #[serde(borrow)]
+    room_id: Option<&'a Id<Room>>,
+    event_id: Option<&'a Id<Event>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,13 +30,22 @@ pub(super) struct Response<'a> {
 
 pub(super) fn get_admin_backfill<'r>(
     request_data: &RequestData<'r>,
-    _request: Request<'r>,
+    request: Request<'r>,
 ) -> Response<'r> {
-    let text = if let Err(err) = send_backfill_request(request_data.state) {
-        bumpalo::format!(in request_data.memory_pool, "Error: {}", err).into_bump_str()
-    } else {
-        request_data.new_str("Backfill successful.")
+    let room_id = request
+        .path
+        .room_id
+        .unwrap_or_else(|| Id::try_from_str("!jhTIqlwlxKKoPPHIgH:synapse-dev.demi.ro").unwrap());
+    let event_id = request.path.event_id.unwrap_or_else(|| {
+        Id::try_from_str("$By7ZDI3wONJDXly1um6f1NqimBdqS_1g3kxeNYjhnBA").unwrap()
+    });
+
+    let text = match send_backfill_request(request_data.state, room_id, event_id) {
+        Ok(count) => bumpalo::format!(in request_data.memory_pool, "Backfilled {} PDUs.", count),
+        Err(err) => bumpalo::format!(in request_data.memory_pool, "Error: {}", err),
     };
 
-    Response { text }
+    Response {
+        text: text.into_bump_str(),
+    }
 }
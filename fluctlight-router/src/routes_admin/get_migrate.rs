@@ -0,0 +1,48 @@
+/// GET /admin/migrate/:room_id
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix_types::{Id, Room},
+    playground::migrate_json_room,
+    request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
+};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/admin/migrate/:room_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    text: &'a str,
+}
+
+pub(super) fn get_admin_migrate<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Response<'r> {
+    let room_id = request.path.room_id;
+
+    let text = match migrate_json_room(request_data.state, room_id) {
+        Ok(count) => bumpalo::format!(
+            in request_data.memory_pool,
+            "Migrated {} PDUs of {} to room storage.",
+            count,
+            room_id
+        ),
+        Err(err) => bumpalo::format!(in request_data.memory_pool, "Error: {}", err),
+    };
+
+    Response {
+        text: text.into_bump_str(),
+    }
+}
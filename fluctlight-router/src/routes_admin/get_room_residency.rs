@@ -0,0 +1,59 @@
+/// GET /admin/rooms/:action/:room_id
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix_types::{Id, Room},
+    playground::load_persistent_room,
+    request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
+};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/admin/rooms/:action/:room_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    action: &'a str,
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    text: &'a str,
+}
+
+pub(super) fn get_admin_room_residency<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Response<'r> {
+    let state = request_data.state;
+    let room_id = request.path.room_id;
+
+    let text = match request.path.action {
+        "load" => match load_persistent_room(state, room_id) {
+            Ok(true) => "Room loaded.",
+            Ok(false) => "Unknown room.",
+            Err(err) => request_data.new_str(&format!("Could not load room: {err}")),
+        },
+        "evict" => state.with_ephemeral_mut(|ephemeral_state| {
+            match ephemeral_state.rooms.get_mut(room_id) {
+                Some(room) if room.resident => {
+                    room.evict();
+                    "Room evicted."
+                }
+                Some(_) => "Room is not loaded.",
+                None => "Unknown room.",
+            }
+        }),
+        _ => "Unknown action; use load or evict.",
+    };
+
+    Response {
+        text: request_data.new_str(text),
+    }
+}
@@ -0,0 +1,39 @@
+use askama::Template;
+/// GET /admin/rooms
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
+    state::State,
+};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/admin/rooms";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    #[serde(skip)]
+    phantom: std::marker::PhantomData<&'a ()>,
+}
+
+#[derive(Template)]
+#[template(path = "rooms.html")]
+pub(super) struct Response<'a> {
+    state: &'a State,
+    memory_usage: usize,
+}
+
+pub(super) fn get_admin_rooms<'r>(
+    request_data: &RequestData<'r>,
+    _request: Request<'r>,
+) -> Response<'r> {
+    Response {
+        state: request_data.state,
+        memory_usage: crate::ALLOCATOR.allocated(),
+    }
+}
@@ -0,0 +1,39 @@
+/// GET /admin/rotate_keys
+use serde::{Deserialize, Serialize};
+
+use crate::request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/admin/rotate_keys";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    #[serde(skip)]
+    phantom: std::marker::PhantomData<&'a ()>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    text: &'a str,
+}
+
+pub(super) fn get_admin_rotate_keys<'r>(
+    request_data: &RequestData<'r>,
+    _request: Request<'r>,
+) -> Response<'r> {
+    let text = match request_data.state.rotate_server_keys() {
+        Ok(key_name) => {
+            bumpalo::format!(in request_data.memory_pool, "Rotated to new key {}.", key_name)
+        }
+        Err(err) => bumpalo::format!(in request_data.memory_pool, "Error: {}", err),
+    };
+
+    Response {
+        text: text.into_bump_str(),
+    }
+}
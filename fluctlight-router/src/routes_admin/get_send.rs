@@ -1,7 +1,8 @@
-/// GET /_matrix/federation/v1/version
+/// GET /admin/send/:room_id/:user_id
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    matrix_types::{Id, Room, User},
     playground::send_join_request,
     request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
 };
@@ -11,13 +12,15 @@ type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
 impl<'a> MatrixRequest for Request<'a> {
     type Response = Response<'a>;
 
-    const PATH_SPEC: &'static str = "/admin/send";
+    const PATH_SPEC: &'static str = "/admin/send/?room_id/?user_id";
 }
 
+/// Without these, the development room gets joined.
 #[derive(Serialize, Deserialize)]
 pub(super) struct RequestPath<'a> {
-    #[serde(skip)]
-    phantom: std::marker::PhantomData<&'a ()>,
+    #[serde(borrow)]
+    room_id: Option<&'a Id<Room>>,
+    user_id: Option<&'a Id<User>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,16 +30,28 @@ pub(super) struct Response<'a> {
 
 pub(super) fn get_admin_send<'r>(
     request_data: &RequestData<'r>,
-    _request: Request<'r>,
+    request: Request<'r>,
 ) -> Response<'r> {
-    let text = request_data.new_str("Hello");
-
-    match send_join_request(&request_data.state) {
-        Ok(value) => value,
+    let room_id = request
+        .path
+        .room_id
+        .unwrap_or_else(|| Id::try_from_str("!MmYfpbopGTdEQTHqlr:matrix.org").unwrap());
+    let user_id = request
+        .path
+        .user_id
+        .unwrap_or_else(|| Id::try_from_str("@whyte:fluctlight-dev.demi.ro").unwrap());
+
+    let text = match send_join_request(request_data.state, room_id, user_id) {
+        Ok(()) => {
+            bumpalo::format!(in request_data.memory_pool, "Joined {} as {}.", room_id, user_id)
+        }
         Err(err) => {
             eprintln!("Error: {}", err);
+            bumpalo::format!(in request_data.memory_pool, "Error: {}", err)
         }
     };
 
-    Response { text }
+    Response {
+        text: text.into_bump_str(),
+    }
 }
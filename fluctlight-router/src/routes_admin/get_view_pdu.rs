@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     matrix_types::{Event, Id, Room},
+    playground::load_persistent_room,
     request::{EmptyBody, EmptyQS, GenericRequest, MatrixRequest, RequestData},
     state::State,
 };
@@ -35,6 +36,10 @@ pub(super) fn get_admin_view_pdu<'r>(
     request_data: &RequestData<'r>,
     request: Request<'r>,
 ) -> Response<'r> {
+    if let Err(err) = load_persistent_room(request_data.state, request.path.room_id) {
+        eprintln!("{err}");
+    }
+
     Response {
         room_id: request.path.room_id,
         event_id: request.path.event_id,
@@ -1,12 +1,18 @@
 use crate::request::RequestData;
 
 use self::{
-    get_backfill::get_admin_backfill, get_load::get_admin_load, get_send::get_admin_send,
-    get_view::get_admin_view, get_view_pdu::get_admin_view_pdu,
+    get_backfill::get_admin_backfill, get_load::get_admin_load, get_migrate::get_admin_migrate,
+    get_room_residency::get_admin_room_residency, get_rooms::get_admin_rooms,
+    get_rotate_keys::get_admin_rotate_keys, get_send::get_admin_send, get_view::get_admin_view,
+    get_view_pdu::get_admin_view_pdu,
 };
 
 mod get_backfill;
 mod get_load;
+mod get_migrate;
+mod get_room_residency;
+mod get_rooms;
+mod get_rotate_keys;
 mod get_send;
 mod get_view;
 mod get_view_pdu;
@@ -18,9 +24,13 @@ pub(super) fn admin_api_handler<'r, 'h>(
     let req = request_data;
 
     let response_body = match uri_segments {
-        ["GET", "admin", "send"] => req.handle_with(get_admin_send),
+        ["GET", "admin", "send", ..] => req.handle_with(get_admin_send),
         ["GET", "admin", "load"] => req.handle_with(get_admin_load),
-        ["GET", "admin", "backfill"] => req.handle_with(get_admin_backfill),
+        ["GET", "admin", "backfill", ..] => req.handle_with(get_admin_backfill),
+        ["GET", "admin", "migrate", _] => req.handle_with(get_admin_migrate),
+        ["GET", "admin", "rooms"] => req.render_template_with(get_admin_rooms),
+        ["GET", "admin", "rooms", _, _] => req.handle_with(get_admin_room_residency),
+        ["GET", "admin", "rotate_keys"] => req.handle_with(get_admin_rotate_keys),
         ["GET", "admin", "view"] => req.render_template_with(get_admin_view),
         ["GET", "admin", "view", "pdu", _, _] => req.render_template_with(get_admin_view_pdu),
 
@@ -0,0 +1,99 @@
+/// GET /_matrix/federation/v1/backfill/{roomId}
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use super::{authenticated_origin, load_known_room};
+use crate::{
+    matrix_types::{Event, Id, Room, ServerName, User},
+    request::{EmptyBody, GenericRequest, MatrixError, MatrixRequest, RequestData},
+    state::TimeStamp,
+};
+
+/// Other servers ask for fewer than this, and ask again for more.
+const MAX_BACKFILL_LIMIT: usize = 100;
+
+type Request<'a> = GenericRequest<RequestPath<'a>, RequestQueryString, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/_matrix/federation/:version/backfill/:room_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    version: &'a str,
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+}
+
+/// The events to start from are in `v`, which may be repeated; see
+/// `RequestData::query_values`.
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestQueryString {
+    limit: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    #[serde(borrow)]
+    origin: &'a Id<ServerName>,
+    origin_server_ts: TimeStamp,
+    pdus: Vec<&'a RawValue>,
+}
+
+/// Answers with the PDUs before (and including) those in `v`, to servers
+/// that are in the room.
+pub(super) fn get_federation_v1_backfill<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Result<Response<'r>, MatrixError> {
+    let room_id = request.path.room_id;
+
+    let event_ids = request_data
+        .query_values("v")
+        .iter()
+        .map(|event_id| Id::<Event>::try_from_str(event_id))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| MatrixError::new(400, "M_INVALID_PARAM", err))?;
+
+    load_known_room(request_data.state, room_id)?;
+
+    let ephemeral_state = request_data.state.ephemeral();
+    let room = ephemeral_state
+        .rooms
+        .get(room_id)
+        .ok_or_else(|| MatrixError::new(404, "M_NOT_FOUND", format!("Unknown room {}", room_id)))?;
+
+    let origin = authenticated_origin(request_data);
+    let origin_in_room = room
+        .current_state()
+        .iter()
+        .any(|((pdu_type, state_key), parsed_pdu)| {
+            *pdu_type == "m.room.member"
+                && parsed_pdu.membership() == Some("join")
+                && Id::<User>::try_from_str(state_key)
+                    .is_ok_and(|user_id| user_id.server_name().as_str() == origin)
+        });
+
+    if !origin_in_room {
+        return Err(MatrixError::new(
+            403,
+            "M_FORBIDDEN",
+            format!("{} is not in {}", origin, room_id),
+        ));
+    }
+
+    let limit = request.query_string.limit.min(MAX_BACKFILL_LIMIT);
+    let pdus = room
+        .history(&event_ids, limit)
+        .into_iter()
+        .map(|parsed_pdu| request_data.new_raw_value(&parsed_pdu.blob))
+        .collect();
+
+    Ok(Response {
+        origin: request_data.new_id(&request_data.state.server_name),
+        origin_server_ts: TimeStamp::now(),
+        pdus,
+    })
+}
@@ -0,0 +1,59 @@
+/// GET /_matrix/key/v2/query/{serverName}
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix_types::{Id, Key, ServerName},
+    rendered_json::RenderedJson,
+    request::{EmptyBody, GenericRequest, MatrixRequest, RequestData},
+    server_keys::ServerKeys,
+    state::TimeStamp,
+};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, RequestQueryString, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/_matrix/key/:version/query/:server_name/?key_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    version: &'a str,
+    #[serde(borrow)]
+    server_name: &'a Id<ServerName>,
+    key_id: Option<&'a Id<Key>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestQueryString {
+    minimum_valid_until_ts: Option<TimeStamp>,
+}
+
+#[derive(Serialize)]
+pub(super) struct Response<'a> {
+    server_keys: Vec<RenderedJson<'a, ServerKeys>>,
+}
+
+pub(super) fn get_key_v2_query<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Response<'r> {
+    // Note: The key_id path segment is deprecated, and like with POST queries,
+    // notaries may return all keys regardless.
+    let minimum_valid_until_ts = request
+        .query_string
+        .minimum_valid_until_ts
+        .unwrap_or_else(TimeStamp::now);
+
+    let server_keys = request_data
+        .state
+        .notary_server_keys(request.path.server_name, minimum_valid_until_ts)
+        .into_iter()
+        .map(|server_keys_json| {
+            RenderedJson::from_trusted_str(request_data.new_str(&server_keys_json))
+        })
+        .collect();
+
+    Response { server_keys }
+}
@@ -0,0 +1,157 @@
+/// GET /_matrix/federation/v1/make_join/{roomId}/{userId}
+use serde::{Deserialize, Serialize};
+
+use super::{authenticated_origin, check_join_allowed, load_known_room};
+use crate::{
+    matrix_types::{Event, Id, Room, User},
+    pdu_ref::MemberContent,
+    request::{EmptyBody, EmptyQS, GenericRequest, MatrixError, MatrixRequest, RequestData},
+    state::TimeStamp,
+};
+
+/// As many as other servers use; more would only make the join PDU larger.
+const MAX_PREV_EVENTS: usize = 10;
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, EmptyBody>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/_matrix/federation/:version/make_join/:room_id/:user_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    version: &'a str,
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+    user_id: &'a Id<User>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    #[serde(borrow)]
+    event: JoinTemplate<'a>,
+    room_version: &'a str,
+}
+
+/// The join PDU minus what the joining server fills in: `origin`,
+/// `origin_server_ts` (again), `hashes` and `signatures`.
+#[derive(Serialize, Deserialize)]
+struct JoinTemplate<'a> {
+    #[serde(borrow)]
+    auth_events: Vec<&'a Id<Event>>,
+    content: MemberContent<'a>,
+    depth: u64,
+    origin_server_ts: TimeStamp,
+    prev_events: Vec<&'a Id<Event>>,
+    room_id: &'a Id<Room>,
+    sender: &'a Id<User>,
+    state_key: &'a Id<User>,
+    #[serde(rename = "type")]
+    pdu_type: &'a str,
+}
+
+pub(super) fn get_federation_v1_make_join<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Result<Response<'r>, MatrixError> {
+    let RequestPath {
+        room_id, user_id, ..
+    } = request.path;
+
+    let origin = authenticated_origin(request_data);
+    if user_id.server_name().as_str() != origin {
+        return Err(MatrixError::new(
+            403,
+            "M_FORBIDDEN",
+            format!("{} can't join on behalf of {}", origin, user_id),
+        ));
+    }
+
+    load_known_room(request_data.state, room_id)?;
+
+    let ephemeral_state = request_data.state.ephemeral();
+    let room = ephemeral_state
+        .rooms
+        .get(room_id)
+        .ok_or_else(|| MatrixError::new(404, "M_NOT_FOUND", format!("Unknown room {}", room_id)))?;
+    let current_state = room.current_state();
+
+    let room_version = current_state
+        .get(&("m.room.create", ""))
+        .and_then(|create_pdu| {
+            let create_pdu: serde_json::Value = serde_json::from_str(create_pdu.blob.get()).ok()?;
+            // Rooms from before versions were a thing are all version 1
+            Some(
+                create_pdu["content"]["room_version"]
+                    .as_str()
+                    .unwrap_or("1")
+                    .to_owned(),
+            )
+        })
+        .ok_or_else(|| {
+            MatrixError::new(
+                404,
+                "M_NOT_FOUND",
+                format!("No m.room.create PDU for {}", room_id),
+            )
+        })?;
+
+    // Servers that don't say which versions they support only support 1
+    let supported_versions = request_data.query_values("ver");
+    let supported = if supported_versions.is_empty() {
+        room_version == "1"
+    } else {
+        supported_versions.contains(&room_version.as_str())
+    };
+
+    if !supported {
+        let mut matrix_error = MatrixError::new(
+            400,
+            "M_INCOMPATIBLE_ROOM_VERSION",
+            format!("{} is a version {} room", room_id, room_version),
+        );
+        matrix_error.room_version = Some(room_version);
+        return Err(matrix_error);
+    }
+
+    check_join_allowed(&current_state, user_id)?;
+
+    let auth_events = [
+        ("m.room.create", ""),
+        ("m.room.power_levels", ""),
+        ("m.room.join_rules", ""),
+        ("m.room.member", user_id.as_str()),
+    ]
+    .iter()
+    .filter_map(|state_key| current_state.get(state_key))
+    .map(|parsed_pdu| request_data.new_id(&parsed_pdu.event_id))
+    .collect();
+
+    let prev_pdus = room.forward_extremities(MAX_PREV_EVENTS);
+    let depth = prev_pdus
+        .iter()
+        .map(|parsed_pdu| parsed_pdu.pdu.depth + 1)
+        .max()
+        .unwrap_or(1);
+    let prev_events = prev_pdus
+        .iter()
+        .map(|parsed_pdu| request_data.new_id(&parsed_pdu.event_id))
+        .collect();
+
+    Ok(Response {
+        event: JoinTemplate {
+            auth_events,
+            content: MemberContent { membership: "join" },
+            depth,
+            origin_server_ts: TimeStamp::now(),
+            prev_events,
+            room_id,
+            sender: user_id,
+            state_key: user_id,
+            pdu_type: "m.room.member",
+        },
+        room_version: request_data.new_str(&room_version),
+    })
+}
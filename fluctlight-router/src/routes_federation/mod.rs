@@ -1,17 +1,37 @@
-use crate::request::RequestData;
+use std::collections::BTreeMap;
+
+use crate::{
+    matrix_types::{Id, Room, User},
+    pdu_arc::AnyContent,
+    playground::{load_persistent_room, ParsedPDU},
+    request::{MatrixError, RequestData},
+    signed_request::parse_x_matrix,
+    state::State,
+};
 
 use self::{
-    get_key_server::get_key_v2_server, get_state::get_federation_v1_state,
-    get_user_devices::get_federation_v1_user_devices, get_version::get_federation_v1_version,
-    post_key_query::post_key_v2_query, put_send::put_federation_v1_send,
+    get_backfill::get_federation_v1_backfill,
+    get_key_query::get_key_v2_query,
+    get_key_server::get_key_v2_server,
+    get_make_join::get_federation_v1_make_join,
+    get_state::get_federation_v1_state,
+    get_user_devices::get_federation_v1_user_devices,
+    get_version::get_federation_v1_version,
+    post_key_query::post_key_v2_query,
+    put_send::put_federation_v1_send,
+    put_send_join::{put_federation_v1_send_join, put_federation_v2_send_join},
 };
 
+mod get_backfill;
+mod get_key_query;
 mod get_key_server;
+mod get_make_join;
 mod get_state;
 mod get_user_devices;
 mod get_version;
 mod post_key_query;
 mod put_send;
+mod put_send_join;
 
 pub(super) fn federation_api_handler<'r, 'h>(
     uri_segments: &[&str],
@@ -26,21 +46,37 @@ pub(super) fn federation_api_handler<'r, 'h>(
         }
         ["GET", "_matrix", "key", "v2", "server", ..] => req.handle_with(get_key_v2_server),
         ["POST", "_matrix", "key", "v2", "query"] => req.handle_with(post_key_v2_query),
-        ["GET", "_matrix", "key", "v2", "query", _, _] => not_implemented(),
+        ["GET", "_matrix", "key", "v2", "query", _, ..] => req.handle_with(get_key_v2_query),
         ["PUT", "_matrix", "federation", "v1", "send", _] => {
-            req.handle_with(put_federation_v1_send)
+            let response = req.handle_with(put_federation_v1_send);
+
+            // Anything not on disk yet when the server started shutting down
+            // may be lost, so the whole transaction has to be sent again
+            if req.state.is_shutting_down() {
+                service_unavailable()
+            } else {
+                response
+            }
         }
         ["GET", "_matrix", "federation", "v1", "event_auth", _, _] => not_implemented(),
-        ["GET", "_matrix", "federation", "v1", "backfill", _] => not_implemented(),
+        ["GET", "_matrix", "federation", "v1", "backfill", _] => {
+            req.try_handle_with(get_federation_v1_backfill)
+        }
         ["POST", "_matrix", "federation", "v1", "get_missing_events", _] => not_implemented(),
         ["GET", "_matrix", "federation", "v1", "event", _] => not_implemented(),
         ["GET", "_matrix", "federation", "v1", "state", _] => {
             req.handle_with(get_federation_v1_state)
         }
         ["GET", "_matrix", "federation", "v1", "state_ids", _] => not_implemented(),
-        ["GET", "_matrix", "federation", "v1", "make_join", _, _] => not_implemented(),
-        ["PUT", "_matrix", "federation", "v1", "send_join", _, _] => not_implemented(),
-        ["PUT", "_matrix", "federation", "v2", "send_join", _, _] => not_implemented(),
+        ["GET", "_matrix", "federation", "v1", "make_join", _, _] => {
+            req.try_handle_with(get_federation_v1_make_join)
+        }
+        ["PUT", "_matrix", "federation", "v1", "send_join", _, _] => {
+            req.try_handle_with(put_federation_v1_send_join)
+        }
+        ["PUT", "_matrix", "federation", "v2", "send_join", _, _] => {
+            req.try_handle_with(put_federation_v2_send_join)
+        }
         ["GET", "_matrix", "federation", "v1", "make_knock", _, _] => not_implemented(),
         ["PUT", "_matrix", "federation", "v1", "send_knock", _, _] => not_implemented(),
         ["PUT", "_matrix", "federation", "v1", "invite", _, _] => return None,
@@ -73,6 +109,77 @@ pub(super) fn federation_api_handler<'r, 'h>(
     Some(response_body)
 }
 
+/// The server that signed the request, as opposed to whatever its body
+/// claims.
+fn authenticated_origin<'a>(request_data: &'a RequestData) -> &'a str {
+    request_data
+        .http_request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(parse_x_matrix)
+        .map(|auth| auth.origin)
+        .expect("Requests without X-Matrix authorization never get this far")
+}
+
+/// Makes sure that the room's PDUs are in memory, to answer from.
+fn load_known_room(state: &State, room_id: &Id<Room>) -> Result<(), MatrixError> {
+    match load_persistent_room(state, room_id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(MatrixError::new(
+            404,
+            "M_NOT_FOUND",
+            format!("Unknown room {}", room_id),
+        )),
+        Err(err) => Err(MatrixError::new(
+            500,
+            "M_UNKNOWN",
+            format!("Could not load room {}: {}", room_id, err),
+        )),
+    }
+}
+
+/// Anyone may join public rooms; other rooms need an invite. There is no
+/// support for knocking or restricted rooms yet.
+fn check_join_allowed(
+    current_state: &BTreeMap<(&str, &str), &ParsedPDU>,
+    user_id: &Id<User>,
+) -> Result<(), MatrixError> {
+    let membership = current_state
+        .get(&("m.room.member", user_id.as_str()))
+        .and_then(|parsed_pdu| parsed_pdu.membership());
+    let join_rule = current_state
+        .get(&("m.room.join_rules", ""))
+        .and_then(|parsed_pdu| match &parsed_pdu.pdu.content {
+            AnyContent::JoinRules(join_rules) => Some(&*join_rules.join_rule),
+            _ => None,
+        });
+
+    match (membership, join_rule) {
+        (Some("ban"), _) => Err(MatrixError::new(
+            403,
+            "M_FORBIDDEN",
+            format!("{} is banned", user_id),
+        )),
+        (Some("invite" | "join"), _) | (_, Some("public")) => Ok(()),
+        _ => Err(MatrixError::new(
+            403,
+            "M_FORBIDDEN",
+            format!("{} is not invited", user_id),
+        )),
+    }
+}
+
+fn service_unavailable() -> Result<http::Response<Vec<u8>>, String> {
+    let response = http::Response::builder()
+        .status(503)
+        .header("Content-Type", "text/plain")
+        .body(b"Shutting down\n".to_vec())
+        .expect("Valid constant response");
+
+    Ok(response)
+}
+
 fn not_implemented() -> Result<http::Response<Vec<u8>>, String> {
     let response = http::Response::builder()
         .status(501)
@@ -25,13 +25,17 @@ pub(super) struct RequestPath<'a> {
 #[derive(Serialize, Deserialize)]
 pub(super) struct RequestBody<'a> {
     #[serde(borrow)]
-    server_keys: BTreeMap<&'a Id<ServerName>, BTreeMap<&'a Id<Key>, TimeStamp>>,
+    server_keys: BTreeMap<&'a Id<ServerName>, BTreeMap<&'a Id<Key>, QueryCriteria>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueryCriteria {
+    minimum_valid_until_ts: Option<TimeStamp>,
 }
 
 #[derive(Serialize)]
 pub(super) struct Response<'a> {
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    server_keys: Vec<&'a RenderedJson<'a, crate::server_keys::ServerKeys>>,
+    server_keys: Vec<RenderedJson<'a, crate::server_keys::ServerKeys>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -71,16 +75,20 @@ pub(super) fn post_key_v2_query<'r>(
     let mut server_keys = Vec::new();
 
     for (server_name, key_query) in request.body.server_keys {
-        // Ignore the query. The spec says: "The notary server may return
+        // Ignore the key IDs. The spec says: "The notary server may return
         // multiple keys regardless of the Key IDs given."
-        let _ = key_query;
-
-        if let Some(foreign_server_keys_json_list) =
-            request_data.state.foreign_server_keys_json.get(server_name)
+        let minimum_valid_until_ts = key_query
+            .values()
+            .filter_map(|criteria| criteria.minimum_valid_until_ts)
+            .max()
+            .unwrap_or_else(TimeStamp::now);
+
+        for server_keys_json in request_data
+            .state
+            .notary_server_keys(server_name, minimum_valid_until_ts)
         {
-            for foreign_server_keys_json in foreign_server_keys_json_list {
-                server_keys.push(foreign_server_keys_json);
-            }
+            let json_str = request_data.new_str(&server_keys_json);
+            server_keys.push(RenderedJson::from_trusted_str(json_str));
         }
     }
 
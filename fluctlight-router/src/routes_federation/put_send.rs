@@ -4,10 +4,12 @@ use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 
+use super::authenticated_origin;
 use crate::{
-    playground::{ingest_transaction, Transaction},
+    playground::Transaction,
     request::{EmptyQS, GenericRequest, MatrixRequest, RequestData},
     state::TimeStamp,
+    transaction_log::ingest_logged_transaction,
 };
 
 type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, RequestBody<'a>>;
@@ -51,13 +53,21 @@ pub(super) fn put_federation_v1_send<'r>(
     request_data: &RequestData<'r>,
     request: Request<'r>,
 ) -> Response<'r> {
-    let pdus = ingest_transaction(
+    let raw_body: &RawValue = serde_json::from_slice(request_data.http_request.body())
+        .expect("Body was already parsed as JSON");
+
+    // The body's `origin` is whatever the sender put there, so retries are
+    // told apart by the server that signed the request instead
+    let origin = authenticated_origin(request_data);
+
+    let pdus = ingest_logged_transaction(
         request_data.state,
-        Some(Transaction {
+        Transaction {
             transaction_id: request.path.transaction_id,
-            origin: request.body.origin,
+            origin,
             origin_server_ts: request.body.origin_server_ts,
-        }),
+        },
+        raw_body,
         &request.body.pdus,
         &request.body.edus.unwrap_or(vec![]),
     );
@@ -0,0 +1,164 @@
+/// PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}
+/// PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use super::{authenticated_origin, check_join_allowed, load_known_room};
+use crate::{
+    matrix_types::{Event, Id, Room, ServerName},
+    pdu_ref::{parse_pdu_ref, AnyContentRef, AnyStateRef},
+    playground::{ingest_transaction, Transaction},
+    request::{EmptyQS, GenericRequest, MatrixError, MatrixRequest, RequestData},
+    signing::event_id,
+    state::TimeStamp,
+};
+
+type Request<'a> = GenericRequest<RequestPath<'a>, EmptyQS, &'a RawValue>;
+type V1Request<'a> = GenericRequest<V1RequestPath<'a>, EmptyQS, &'a RawValue>;
+
+impl<'a> MatrixRequest for Request<'a> {
+    type Response = Response<'a>;
+
+    const PATH_SPEC: &'static str = "/_matrix/federation/v2/send_join/:room_id/:event_id";
+}
+
+impl<'a> MatrixRequest for V1Request<'a> {
+    type Response = V1Response<'a>;
+
+    const PATH_SPEC: &'static str = "/_matrix/federation/v1/send_join/:room_id/:event_id";
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct RequestPath<'a> {
+    #[serde(borrow)]
+    room_id: &'a Id<Room>,
+    event_id: &'a Id<Event>,
+}
+
+/// Only differs in the response, which v1 wraps in `[200, …]`.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub(super) struct V1RequestPath<'a>(#[serde(borrow)] RequestPath<'a>);
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Response<'a> {
+    #[serde(borrow)]
+    auth_chain: Vec<&'a RawValue>,
+    event: &'a RawValue,
+    origin: &'a Id<ServerName>,
+    state: Vec<&'a RawValue>,
+}
+
+type V1Response<'a> = (u16, Response<'a>);
+
+pub(super) fn put_federation_v1_send_join<'r>(
+    request_data: &RequestData<'r>,
+    request: V1Request<'r>,
+) -> Result<V1Response<'r>, MatrixError> {
+    let request = GenericRequest::new(request.path.0, request.query_string, request.body);
+
+    Ok((200, put_federation_v2_send_join(request_data, request)?))
+}
+
+/// Stores the join PDU, answering with the room's state from before it.
+pub(super) fn put_federation_v2_send_join<'r>(
+    request_data: &RequestData<'r>,
+    request: Request<'r>,
+) -> Result<Response<'r>, MatrixError> {
+    let RequestPath {
+        room_id,
+        event_id: path_event_id,
+    } = request.path;
+    let bad_join = |error: String| MatrixError::new(400, "M_BAD_JSON", error);
+
+    let pdu_ref = parse_pdu_ref(request.body)
+        .map_err(|err| bad_join(format!("Invalid join PDU: {}", err)))?;
+
+    if &*event_id(&pdu_ref) != path_event_id {
+        return Err(bad_join(format!("Join PDU is not {}", path_event_id)));
+    }
+    if pdu_ref.room_id != room_id {
+        return Err(bad_join(format!("Join PDU is not in {}", room_id)));
+    }
+
+    let is_own_join = match (&pdu_ref.content, &pdu_ref.state_key) {
+        (AnyContentRef::Member(member), AnyStateRef::UserId(state_key)) => {
+            member.membership == "join" && state_key.user_id == pdu_ref.sender
+        }
+        _ => false,
+    };
+    if !is_own_join {
+        return Err(bad_join("Not a join of the PDU's sender".to_owned()));
+    }
+
+    let origin = authenticated_origin(request_data);
+    if pdu_ref.sender.server_name().as_str() != origin {
+        return Err(MatrixError::new(
+            403,
+            "M_FORBIDDEN",
+            format!("{} can't join on behalf of {}", origin, pdu_ref.sender),
+        ));
+    }
+
+    load_known_room(request_data.state, room_id)?;
+
+    // Taken before the join goes in, as it is the state the joining server
+    // starts out from
+    let (state, auth_chain) = {
+        let ephemeral_state = request_data.state.ephemeral();
+        let room = ephemeral_state.rooms.get(room_id).ok_or_else(|| {
+            MatrixError::new(404, "M_NOT_FOUND", format!("Unknown room {}", room_id))
+        })?;
+        let current_state = room.current_state();
+
+        check_join_allowed(&current_state, &pdu_ref.sender)?;
+
+        let auth_event_ids: Vec<&Id<Event>> = current_state
+            .values()
+            .flat_map(|parsed_pdu| parsed_pdu.pdu.auth_events.iter().map(|id| &**id))
+            .chain(pdu_ref.auth_events.iter().copied())
+            .collect();
+
+        let state: Vec<&RawValue> = current_state
+            .values()
+            .map(|parsed_pdu| request_data.new_raw_value(&parsed_pdu.blob))
+            .collect();
+        let auth_chain: Vec<&RawValue> = room
+            .auth_chain(&auth_event_ids)
+            .into_iter()
+            .map(|parsed_pdu| request_data.new_raw_value(&parsed_pdu.blob))
+            .collect();
+
+        (state, auth_chain)
+    };
+
+    let results = ingest_transaction(
+        request_data.state,
+        Some(Transaction {
+            transaction_id: path_event_id.as_str(),
+            origin,
+            origin_server_ts: TimeStamp::now(),
+        }),
+        &[request.body],
+        &[],
+    );
+
+    match results.get(path_event_id) {
+        Some(Ok(())) => {}
+        Some(Err(err)) => {
+            return Err(MatrixError::new(
+                500,
+                "M_UNKNOWN",
+                format!("Could not store join: {}", err),
+            ))
+        }
+        None => return Err(MatrixError::new(500, "M_UNKNOWN", "Could not store join")),
+    }
+
+    Ok(Response {
+        auth_chain,
+        event: request.body,
+        origin: request_data.new_id(&request_data.state.server_name),
+        state,
+    })
+}
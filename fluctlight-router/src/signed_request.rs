@@ -3,7 +3,11 @@ use std::error::Error;
 use serde::Serialize;
 use serde_json::value::RawValue;
 
-use crate::state::State;
+use crate::{
+    matrix_types::{Id, ServerName},
+    signing::sign_detached,
+    state::State,
+};
 
 #[derive(Serialize)]
 struct SignedJson<'a> {
@@ -69,7 +73,7 @@ impl<'a> SignedRequestBuilder<'a> {
             uri: self.uri,
         };
 
-        let url = format!("http://{}:8008{}", signed_json.destination, self.uri);
+        let url = federation_url(self.state, signed_json.destination, self.uri);
 
         let mut req = match self.method {
             "GET" => ureq::get(&url),
@@ -95,7 +99,7 @@ impl<'a> SignedRequestBuilder<'a> {
             uri: self.uri,
         };
 
-        let url = format!("http://{}:8008{}", signed_json.destination, self.uri);
+        let url = federation_url(self.state, signed_json.destination, self.uri);
 
         let mut req = match self.method {
             "GET" => ureq::get(&url),
@@ -114,20 +118,58 @@ impl<'a> SignedRequestBuilder<'a> {
     }
 }
 
-fn sign(mut req: ureq::Request, state: &State, signed_json: &SignedJson) -> ureq::Request {
-    // TODO: Check if sending directly to a hasher helps
-    let signable_bytes = serde_json::to_vec(&signed_json).unwrap();
+/// The parameters of an incoming request's `X-Matrix` authorization header.
+pub(crate) struct XMatrixAuth<'a> {
+    pub origin: &'a str,
+    pub key: &'a str,
+    pub sig: &'a str,
+}
+
+/// Parses `X-Matrix origin=…,key="…",sig="…"`, quoted or not, ignoring any
+/// other parameters (e.g. `destination`).
+pub(crate) fn parse_x_matrix(header: &str) -> Option<XMatrixAuth<'_>> {
+    let (scheme, params) = header.split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("X-Matrix") {
+        return None;
+    }
+
+    let (mut origin, mut key, mut sig) = (None, None, None);
 
-    eprintln!(
-        "Signing signable:\n---\n{}\n---\n",
-        String::from_utf8_lossy(&signable_bytes)
-    );
+    for param in params.split(',') {
+        let (name, value) = param.trim().split_once('=')?;
+        let value = value.trim_matches('"');
 
-    for (key_name, server_key) in &state.server_key_pairs {
-        let noise = None;
-        let signature = server_key.key_pair.sk.sign(&signable_bytes, noise);
-        let sig_b64 = base64::encode_config(&*signature, base64::STANDARD_NO_PAD);
+        match name {
+            "origin" => origin = Some(value),
+            "key" => key = Some(value),
+            "sig" => sig = Some(value),
+            _ => {}
+        }
+    }
+
+    Some(XMatrixAuth {
+        origin: origin?,
+        key: key?,
+        sig: sig?,
+    })
+}
+
+// FIXME: Use server discovery instead of assuming a local development server
+pub(crate) fn federation_url(state: &State, destination: &str, uri: &str) -> String {
+    let address = Id::<ServerName>::try_from_str(destination)
+        .ok()
+        .and_then(|server_name| state.config.federation_addresses.get(server_name))
+        .cloned()
+        .unwrap_or_else(|| format!("{}:8008", destination));
+
+    format!("http://{}{}", address, uri)
+}
+
+fn sign(mut req: ureq::Request, state: &State, signed_json: &SignedJson) -> ureq::Request {
+    let signatures = sign_detached(signed_json, state.server_key_pairs().signing_keys());
 
+    for (key_name, sig_b64) in signatures {
         let header = format!(
             "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
             state.server_name, key_name, sig_b64
@@ -0,0 +1,227 @@
+use std::{collections::BTreeMap, io::Write};
+
+use ed25519_compact::{KeyPair, PublicKey, Signature};
+use serde::Serialize;
+use sha2::Digest;
+use smallvec::SmallVec;
+
+use crate::{
+    canonical_hash::write_canonical_serialize,
+    matrix_types::{Event, Id, Key, ServerName},
+    pdu_ref::SignaturesRef,
+    server_keys::Signatures,
+    state::{State, TimeStamp},
+};
+
+/// Signatures made by a single entity, by key name.
+pub(crate) type KeySignatures = BTreeMap<Box<Id<Key>>, String>;
+
+/// Keys that are never covered by signatures.
+const UNSIGNED_KEYS: &[&str] = &["signatures", "unsigned"];
+/// Keys that are never covered by a PDU's content hash.
+const UNHASHED_KEYS: &[&str] = &["hashes", "signatures", "unsigned"];
+
+const NOT_CANONICAL: &str = "Not valid canonical JSON";
+const NOT_SIGNED: &str = "Not signed by the expected server";
+const NO_KEYS_SUCCEEDED: &str = "No keys succeeded";
+
+/// Every error `Verifiable::verify` can return.
+pub(crate) const VERIFY_ERRORS: &[&str] = &[NOT_CANONICAL, NOT_SIGNED, NO_KEYS_SUCCEEDED];
+
+/// Writes the canonical JSON of `value` without the given top-level keys.
+///
+/// Field order in the `Serialize` impl doesn't matter, as keys get sorted on
+/// the way out.
+fn write_canonical<T, W>(value: &T, removed_keys: &[&str], writer: &mut W) -> Result<(), String>
+where
+    T: Serialize + ?Sized,
+    W: Write,
+{
+    write_canonical_serialize(value, removed_keys, writer)
+}
+
+/// The bytes that get signed for `value`, i.e. its canonical JSON without
+/// `signatures` and `unsigned`.
+pub(crate) fn signable_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    write_canonical(value, UNSIGNED_KEYS, &mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Signs already-canonical bytes with each of the given keys.
+pub(crate) fn sign_bytes<'k>(
+    bytes: &[u8],
+    key_pairs: impl IntoIterator<Item = (&'k Id<Key>, &'k KeyPair)>,
+) -> KeySignatures {
+    key_pairs
+        .into_iter()
+        .map(|(key_name, key_pair)| {
+            let noise = None;
+            let signature = key_pair.sk.sign(bytes, noise);
+            let sig_b64 = base64::encode_config(*signature, base64::STANDARD_NO_PAD);
+
+            (key_name.to_owned(), sig_b64)
+        })
+        .collect()
+}
+
+/// Signs `value` without modifying it, returning the signatures for the
+/// caller to place wherever they belong (e.g. an `X-Matrix` header).
+pub(crate) fn sign_detached<'k, T: Serialize + ?Sized>(
+    value: &T,
+    key_pairs: impl IntoIterator<Item = (&'k Id<Key>, &'k KeyPair)>,
+) -> KeySignatures {
+    let bytes = signable_bytes(value).expect("Own objects should always be signable");
+
+    sign_bytes(&bytes, key_pairs)
+}
+
+/// Checks a single base64-encoded signature over already-canonical bytes.
+pub(crate) fn verify_bytes(
+    bytes: &[u8],
+    public_key: &PublicKey,
+    signature: &str,
+) -> Result<(), String> {
+    let signature = decode_signature(signature)?;
+
+    public_key
+        .verify(bytes, &signature)
+        .map_err(|err| format!("Signature check failed: {}", err))
+}
+
+pub(crate) fn decode_public_key(key: &str) -> Result<PublicKey, String> {
+    let key_bytes = base64::decode_config(key, base64::STANDARD_NO_PAD)
+        .map_err(|err| format!("Invalid public key encoding: {}", err))?;
+
+    PublicKey::from_slice(&key_bytes).map_err(|err| format!("Invalid public key: {}", err))
+}
+
+pub(crate) fn decode_signature(signature: &str) -> Result<Signature, String> {
+    let signature_bytes = base64::decode_config(signature, base64::STANDARD_NO_PAD)
+        .map_err(|err| format!("Invalid signature encoding: {}", err))?;
+
+    Signature::from_slice(&signature_bytes).map_err(|err| format!("Invalid signature: {}", err))
+}
+
+/// Objects that carry their own `signatures` map, like server keys.
+pub(crate) trait Signable: Serialize {
+    fn signatures_mut(&mut self) -> &mut Option<Signatures>;
+
+    /// Adds `signer`'s signatures to the object, keeping any existing ones.
+    fn sign<'k>(
+        &mut self,
+        signer: &Id<ServerName>,
+        key_pairs: impl IntoIterator<Item = (&'k Id<Key>, &'k KeyPair)>,
+    ) {
+        let new_signatures = sign_detached(self, key_pairs);
+
+        self.signatures_mut()
+            .get_or_insert_with(Default::default)
+            .signatures
+            .entry(signer.to_owned())
+            .or_default()
+            .extend(new_signatures);
+    }
+}
+
+pub(crate) trait Verifiable: Serialize {
+    /// The time at which the signing keys must have been valid.
+    fn signing_time(&self) -> TimeStamp {
+        TimeStamp::now()
+    }
+
+    /// Makes sure that the keys `verify` needs are cached, fetching them if
+    /// not; call this before taking the locks that `verify` runs under.
+    fn fetch_signing_keys(
+        &self,
+        state: &State,
+        server_name: &Id<ServerName>,
+        signatures: &SignaturesRef<'_>,
+    ) {
+        let signing_time = self.signing_time();
+
+        for (key_name, _signature) in signatures.get_signatures(server_name).into_iter().flatten() {
+            state.get_server_key(server_name, key_name, signing_time);
+        }
+    }
+
+    /// Checks the signatures using only keys that are already known; see
+    /// `fetch_signing_keys`.
+    fn verify(
+        &self,
+        state: &State,
+        server_name: &Id<ServerName>,
+        signatures: &SignaturesRef<'_>,
+    ) -> Result<(), &'static str> {
+        let bytes = signable_bytes(self).map_err(|err| {
+            eprintln!("Could not canonicalize signable: {}", err);
+            NOT_CANONICAL
+        })?;
+
+        let server_signatures = match signatures.get_signatures(server_name) {
+            Some(value) => value,
+            None => {
+                return Err(NOT_SIGNED);
+            }
+        };
+
+        let signing_time = self.signing_time();
+
+        for (key_name, signature) in server_signatures {
+            let public_key = match state.cached_server_key(server_name, key_name, signing_time) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match verify_bytes(&bytes, &public_key, signature) {
+                Ok(()) => {
+                    return Ok(());
+                }
+                Err(err) => {
+                    // TODO: Figure out if this is just a warning or if
+                    // the check needs to abort here
+                    eprintln!("Key check for {} failed: {}", key_name, err);
+                }
+            }
+        }
+
+        Err(NO_KEYS_SUCCEEDED)
+    }
+}
+
+/// The `sha256` content hash of a PDU, unpadded base64.
+///
+/// Only the fields known to the serialized type are hashed, so this is only
+/// useful for this server's own PDUs.
+pub(crate) fn content_hash<T: Serialize + ?Sized>(value: &T) -> String {
+    let sha256_hash = sha256(value, UNHASHED_KEYS);
+
+    base64::encode_config(sha256_hash, base64::STANDARD_NO_PAD)
+}
+
+/// The ID of a room version 4+ event, i.e. its URL-safe reference hash.
+pub(crate) fn event_id<T: Serialize + ?Sized>(value: &T) -> Box<Id<Event>> {
+    let sha256_hash = sha256(value, UNSIGNED_KEYS);
+
+    let mut scratch_buffer = SmallVec::<[u8; 64]>::new();
+    scratch_buffer.resize(64, 0);
+    scratch_buffer[0] = b'$';
+    let hash_size = base64::encode_config_slice(
+        sha256_hash,
+        base64::URL_SAFE_NO_PAD,
+        &mut scratch_buffer[1..64],
+    );
+    let event_id: &str =
+        std::str::from_utf8(&scratch_buffer[..hash_size + 1]).expect("Base64 is always a string");
+
+    Id::<Event>::try_boxed_from_str(event_id).expect("Valid event ID")
+}
+
+fn sha256<T: Serialize + ?Sized>(value: &T, removed_keys: &[&str]) -> impl AsRef<[u8]> {
+    let mut hasher = sha2::Sha256::new();
+    write_canonical(value, removed_keys, &mut hasher)
+        .expect("Serializable objects should always be hashable");
+
+    hasher.finalize()
+}
@@ -1,39 +1,53 @@
 use std::{
-    collections::BTreeMap,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
     io::Write,
-    sync::{RwLock, RwLockReadGuard},
-    time::SystemTime,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        RwLock, RwLockReadGuard,
+    },
+    time::{Instant, SystemTime},
 };
 
-use ed25519_compact::{KeyPair, PublicKey};
+use ed25519_compact::KeyPair;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 
 use crate::{
+    config::Config,
+    foreign_keys::ForeignKeyCache,
     interner::{ArcStr, Interner},
     matrix_types::{Event, Id, Key, Room, ServerName},
-    persistence::RoomPersistence,
+    pdu_ref::parse_pdu_ref,
+    persistence::sync_directory,
     playground::ParsedPDU,
-    rendered_json::RenderedJson,
-    server_keys::{ServerKeys, VerifyKey},
+    room_store::SharedRoomStore,
+    server_keys::{OldVerifyKey, ServerKeys, VerifyKey},
+    signing::Signable,
+    transaction_log::TransactionLog,
 };
 
 pub(crate) struct State {
     // pub users: BTreeMap<Box<Id<User>>, UserState>,
-    pub server_key_pairs: BTreeMap<Box<Id<Key>>, ServerKeyPair>,
+    pub config: Config,
+    /// Where all of our files are; the working directory unless embedded.
+    pub data_dir: PathBuf,
+    server_key_pairs: RwLock<ServerKeyPairs>,
     pub server_name: Box<Id<ServerName>>,
-    pub foreign_server_keys_json:
-        BTreeMap<Box<Id<ServerName>>, Vec<RenderedJson<'static, ServerKeys>>>,
-    pub foreign_key_cache: BTreeMap<(Box<Id<ServerName>>, Box<Id<Key>>), PublicKey>,
+    foreign_keys: RwLock<ForeignKeyCache>,
     persistent: RwLock<Persistent>,
     ephemeral: RwLock<Ephemeral>,
+    transaction_log: RwLock<TransactionLog>,
+    net_log_index: AtomicUsize,
+    /// Set once `shutdown` started, after which nothing gets written anymore.
+    shutting_down: AtomicBool,
 }
 
 // TODO: Just a quick and dirty persistence store; needs to be fundamentally different
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Persistent {
     pub rooms: BTreeMap<Box<Id<Room>>, RoomState>,
-    pub net_log_index: usize,
 }
 
 // TODO: Same as above, but even quicker, and even dirtier
@@ -53,6 +67,30 @@ pub(crate) struct Ephemeral {
 pub(crate) struct RoomState {
     pub pdu_blobs: Vec<String>,
     pub room_db: Option<String>,
+    /// The event IDs of `pdu_blobs`, built on first use.
+    #[serde(skip)]
+    blob_event_ids: Option<BTreeSet<Box<Id<Event>>>>,
+}
+
+impl RoomState {
+    /// Adds a PDU to `pdu_blobs`, unless one with the same event ID is
+    /// already in there.
+    pub fn push_pdu_blob(&mut self, event_id: &Id<Event>, pdu_blob: &str) {
+        let pdu_blobs = &self.pdu_blobs;
+        let blob_event_ids = self.blob_event_ids.get_or_insert_with(|| {
+            pdu_blobs
+                .iter()
+                .filter_map(|pdu_blob| {
+                    let pdu_blob: &RawValue = serde_json::from_str(pdu_blob).ok()?;
+                    Some(crate::signing::event_id(&parse_pdu_ref(pdu_blob).ok()?))
+                })
+                .collect()
+        });
+
+        if blob_event_ids.insert(event_id.to_owned()) {
+            self.pdu_blobs.push(pdu_blob.to_owned());
+        }
+    }
 }
 
 #[derive(Default)]
@@ -60,19 +98,187 @@ pub(crate) struct EphemeralRoomState {
     pub pdus: BTreeMap<ArcStr<Id<Event>>, ParsedPDU>,
     pub pdus_by_timestamp: BTreeMap<TimeStamp, ArcStr<Id<Event>>>,
     pub interner: Interner,
-    pub room_persistence: Option<RoomPersistence>,
+    pub room_store: Option<SharedRoomStore>,
+    /// Whether `pdus` holds the whole room; otherwise it is empty, and new
+    /// PDUs only go to the store until the room is loaded again.
+    pub resident: bool,
+    pub last_access: Option<Instant>,
+    /// Memory taken up by the parsed PDUs, as measured while loading.
+    pub resident_size: usize,
+    /// How many loads of the room are under way; PDUs that come in meanwhile
+    /// are also kept in `pending_pdus`, as the loads may have read past them.
+    pub loading: usize,
+    pub pending_pdus: Vec<StoredPDU>,
+}
+
+/// A PDU as read back from storage, with its real origin if known.
+pub(crate) type StoredPDU = (Box<RawValue>, Option<Box<Id<ServerName>>>);
+
+impl EphemeralRoomState {
+    /// Drops the parsed PDUs, keeping the store (and its index) around to
+    /// load them again from.
+    pub fn evict(&mut self) {
+        self.pdus = BTreeMap::new();
+        self.pdus_by_timestamp = BTreeMap::new();
+        self.interner = Interner::default();
+        self.resident = false;
+        self.resident_size = 0;
+    }
+
+    pub fn idle_seconds(&self) -> Option<u64> {
+        Some(self.last_access?.elapsed().as_secs())
+    }
+
+    /// The latest PDU of each piece of state, by event type and state key.
+    ///
+    /// There is no state resolution yet, so later PDUs (by depth, then by
+    /// timestamp) simply win.
+    pub fn current_state(&self) -> BTreeMap<(&str, &str), &ParsedPDU> {
+        let mut state_pdus: Vec<&ParsedPDU> = self
+            .pdus
+            .values()
+            .filter(|parsed_pdu| parsed_pdu.pdu.state_key.as_str().is_some())
+            .collect();
+        state_pdus
+            .sort_by_key(|parsed_pdu| (parsed_pdu.pdu.depth, parsed_pdu.pdu.origin_server_ts));
+
+        state_pdus
+            .into_iter()
+            .filter_map(|parsed_pdu| {
+                let state_key = parsed_pdu.pdu.state_key.as_str()?;
+                Some(((&*parsed_pdu.pdu.pdu_type, state_key), parsed_pdu))
+            })
+            .collect()
+    }
+
+    /// The most recent PDUs that no other PDU follows up on, i.e. the
+    /// `prev_events` of the next one.
+    pub fn forward_extremities(&self, limit: usize) -> Vec<&ParsedPDU> {
+        let followed_up: BTreeSet<&Id<Event>> = self
+            .pdus
+            .values()
+            .flat_map(|parsed_pdu| parsed_pdu.pdu.prev_events.iter())
+            .map(|event_id| &**event_id)
+            .collect();
+
+        let mut extremities: Vec<&ParsedPDU> = self
+            .pdus
+            .values()
+            .filter(|parsed_pdu| !followed_up.contains(&*parsed_pdu.event_id))
+            .collect();
+        extremities.sort_by_key(|parsed_pdu| {
+            Reverse((parsed_pdu.pdu.depth, parsed_pdu.pdu.origin_server_ts))
+        });
+        extremities.truncate(limit);
+
+        extremities
+    }
+
+    /// The PDUs of `auth_event_ids`, and those that authorized them in turn,
+    /// as far as they are known.
+    pub fn auth_chain(&self, auth_event_ids: &[&Id<Event>]) -> Vec<&ParsedPDU> {
+        let mut seen = BTreeSet::new();
+        let mut auth_chain = Vec::new();
+        let mut next_pdus: Vec<&ParsedPDU> = auth_event_ids
+            .iter()
+            .filter_map(|event_id| self.pdus.get(*event_id))
+            .collect();
+
+        while let Some(parsed_pdu) = next_pdus.pop() {
+            if !seen.insert(&*parsed_pdu.event_id) {
+                continue;
+            }
+
+            auth_chain.push(parsed_pdu);
+            next_pdus.extend(
+                parsed_pdu
+                    .pdu
+                    .auth_events
+                    .iter()
+                    .filter_map(|event_id| self.pdus.get(&**event_id)),
+            );
+        }
+
+        auth_chain
+    }
+
+    /// Up to `limit` PDUs from `event_ids` back along their `prev_events`,
+    /// the deepest first; PDUs that aren't known end the walk on their
+    /// branch.
+    pub fn history(&self, event_ids: &[&Id<Event>], limit: usize) -> Vec<&ParsedPDU> {
+        let mut seen = BTreeSet::new();
+        let mut history = Vec::new();
+        let mut next_pdus = BinaryHeap::new();
+
+        let queue = |parsed_pdu: &ParsedPDU| {
+            (
+                parsed_pdu.pdu.depth,
+                parsed_pdu.pdu.origin_server_ts,
+                parsed_pdu.arc_event_id.clone(),
+            )
+        };
+
+        for &event_id in event_ids {
+            if let Some(parsed_pdu) = self.pdus.get(event_id) {
+                if seen.insert(&*parsed_pdu.event_id) {
+                    next_pdus.push(queue(parsed_pdu));
+                }
+            }
+        }
+
+        while history.len() < limit {
+            let parsed_pdu = match next_pdus.pop() {
+                Some((_depth, _timestamp, Some(event_id))) => &self.pdus[&event_id],
+                Some((_depth, _timestamp, None)) => continue,
+                None => break,
+            };
+            history.push(parsed_pdu);
+
+            for prev_event_id in &parsed_pdu.pdu.prev_events {
+                if let Some(prev_pdu) = self.pdus.get(&**prev_event_id) {
+                    if seen.insert(&*prev_pdu.event_id) {
+                        next_pdus.push(queue(prev_pdu));
+                    }
+                }
+            }
+        }
+
+        history
+    }
+}
+
+/// Our own signing keys; only the active ones are used to sign anything.
+///
+/// Expired keys are kept around so that they can still be advertised in
+/// `old_verify_keys`, and so that our own old events can still be verified.
+#[derive(Clone, Default)]
+pub(crate) struct ServerKeyPairs {
+    pub active: BTreeMap<Box<Id<Key>>, ServerKeyPair>,
+    pub expired: BTreeMap<Box<Id<Key>>, ServerKeyPair>,
+}
+
+impl ServerKeyPairs {
+    /// The keys that anything we sign should be signed with.
+    pub fn signing_keys(&self) -> impl Iterator<Item = (&Id<Key>, &KeyPair)> {
+        self.active
+            .iter()
+            .map(|(key_name, key_pair)| (&**key_name, &key_pair.key_pair))
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct ServerKeyPair {
     pub public_key_base64: String,
     pub key_pair: KeyPair,
+    pub expired_ts: Option<TimeStamp>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct ServerKeyPairBase64 {
     pub public_key_base64: String,
     pub key_pair_base64: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expired_ts: Option<TimeStamp>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -118,105 +324,215 @@ impl TimeStamp {
 }
 
 impl State {
-    pub(crate) fn new() -> Self {
-        let server_key_pairs = load_server_key_pairs();
-
-        let mut verify_keys = BTreeMap::new();
-
-        let server_name = Id::try_boxed_from_str("fluctlight-dev.demi.ro").unwrap();
-
-        for (key_name, key_pair) in &server_key_pairs {
-            let verify_key = VerifyKey {
-                key: key_pair.public_key_base64.clone(),
-            };
-
-            verify_keys.insert(key_name.clone(), verify_key);
-        }
-
-        let valid_until_ts = TimeStamp::one_week_from_today();
-
-        let mut own_server_keys = ServerKeys {
-            old_verify_keys: None,
-            server_name: server_name.clone(),
-            signatures: None,
-            valid_until_ts: Some(valid_until_ts),
-            verify_keys,
-        };
-        own_server_keys.sign(&server_name, &server_key_pairs);
-
-        let mut foreign_server_keys_json = BTreeMap::new();
-        let mut foreign_server_keys = BTreeMap::new();
-        let rendered_json = RenderedJson::from_trusted(
-            serde_json::to_string(&own_server_keys).expect("Valid JSON"),
-        );
-        foreign_server_keys_json.insert(server_name.clone(), vec![rendered_json]);
-        foreign_server_keys.insert(server_name, vec![own_server_keys.clone()]);
-
-        foreign_server_keys.extend(load_foreign_keys());
-
-        let mut foreign_key_cache = BTreeMap::new();
-
-        for (server_name, server_keys_list) in &foreign_server_keys {
-            for server_keys in server_keys_list {
-                for (key_name, verify_key) in &server_keys.verify_keys {
-                    let public_key = &verify_key.key;
-                    let public_key_bytes =
-                        base64::decode_config(public_key, base64::STANDARD_NO_PAD)
-                            .expect("Key already validated");
-                    let public_key =
-                        PublicKey::from_slice(&public_key_bytes).expect("Key already validated");
-
-                    let key_id = (server_name.to_owned(), key_name.to_owned());
-                    foreign_key_cache.insert(key_id, public_key);
-                    // FIXME: Check that it wasn't overwritten with something else
-                }
+    pub(crate) fn new(data_dir: &Path) -> Result<Self, String> {
+        let config = Config::load(data_dir)?;
+        let server_key_pairs = match read_server_key_pairs(data_dir)? {
+            Some(server_key_pairs) => server_key_pairs,
+            None => {
+                eprintln!("Generating new server keys...");
+                let server_key_pairs = ServerKeyPairs {
+                    active: generate_server_key_pairs(),
+                    expired: BTreeMap::new(),
+                };
+                save_server_key_pairs(data_dir, &server_key_pairs)
+                    .map_err(|err| format!("Could not save server keys: {}", err))?;
+                server_key_pairs
             }
-        }
+        };
+        let server_name = config.server_name.clone();
+
+        let own_server_keys = own_server_keys(&server_name, &server_key_pairs);
+        let foreign_keys = ForeignKeyCache::load(data_dir)
+            .map_err(|err| format!("Could not load foreign keys: {}", err))?;
 
         let rendered_server_keys: Box<RawValue> = serde_json::value::to_raw_value(&own_server_keys)
             .expect("Serialization should always succeed");
 
-        let persistent = Persistent::load();
+        let persistent = Persistent::load(data_dir)
+            .map_err(|err| format!("Could not load persistent state: {}", err))?;
+        let transaction_log = TransactionLog::load(data_dir)
+            .map_err(|err| format!("Could not load transaction log: {}", err))?;
         let ephemeral = Ephemeral {
             rooms: BTreeMap::new(),
             own_server_keys,
             rendered_server_keys,
         };
 
-        State {
+        Ok(State {
             // users: BTreeMap::new(),
-            server_key_pairs,
-            server_name: Id::try_boxed_from_str("fluctlight-dev.demi.ro").unwrap(),
-            foreign_server_keys_json,
-            foreign_key_cache,
+            config,
+            data_dir: data_dir.to_owned(),
+            server_key_pairs: RwLock::new(server_key_pairs),
+            server_name,
+            foreign_keys: RwLock::new(foreign_keys),
             persistent: RwLock::new(persistent),
             ephemeral: RwLock::new(ephemeral),
+            transaction_log: RwLock::new(transaction_log),
+            net_log_index: AtomicUsize::new(next_net_log_index(data_dir)),
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+
+    /// Loads everything `State::new` would, without keeping any of it, so that
+    /// a module that could not start gets refused before the old one is gone.
+    ///
+    /// Only reads; missing server keys are left for `State::new` to generate.
+    pub(crate) fn self_test(data_dir: &Path) -> Result<(), String> {
+        let config = Config::load(data_dir)?;
+        if let Some(server_key_pairs) = read_server_key_pairs(data_dir)? {
+            own_server_keys(&config.server_name, &server_key_pairs);
+        }
+
+        ForeignKeyCache::load(data_dir)
+            .map_err(|err| format!("Could not load foreign keys: {}", err))?;
+        Persistent::load(data_dir)
+            .map_err(|err| format!("Could not load persistent state: {}", err))?;
+
+        Ok(())
+    }
+
+    /// Makes sure everything written so far is on disk, and closes the room
+    /// stores (releasing their locks), so that the process can exit.
+    ///
+    /// Requests still running fail from here on instead of writing anything,
+    /// so that their senders retry them with whatever comes next.
+    ///
+    /// Keeps going after errors, so that one broken room doesn't keep the
+    /// others from being synced.
+    pub(crate) fn shutdown(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        // Checked under each lock below, so whoever holds one now finishes
+        // writing before it gets synced, and nobody writes after that
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        if self.persistent.is_poisoned() {
+            self.recover_persistent();
+        }
+
+        let persistent = match self.persistent.write() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
+        };
+
+        if let Err(err) = persistent.save(&self.data_dir) {
+            errors.push(format!("Could not save persistent state: {}", err));
+        }
+
+        drop(persistent);
+
+        if let Err(err) = self.with_transaction_log_mut(|transaction_log| transaction_log.close()) {
+            errors.push(format!("Could not sync transaction log: {}", err));
+        }
+
+        self.with_ephemeral_mut(|ephemeral| {
+            for (room_id, room) in &mut ephemeral.rooms {
+                let room_store = match room.room_store.take() {
+                    Some(room_store) => room_store,
+                    None => continue,
+                };
+
+                let sync_result = room_store.lock().sync();
+                if let Err(err) = sync_result {
+                    errors.push(format!("Could not sync room {}: {}", room_id, err));
+                }
+            }
+        });
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    pub fn server_key_pairs(&self) -> RwLockReadGuard<ServerKeyPairs> {
+        // Key pairs are only ever replaced wholesale, so they can't be corrupted
+        match self.server_key_pairs.read() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
         }
     }
 
     pub fn persistent(&self) -> RwLockReadGuard<Persistent> {
-        // TODO: corrupted; should use an older backup instead
+        if self.persistent.is_poisoned() {
+            self.recover_persistent();
+        }
+
         match self.persistent.read() {
             Ok(guard) => guard,
             Err(poison_error) => poison_error.into_inner(),
         }
     }
 
-    pub fn with_persistent_mut<R, F>(&self, f: F) -> R
+    /// Changes the persistent state and saves it to disk.
+    ///
+    /// If saving fails, the change is kept in memory but the error is
+    /// returned, so that the caller can decide whether to carry on.
+    pub fn with_persistent_mut<R, F>(&self, f: F) -> std::io::Result<R>
     where
         F: FnOnce(&mut Persistent) -> R,
     {
-        let mut persistent = self
-            .persistent
-            .write()
-            .expect("Lock poisoned; cannot do more changes on corrupt data");
+        if self.persistent.is_poisoned() {
+            self.recover_persistent();
+        }
+
+        let mut persistent = match self.persistent.write() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
+        };
+
+        // Would overwrite whatever the next module saves
+        if self.is_shutting_down() {
+            return Err(std::io::Error::other("Shutting down"));
+        }
 
-        // TODO: maybe reload from disk on panics and unpoison
-        let result = f(&mut *persistent);
+        let result = f(&mut persistent);
 
-        persistent.save();
+        persistent.save(&self.data_dir)?;
 
-        result
+        Ok(result)
+    }
+
+    /// Replaces persistent state left half-changed by a panic with the last
+    /// version that made it to disk.
+    fn recover_persistent(&self) {
+        let mut persistent = match self.persistent.write() {
+            Ok(_guard) => return,
+            Err(poison_error) => poison_error.into_inner(),
+        };
+
+        eprintln!("Persistent state lock poisoned; reloading from disk...");
+        match Persistent::load(&self.data_dir) {
+            Ok(backup) => *persistent = backup,
+            Err(err) => {
+                // Keep going with what we have, since there is nothing better
+                eprintln!("Could not reload persistent state: {}", err);
+            }
+        }
+
+        self.persistent.clear_poison();
+    }
+
+    pub fn with_transaction_log_mut<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut TransactionLog) -> R,
+    {
+        // Everything in the log is already on disk, so a panic can't corrupt it
+        let mut transaction_log = match self.transaction_log.write() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
+        };
+
+        f(&mut transaction_log)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn next_net_log_index(&self) -> usize {
+        self.net_log_index.fetch_add(1, Ordering::Relaxed)
     }
 
     pub fn ephemeral(&self) -> RwLockReadGuard<Ephemeral> {
@@ -240,109 +556,188 @@ impl State {
         f(&mut *ephemeral)
     }
 
-    pub fn get_server_key(
-        &self,
-        server_name: &Id<ServerName>,
-        key_name: &Id<Key>,
-    ) -> Option<&PublicKey> {
-        // FIXME: Timestamp should not be ignored
-        self.foreign_key_cache
-            .get(&(server_name.to_owned(), key_name.to_owned()))
+    pub fn foreign_keys(&self) -> RwLockReadGuard<ForeignKeyCache> {
+        // The cache can always be refetched, so a poisoned lock is harmless
+        match self.foreign_keys.read() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
+        }
+    }
+
+    pub fn with_foreign_keys_mut<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut ForeignKeyCache) -> R,
+    {
+        let mut foreign_keys = match self.foreign_keys.write() {
+            Ok(guard) => guard,
+            Err(poison_error) => poison_error.into_inner(),
+        };
+
+        f(&mut foreign_keys)
     }
 }
 
+const PERSISTENT_FILE: &str = "persistent.json";
+const PERSISTENT_TMP_FILE: &str = "persistent.json.tmp";
+const PERSISTENT_BACKUP_FILE: &str = "persistent.json.bak";
+
 impl Persistent {
-    fn load() -> Self {
-        if !std::path::Path::new("persistent.json").exists() {
+    /// Loads `persistent.json`, falling back to the previous version if the
+    /// latest one is missing or corrupt.
+    fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let path = data_dir.join(PERSISTENT_FILE);
+        let backup_path = data_dir.join(PERSISTENT_BACKUP_FILE);
+
+        if !path.exists() && !backup_path.exists() {
             eprintln!("Creating new persistent state...");
-            return Persistent {
+            return Ok(Persistent {
                 rooms: BTreeMap::new(),
-                net_log_index: 0,
-            };
+            });
         }
 
-        // FIXME: fix unwraps
-        let persistent_file = std::fs::File::open("persistent.json").unwrap();
-        serde_json::from_reader(persistent_file).unwrap()
+        let load_from = |path: &Path| -> std::io::Result<Persistent> {
+            let persistent_file = std::io::BufReader::new(std::fs::File::open(path)?);
+            Ok(serde_json::from_reader(persistent_file)?)
+        };
+
+        match load_from(&path) {
+            Ok(persistent) => Ok(persistent),
+            Err(err) if backup_path.exists() => {
+                eprintln!(
+                    "Could not load {}, using {}: {}",
+                    PERSISTENT_FILE, PERSISTENT_BACKUP_FILE, err
+                );
+                load_from(&backup_path)
+            }
+            Err(err) => Err(err),
+        }
     }
 
-    fn save(&self) {
-        // FIXME: fix unwraps
-        let persistent_file = std::fs::File::create("persistent.json").unwrap();
+    /// Writes the state to a temporary file and swaps it in, keeping the
+    /// previous version around as a backup.
+    fn save(&self, data_dir: &Path) -> std::io::Result<()> {
+        let path = data_dir.join(PERSISTENT_FILE);
+        let tmp_path = data_dir.join(PERSISTENT_TMP_FILE);
 
-        // FIXME: fix unwraps
-        serde_json::to_writer_pretty(persistent_file, self).unwrap();
+        let mut persistent_file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut persistent_file, self)?;
+        persistent_file.write_all(b"\n")?;
+
+        let persistent_file = persistent_file
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+        persistent_file.sync_all()?;
+        drop(persistent_file);
+
+        if path.exists() {
+            std::fs::rename(&path, data_dir.join(PERSISTENT_BACKUP_FILE))?;
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        sync_directory(data_dir)
     }
 }
 
-fn save_server_key_pairs(key_pairs: BTreeMap<Box<Id<Key>>, ServerKeyPair>) {
+/// Picks up numbering after the newest entry already in `net_log/`.
+fn next_net_log_index(data_dir: &Path) -> usize {
+    let entries = match std::fs::read_dir(data_dir.join("net_log")) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name();
+            let file_name = file_name.to_str()?;
+            let index = file_name.strip_prefix("net.")?.split('.').next()?;
+            index.parse::<usize>().ok()
+        })
+        .max()
+        .map_or(0, |index| index + 1)
+}
+
+fn save_server_key_pairs(data_dir: &Path, key_pairs: &ServerKeyPairs) -> std::io::Result<()> {
     #[cfg(unix)]
     use std::os::unix::prelude::OpenOptionsExt;
 
-    // FIXME: fix unwraps
+    let tmp_path = data_dir.join("server_keys.json.tmp");
+
     #[cfg(unix)]
     let mut file = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .mode(0o600)
-        .open("server_keys.json.tmp")
-        .unwrap();
+        .open(&tmp_path)?;
 
     #[cfg(not(unix))]
     let mut file = std::fs::OpenOptions::new()
         .create(true)
         .write(true)
-        .open("server_keys.json.tmp")
-        .unwrap();
+        .truncate(true)
+        .open(&tmp_path)?;
 
-    let key_pairs_base64: BTreeMap<Box<Id<Key>>, ServerKeyPairBase64> = key_pairs
-        .into_iter()
+    let key_pairs_base64: BTreeMap<&Id<Key>, ServerKeyPairBase64> = key_pairs
+        .active
+        .iter()
+        .chain(&key_pairs.expired)
         .map(|(key_name, key_pair)| {
             (
-                key_name,
+                &**key_name,
                 ServerKeyPairBase64 {
-                    public_key_base64: key_pair.public_key_base64,
+                    public_key_base64: key_pair.public_key_base64.clone(),
                     key_pair_base64: base64::encode(&*key_pair.key_pair),
+                    expired_ts: key_pair.expired_ts,
                 },
             )
         })
         .collect();
 
-    serde_json::to_writer_pretty(&mut file, &key_pairs_base64).unwrap();
-    file.write(b"\n").unwrap();
+    serde_json::to_writer_pretty(&mut file, &key_pairs_base64)?;
+    file.write_all(b"\n")?;
+    // Losing the key file would mean losing our identity, so make sure the
+    // new one is on disk before it replaces the old one
+    file.sync_all()?;
     drop(file);
-    std::fs::rename("server_keys.json.tmp", "server_keys.json").unwrap();
+    std::fs::rename(tmp_path, data_dir.join("server_keys.json"))?;
+    sync_directory(data_dir)
 }
 
-fn load_server_key_pairs() -> BTreeMap<Box<Id<Key>>, ServerKeyPair> {
-    if !std::path::Path::new("server_keys.json").exists() {
-        eprintln!("Generating new server keys...");
-        let key_pairs = generate_server_key_pairs();
-        save_server_key_pairs(key_pairs.clone());
-        return key_pairs;
+/// Reads our signing keys, if there are any yet.
+fn read_server_key_pairs(data_dir: &Path) -> Result<Option<ServerKeyPairs>, String> {
+    let path = data_dir.join("server_keys.json");
+
+    if !path.exists() {
+        return Ok(None);
     }
 
-    // FIXME: fix unwraps
-    let key_file = std::fs::File::open("server_keys.json").unwrap();
+    let invalid_keys = |err: &dyn std::fmt::Display| format!("Invalid {}: {}", path.display(), err);
+
+    let key_file = std::fs::File::open(&path)
+        .map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
 
     let key_pairs_base64: BTreeMap<Box<Id<Key>>, ServerKeyPairBase64> =
-        serde_json::from_reader(key_file).unwrap();
-    key_pairs_base64
-        .into_iter()
-        .map(|(key_name, key_pair)| {
-            (
-                key_name,
-                ServerKeyPair {
-                    public_key_base64: key_pair.public_key_base64,
-                    // FIXME: fix unwraps
-                    key_pair: KeyPair::from_slice(
-                        &base64::decode(key_pair.key_pair_base64).unwrap(),
-                    )
-                    .unwrap(),
-                },
-            )
-        })
-        .collect()
+        serde_json::from_reader(key_file).map_err(|err| invalid_keys(&err))?;
+
+    let mut key_pairs = ServerKeyPairs::default();
+
+    for (key_name, key_pair) in key_pairs_base64 {
+        let key_pair_bytes =
+            base64::decode(key_pair.key_pair_base64).map_err(|err| invalid_keys(&err))?;
+
+        let key_pair = ServerKeyPair {
+            public_key_base64: key_pair.public_key_base64,
+            key_pair: KeyPair::from_slice(&key_pair_bytes).map_err(|err| invalid_keys(&err))?,
+            expired_ts: key_pair.expired_ts,
+        };
+
+        if key_pair.expired_ts.is_some() {
+            key_pairs.expired.insert(key_name, key_pair);
+        } else {
+            key_pairs.active.insert(key_name, key_pair);
+        }
+    }
+
+    Ok(Some(key_pairs))
 }
 
 fn generate_server_key_pairs() -> BTreeMap<Box<Id<Key>>, ServerKeyPair> {
@@ -363,6 +758,7 @@ fn generate_server_key_pairs() -> BTreeMap<Box<Id<Key>>, ServerKeyPair> {
     let server_key_pair = ServerKeyPair {
         public_key_base64: public_key_base64.clone(),
         key_pair,
+        expired_ts: None,
     };
 
     let mut server_key_pairs = BTreeMap::new();
@@ -371,14 +767,120 @@ fn generate_server_key_pairs() -> BTreeMap<Box<Id<Key>>, ServerKeyPair> {
     server_key_pairs
 }
 
-fn load_foreign_keys() -> BTreeMap<Box<Id<ServerName>>, Vec<ServerKeys>> {
-    if !std::path::Path::new("foreign_keys.json").exists() {
-        return BTreeMap::new();
+fn own_server_keys(server_name: &Id<ServerName>, key_pairs: &ServerKeyPairs) -> ServerKeys {
+    let verify_keys = key_pairs
+        .active
+        .iter()
+        .map(|(key_name, key_pair)| {
+            let verify_key = VerifyKey {
+                key: key_pair.public_key_base64.clone(),
+            };
+            (key_name.clone(), verify_key)
+        })
+        .collect();
+
+    let old_verify_keys: BTreeMap<_, _> = key_pairs
+        .expired
+        .iter()
+        .map(|(key_name, key_pair)| {
+            let old_verify_key = OldVerifyKey {
+                expired_ts: key_pair.expired_ts.expect("Expired keys have a timestamp"),
+                key: key_pair.public_key_base64.clone(),
+            };
+            (key_name.clone(), old_verify_key)
+        })
+        .collect();
+
+    let mut own_server_keys = ServerKeys {
+        old_verify_keys: (!old_verify_keys.is_empty()).then_some(old_verify_keys),
+        server_name: server_name.to_owned(),
+        signatures: None,
+        valid_until_ts: Some(TimeStamp::one_week_from_today()),
+        verify_keys,
+    };
+    own_server_keys.sign(server_name, key_pairs.signing_keys());
+
+    own_server_keys
+}
+
+impl State {
+    /// Replace the active signing keys with a freshly generated one.
+    ///
+    /// The previous keys are moved to `old_verify_keys`, so that other
+    /// servers can still check whatever we signed with them so far.
+    pub(crate) fn rotate_server_keys(&self) -> Result<Box<Id<Key>>, String> {
+        let new_key_name = {
+            let mut key_pairs = self
+                .server_key_pairs
+                .write()
+                .expect("Lock poisoned; cannot do more changes on corrupt data");
+
+            let mut new_key_pairs = ServerKeyPairs {
+                active: generate_server_key_pairs(),
+                expired: key_pairs.expired.clone(),
+            };
+
+            let new_key_name = new_key_pairs
+                .active
+                .keys()
+                .next()
+                .expect("A key was just generated")
+                .clone();
+
+            if key_pairs.active.contains_key(&new_key_name)
+                || key_pairs.expired.contains_key(&new_key_name)
+            {
+                return Err(format!(
+                    "Generated key name {} is already in use",
+                    new_key_name
+                ));
+            }
+
+            let expired_ts = TimeStamp::now();
+            for (key_name, key_pair) in &key_pairs.active {
+                let mut key_pair = key_pair.clone();
+                key_pair.expired_ts = Some(expired_ts);
+                new_key_pairs.expired.insert(key_name.clone(), key_pair);
+            }
+
+            // Only switch over once the new keys are safely on disk
+            save_server_key_pairs(&self.data_dir, &new_key_pairs)
+                .map_err(|e| format!("Could not save server keys: {}", e))?;
+            *key_pairs = new_key_pairs;
+
+            new_key_name
+        };
+
+        let own_server_keys = own_server_keys(&self.server_name, &self.server_key_pairs());
+        let rendered_server_keys = serde_json::value::to_raw_value(&own_server_keys)
+            .expect("Serialization should always succeed");
+
+        self.with_ephemeral_mut(|ephemeral_state| {
+            ephemeral_state.own_server_keys = own_server_keys;
+            ephemeral_state.rendered_server_keys = rendered_server_keys;
+        });
+
+        Ok(new_key_name)
     }
 
-    // FIXME: fix unwraps
-    let key_file = std::fs::File::open("foreign_keys.json").unwrap();
-    serde_json::from_reader(key_file).unwrap()
+    /// Look up one of our own public keys, as it was valid at `valid_at`.
+    pub(crate) fn own_server_key(
+        &self,
+        key_name: &Id<Key>,
+        valid_at: TimeStamp,
+    ) -> Option<ed25519_compact::PublicKey> {
+        let key_pairs = self.server_key_pairs();
+
+        if let Some(key_pair) = key_pairs.active.get(key_name) {
+            return Some(key_pair.key_pair.pk);
+        }
+
+        key_pairs
+            .expired
+            .get(key_name)
+            .filter(|key_pair| key_pair.expired_ts >= Some(valid_at))
+            .map(|key_pair| key_pair.key_pair.pk)
+    }
 }
 
 impl State {
@@ -397,7 +899,7 @@ impl State {
                     Some(TimeStamp::one_week_from_today());
                 ephemeral_state
                     .own_server_keys
-                    .sign(&self.server_name, &self.server_key_pairs);
+                    .sign(&self.server_name, self.server_key_pairs().signing_keys());
                 ephemeral_state.rendered_server_keys =
                     serde_json::value::to_raw_value(&ephemeral_state.own_server_keys)
                         .expect("Serialization should always succeed");
@@ -0,0 +1,700 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    matrix_types::{Event, Id},
+    pdu_ref::parse_pdu_ref,
+    persistence::sync_directory,
+    playground::{ingest_transaction, Transaction},
+    signing::event_id,
+    state::{State, TimeStamp},
+};
+
+const TRANSACTION_LOG_FILE: &str = "transaction_log.json";
+const TRANSACTION_LOG_TMP_FILE: &str = "transaction_log.json.tmp";
+
+// Servers only ever retry their most recent transactions, so there's no need
+// to remember more than this many per origin.
+const REMEMBERED_TRANSACTIONS: usize = 1000;
+
+// The log gets compacted once it has grown by as many entries as compaction
+// left in it, so rewriting it costs a constant amount per entry on average.
+const MIN_ENTRIES_BEFORE_COMPACTION: usize = 1000;
+
+// A retry arriving while the original is still being ingested waits for it,
+// though not for longer than the sender would wait for an answer
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const IN_FLIGHT_MAX_WAIT: Duration = Duration::from_secs(30);
+
+pub(crate) type PDUResults = BTreeMap<Box<Id<Event>>, Result<(), String>>;
+
+/// A write-ahead log of incoming transactions.
+///
+/// Each transaction is written to `transaction_log.json` before being
+/// ingested, and marked as done with its results afterwards. Transactions that
+/// never got marked are ingested again on startup, and the results of done
+/// ones are used to answer retries without ingesting anything twice.
+#[derive(Default)]
+pub(crate) struct TransactionLog {
+    file: Option<File>,
+    completed: BTreeMap<String, VecDeque<(String, PDUResults)>>,
+    /// Not done, and not being ingested either; replayed on startup.
+    pending: Vec<PendingTransaction>,
+    /// Being ingested right now, by origin and transaction ID.
+    in_flight: BTreeMap<(String, String), Box<RawValue>>,
+    appended_entries: usize,
+    compacted_entries: usize,
+    /// Set on shutdown, as the file belongs to the next module from then on.
+    closed: bool,
+    data_dir: PathBuf,
+}
+
+pub(crate) struct PendingTransaction {
+    pub origin: String,
+    pub transaction_id: String,
+    pub body: Box<RawValue>,
+}
+
+/// A line in the log; `body` is set when received, `results` when done.
+#[derive(Serialize, Deserialize)]
+struct LogEntry<'a> {
+    #[serde(borrow)]
+    origin: Cow<'a, str>,
+    #[serde(borrow)]
+    transaction_id: Cow<'a, str>,
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    body: Option<&'a RawValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<BTreeMap<Box<Id<Event>>, Option<String>>>,
+}
+
+/// The parts of a `PUT /send` body needed to ingest it again.
+#[derive(Deserialize)]
+struct TransactionBody<'a> {
+    #[serde(borrow)]
+    edus: Option<Vec<&'a RawValue>>,
+    origin_server_ts: TimeStamp,
+    pdus: Vec<&'a RawValue>,
+}
+
+impl TransactionLog {
+    /// Reads the log, collecting transactions that were never marked as done,
+    /// and rewrites it without the bodies of finished ones.
+    pub(crate) fn load(data_dir: &Path) -> std::io::Result<Self> {
+        let mut transaction_log = TransactionLog {
+            data_dir: data_dir.to_owned(),
+            ..TransactionLog::default()
+        };
+        let log_path = data_dir.join(TRANSACTION_LOG_FILE);
+
+        if log_path.exists() {
+            let reader = BufReader::new(File::open(&log_path)?);
+            let mut pending = BTreeMap::new();
+
+            for line in reader.lines() {
+                let line = line?;
+
+                // A torn write at the end from a crash; the transaction was
+                // never acknowledged, so the sender will retry it
+                let entry: LogEntry = match serde_json::from_str(&line) {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        eprintln!("Skipping unreadable transaction log entry: {}", err);
+                        continue;
+                    }
+                };
+
+                let key = (entry.origin.to_string(), entry.transaction_id.to_string());
+
+                if let Some(results) = entry.results {
+                    pending.remove(&key);
+                    transaction_log.remember(key.0, key.1, decode_results(results));
+                } else if let Some(body) = entry.body {
+                    pending.insert(key, body.to_owned());
+                }
+            }
+
+            transaction_log.pending = pending
+                .into_iter()
+                .map(|((origin, transaction_id), body)| PendingTransaction {
+                    origin,
+                    transaction_id,
+                    body,
+                })
+                .collect();
+        }
+
+        transaction_log.compact()?;
+
+        Ok(transaction_log)
+    }
+
+    /// Returns the results of an already ingested transaction.
+    pub(crate) fn completed(&self, origin: &str, transaction_id: &str) -> Option<&PDUResults> {
+        self.completed
+            .get(origin)?
+            .iter()
+            .find(|(completed_id, _results)| completed_id == transaction_id)
+            .map(|(_completed_id, results)| results)
+    }
+
+    /// Whether a transaction is being ingested right now.
+    pub(crate) fn in_flight(&self, origin: &str, transaction_id: &str) -> bool {
+        self.in_flight
+            .contains_key(&(origin.to_string(), transaction_id.to_string()))
+    }
+
+    /// Durably records a transaction before it gets ingested.
+    ///
+    /// Until it is completed or left pending again, the transaction counts as
+    /// in flight.
+    pub(crate) fn begin(
+        &mut self,
+        origin: &str,
+        transaction_id: &str,
+        body: &RawValue,
+    ) -> std::io::Result<()> {
+        self.forget_pending(origin, transaction_id);
+        self.in_flight.insert(
+            (origin.to_string(), transaction_id.to_string()),
+            body.to_owned(),
+        );
+
+        let entry = LogEntry {
+            origin: Cow::Borrowed(origin),
+            transaction_id: Cow::Borrowed(transaction_id),
+            body: Some(body),
+            results: None,
+        };
+
+        self.append(&entry, true)
+    }
+
+    /// Marks a transaction as done, so that it won't be ingested again.
+    pub(crate) fn complete(
+        &mut self,
+        origin: &str,
+        transaction_id: &str,
+        results: &PDUResults,
+    ) -> std::io::Result<()> {
+        let entry = LogEntry {
+            origin: Cow::Borrowed(origin),
+            transaction_id: Cow::Borrowed(transaction_id),
+            body: None,
+            results: Some(encode_results(results)),
+        };
+
+        // Losing this only means ingesting the transaction again on startup
+        let result = self.append(&entry, false);
+
+        self.in_flight
+            .remove(&(origin.to_string(), transaction_id.to_string()));
+        self.remember(
+            origin.to_string(),
+            transaction_id.to_string(),
+            results.clone(),
+        );
+
+        if self.appended_entries >= self.compacted_entries.max(MIN_ENTRIES_BEFORE_COMPACTION) {
+            if let Err(err) = self.compact() {
+                eprintln!("Could not compact the transaction log: {}", err);
+            }
+        }
+
+        result
+    }
+
+    /// Gives up on a transaction for now, leaving it to be ingested again by
+    /// a retry or on startup.
+    pub(crate) fn leave_pending(&mut self, origin: &str, transaction_id: &str) {
+        let key = (origin.to_string(), transaction_id.to_string());
+
+        if let Some(body) = self.in_flight.remove(&key) {
+            let (origin, transaction_id) = key;
+            self.pending.push(PendingTransaction {
+                origin,
+                transaction_id,
+                body,
+            });
+        }
+    }
+
+    /// Takes the transactions to replay, which count as in flight from then on.
+    pub(crate) fn take_pending(&mut self) -> Vec<PendingTransaction> {
+        let pending = std::mem::take(&mut self.pending);
+
+        for pending in &pending {
+            self.in_flight.insert(
+                (pending.origin.clone(), pending.transaction_id.clone()),
+                pending.body.clone(),
+            );
+        }
+
+        pending
+    }
+
+    fn forget_pending(&mut self, origin: &str, transaction_id: &str) {
+        self.pending
+            .retain(|pending| pending.origin != origin || pending.transaction_id != transaction_id);
+    }
+
+    fn remember(&mut self, origin: String, transaction_id: String, results: PDUResults) {
+        let transactions = self.completed.entry(origin).or_default();
+
+        transactions.retain(|(completed_id, _results)| *completed_id != transaction_id);
+        transactions.push_back((transaction_id, results));
+
+        if transactions.len() > REMEMBERED_TRANSACTIONS {
+            transactions.pop_front();
+        }
+    }
+
+    /// Gets the entries that were appended without syncing onto disk too.
+    pub(crate) fn sync(&mut self) -> std::io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Syncs the log and stops writing to it.
+    pub(crate) fn close(&mut self) -> std::io::Result<()> {
+        let result = self.sync();
+
+        self.closed = true;
+        self.file = None;
+
+        result
+    }
+
+    fn append(&mut self, entry: &LogEntry, sync: bool) -> std::io::Result<()> {
+        if self.closed {
+            return Err(std::io::Error::other("Transaction log is closed"));
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.data_dir.join(TRANSACTION_LOG_FILE))?,
+            ),
+        };
+
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        self.appended_entries += 1;
+
+        if sync {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the log with only what is still needed: the results of the
+    /// remembered transactions, and the full bodies of pending and in-flight
+    /// ones.
+    fn compact(&mut self) -> std::io::Result<()> {
+        if self.closed {
+            return Err(std::io::Error::other("Transaction log is closed"));
+        }
+
+        let tmp_path = self.data_dir.join(TRANSACTION_LOG_TMP_FILE);
+        let mut tmp_file = File::create(&tmp_path)?;
+        let mut contents = Vec::new();
+        let mut entries = 0;
+
+        for (origin, transactions) in &self.completed {
+            for (transaction_id, results) in transactions {
+                let entry = LogEntry {
+                    origin: Cow::Borrowed(origin),
+                    transaction_id: Cow::Borrowed(transaction_id),
+                    body: None,
+                    results: Some(encode_results(results)),
+                };
+                serde_json::to_writer(&mut contents, &entry)?;
+                contents.push(b'\n');
+                entries += 1;
+            }
+        }
+
+        let pending = self
+            .pending
+            .iter()
+            .map(|pending| (&pending.origin, &pending.transaction_id, &pending.body));
+        let in_flight = self
+            .in_flight
+            .iter()
+            .map(|((origin, transaction_id), body)| (origin, transaction_id, body));
+
+        for (origin, transaction_id, body) in pending.chain(in_flight) {
+            let entry = LogEntry {
+                origin: Cow::Borrowed(origin),
+                transaction_id: Cow::Borrowed(transaction_id),
+                body: Some(body),
+                results: None,
+            };
+            serde_json::to_writer(&mut contents, &entry)?;
+            contents.push(b'\n');
+            entries += 1;
+        }
+
+        tmp_file.write_all(&contents)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        std::fs::rename(&tmp_path, self.data_dir.join(TRANSACTION_LOG_FILE))?;
+        sync_directory(&self.data_dir)?;
+
+        // Reopen lazily, since the old handle points to the replaced file
+        self.file = None;
+        self.appended_entries = 0;
+        self.compacted_entries = entries;
+
+        Ok(())
+    }
+}
+
+fn encode_results(results: &PDUResults) -> BTreeMap<Box<Id<Event>>, Option<String>> {
+    results
+        .iter()
+        .map(|(event_id, result)| (event_id.clone(), result.clone().err()))
+        .collect()
+}
+
+fn decode_results(results: BTreeMap<Box<Id<Event>>, Option<String>>) -> PDUResults {
+    results
+        .into_iter()
+        .map(|(event_id, error)| (event_id, error.map_or(Ok(()), Err)))
+        .collect()
+}
+
+/// Ingests a transaction received over federation, at most once.
+///
+/// `origin` has to be the server that authenticated the request, as anyone
+/// could put a different server's name into the body.
+pub(crate) fn ingest_logged_transaction(
+    state: &State,
+    transaction: Transaction<'_>,
+    body: &RawValue,
+    pdus: &[&RawValue],
+    edus: &[&RawValue],
+) -> PDUResults {
+    let Transaction {
+        transaction_id,
+        origin,
+        ..
+    } = transaction;
+
+    let waiting_since = Instant::now();
+
+    let already_completed = loop {
+        // `None` while another request is still ingesting the transaction
+        let status = state.with_transaction_log_mut(|transaction_log| {
+            if let Some(results) = transaction_log.completed(origin, transaction_id) {
+                return Some(Some(results.clone()));
+            }
+
+            if transaction_log.in_flight(origin, transaction_id) {
+                return None;
+            }
+
+            if let Err(err) = transaction_log.begin(origin, transaction_id, body) {
+                // Carry on regardless; the sender will retry if we crash
+                eprintln!("Could not log transaction {transaction_id} from {origin}: {err}");
+            }
+
+            Some(None)
+        });
+
+        match status {
+            Some(already_completed) => break already_completed,
+            None if waiting_since.elapsed() >= IN_FLIGHT_MAX_WAIT => {
+                eprintln!("Transaction {transaction_id} from {origin} is still in flight");
+                return still_in_flight(pdus);
+            }
+            None => std::thread::sleep(IN_FLIGHT_POLL_INTERVAL),
+        }
+    };
+
+    if let Some(results) = already_completed {
+        eprintln!("Transaction {transaction_id} from {origin} already ingested");
+        return results;
+    }
+
+    let in_flight = InFlight {
+        state,
+        origin,
+        transaction_id,
+    };
+    let results = ingest_transaction(state, Some(transaction), pdus, edus);
+    in_flight.complete(&results);
+
+    results
+}
+
+/// Fails every PDU of a transaction that is still being ingested by another
+/// request, so that the sender tries them again later.
+fn still_in_flight(pdus: &[&RawValue]) -> PDUResults {
+    pdus.iter()
+        .filter_map(|pdu_blob| parse_pdu_ref(pdu_blob).ok())
+        .map(|pdu_ref| {
+            let err = "Transaction is still being processed".to_string();
+            (event_id(&pdu_ref), Err(err))
+        })
+        .collect()
+}
+
+/// A transaction that is being ingested, which stops being in flight once
+/// dropped, even if ingesting it panicked.
+struct InFlight<'a> {
+    state: &'a State,
+    origin: &'a str,
+    transaction_id: &'a str,
+}
+
+impl<'a> InFlight<'a> {
+    fn complete(self, results: &PDUResults) {
+        let (origin, transaction_id) = (self.origin, self.transaction_id);
+
+        // Failed PDUs get another chance when the sender (or startup) retries
+        if results.values().any(|result| result.is_err()) {
+            return;
+        }
+
+        self.state.with_transaction_log_mut(|transaction_log| {
+            if let Err(err) = transaction_log.complete(origin, transaction_id, results) {
+                eprintln!(
+                    "Could not mark transaction {transaction_id} from {origin} as done: {err}"
+                );
+            }
+        });
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        // Does nothing if completed already
+        self.state.with_transaction_log_mut(|transaction_log| {
+            transaction_log.leave_pending(self.origin, self.transaction_id)
+        });
+    }
+}
+
+/// Ingests the transactions that were received but not fully processed
+/// before the last shutdown.
+pub(crate) fn replay_pending_transactions(state: &State) {
+    let pending = state.with_transaction_log_mut(|transaction_log| transaction_log.take_pending());
+
+    for pending in pending {
+        let PendingTransaction {
+            origin,
+            transaction_id,
+            body,
+        } = &pending;
+
+        eprintln!("Replaying transaction {transaction_id} from {origin}");
+
+        let in_flight = InFlight {
+            state,
+            origin,
+            transaction_id,
+        };
+
+        let transaction_body: TransactionBody = match serde_json::from_str(body.get()) {
+            Ok(transaction_body) => transaction_body,
+            Err(err) => {
+                // It won't parse any better next time, so it is marked as
+                // done without any PDUs, rather than left pending forever
+                eprintln!("Dropping unparseable logged transaction: {}", err);
+                in_flight.complete(&PDUResults::new());
+                continue;
+            }
+        };
+
+        let transaction = Transaction {
+            transaction_id,
+            origin,
+            origin_server_ts: transaction_body.origin_server_ts,
+        };
+
+        let results = ingest_transaction(
+            state,
+            Some(transaction),
+            &transaction_body.pdus,
+            &transaction_body.edus.unwrap_or_default(),
+        );
+        in_flight.complete(&results);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty data directory, unique to the test.
+    fn data_dir(test_name: &str) -> PathBuf {
+        let data_dir = std::env::temp_dir().join(format!(
+            "fluctlight-transaction-log-{}-{}",
+            std::process::id(),
+            test_name
+        ));
+
+        if data_dir.exists() {
+            std::fs::remove_dir_all(&data_dir).unwrap();
+        }
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        data_dir
+    }
+
+    fn body(json: &str) -> Box<RawValue> {
+        RawValue::from_string(json.to_string()).unwrap()
+    }
+
+    fn results(event_ids: &[(&str, Option<&str>)]) -> PDUResults {
+        event_ids
+            .iter()
+            .map(|&(event_id, error)| {
+                let event_id = Id::try_boxed_from_str(event_id).unwrap();
+                (
+                    event_id,
+                    error.map_or(Ok(()), |error| Err(error.to_string())),
+                )
+            })
+            .collect()
+    }
+
+    fn pending_ids(transaction_log: &mut TransactionLog) -> Vec<String> {
+        transaction_log
+            .take_pending()
+            .into_iter()
+            .map(|pending| format!("{}/{}", pending.origin, pending.transaction_id))
+            .collect()
+    }
+
+    #[test]
+    fn completed_transactions_are_remembered_across_restart() {
+        let data_dir = data_dir("completed_transactions_are_remembered_across_restart");
+        let done = results(&[("$accepted", None), ("$rejected", Some("Bad signature"))]);
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        transaction_log
+            .begin("a.example", "1", &body(r#"{"pdus":[]}"#))
+            .unwrap();
+        transaction_log.complete("a.example", "1", &done).unwrap();
+        drop(transaction_log);
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        assert_eq!(transaction_log.completed("a.example", "1"), Some(&done));
+        // Same ID, different origin
+        assert_eq!(transaction_log.completed("b.example", "1"), None);
+        assert!(pending_ids(&mut transaction_log).is_empty());
+    }
+
+    #[test]
+    fn unfinished_transactions_are_pending_after_restart() {
+        let data_dir = data_dir("unfinished_transactions_are_pending_after_restart");
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        for transaction_id in ["1", "2", "3"] {
+            transaction_log
+                .begin("a.example", transaction_id, &body(r#"{"pdus":[]}"#))
+                .unwrap();
+        }
+        transaction_log
+            .complete("a.example", "2", &PDUResults::new())
+            .unwrap();
+        // Failed for now, like when a PDU could not be written
+        transaction_log.leave_pending("a.example", "3");
+        drop(transaction_log);
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        assert_eq!(
+            pending_ids(&mut transaction_log),
+            ["a.example/1", "a.example/3"]
+        );
+        assert!(transaction_log.in_flight("a.example", "1"));
+    }
+
+    #[test]
+    fn compaction_keeps_only_what_is_needed() {
+        let data_dir = data_dir("compaction_keeps_only_what_is_needed");
+        let large_body = format!(r#"{{"pdus":[],"padding":"{}"}}"#, "x".repeat(1000));
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        transaction_log
+            .begin("a.example", "done", &body(&large_body))
+            .unwrap();
+        transaction_log
+            .complete("a.example", "done", &results(&[("$event", None)]))
+            .unwrap();
+        transaction_log
+            .begin("a.example", "unfinished", &body(r#"{"pdus":[]}"#))
+            .unwrap();
+        transaction_log.compact().unwrap();
+        drop(transaction_log);
+
+        // One line each, without the body of the finished one
+        let contents = std::fs::read_to_string(data_dir.join(TRANSACTION_LOG_FILE)).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(!contents.contains("padding"));
+        assert!(!data_dir.join(TRANSACTION_LOG_TMP_FILE).exists());
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        assert!(transaction_log.completed("a.example", "done").is_some());
+        assert_eq!(pending_ids(&mut transaction_log), ["a.example/unfinished"]);
+    }
+
+    #[test]
+    fn replay_finishes_pending_transactions() {
+        let data_dir = data_dir("replay_finishes_pending_transactions");
+        std::fs::write(
+            data_dir.join("config.json"),
+            r#"{"server_name":"replay.example"}"#,
+        )
+        .unwrap();
+
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        transaction_log
+            .begin(
+                "a.example",
+                "empty",
+                &body(r#"{"origin_server_ts":1,"pdus":[]}"#),
+            )
+            .unwrap();
+        transaction_log
+            .begin("a.example", "unparseable", &body(r#"{"pdus":"none"}"#))
+            .unwrap();
+        drop(transaction_log);
+
+        let state = State::new(&data_dir).unwrap();
+        replay_pending_transactions(&state);
+
+        state.with_transaction_log_mut(|transaction_log| {
+            for transaction_id in ["empty", "unparseable"] {
+                assert!(!transaction_log.in_flight("a.example", transaction_id));
+                assert!(transaction_log
+                    .completed("a.example", transaction_id)
+                    .is_some());
+            }
+        });
+        state.shutdown().unwrap();
+        drop(state);
+
+        // Neither comes back on the next start
+        let mut transaction_log = TransactionLog::load(&data_dir).unwrap();
+        assert!(pending_ids(&mut transaction_log).is_empty());
+    }
+}
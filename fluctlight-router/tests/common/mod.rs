@@ -0,0 +1,539 @@
+//! Runs fluctlight servers inside the test process, each in its own data
+//! directory and on its own loopback port, with `federation_addresses`
+//! pointing them at each other so that nothing goes out to the network.
+
+use std::{
+    borrow::Cow,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_compact::KeyPair;
+use fluctlight_mod_interface::{Header, OpaqueModuleState, Request, RequestHead, Response};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+static NEXT_FEDERATION_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestServer {
+    pub server_name: String,
+    pub data_dir: PathBuf,
+    pub address: SocketAddr,
+    module_state: Arc<OpaqueModuleState>,
+}
+
+/// Starts a server for each name, with freshly generated keys, all of them
+/// already members of the given rooms.
+///
+/// The rooms are kept in `persistent.json`, so that the PDUs each server
+/// accepted can be read back from there.
+pub fn start_federation(server_names: &[&str], room_ids: &[&str]) -> Vec<TestServer> {
+    let servers: Vec<(&str, &[&str])> = server_names
+        .iter()
+        .map(|server_name| (*server_name, room_ids))
+        .collect();
+
+    start_servers(&servers)
+}
+
+/// Like `start_federation`, with the rooms each server starts out in given
+/// separately, e.g. to have a server join one later on.
+pub fn start_servers(servers: &[(&str, &[&str])]) -> Vec<TestServer> {
+    let server_names: Vec<&str> = servers
+        .iter()
+        .map(|(server_name, _rooms)| *server_name)
+        .collect();
+    let federation_id = NEXT_FEDERATION_ID.fetch_add(1, Ordering::Relaxed);
+    let base_dir = std::env::temp_dir().join(format!(
+        "fluctlight-test-{}-{}",
+        std::process::id(),
+        federation_id
+    ));
+    let _ = fs::remove_dir_all(&base_dir);
+
+    // Every server has to know where the others are before it starts
+    let listeners: Vec<TcpListener> = server_names
+        .iter()
+        .map(|_| TcpListener::bind("127.0.0.1:0").expect("Could not listen on loopback"))
+        .collect();
+
+    let federation_addresses: serde_json::Map<String, Value> = server_names
+        .iter()
+        .zip(&listeners)
+        .map(|(server_name, listener)| {
+            let address = listener.local_addr().expect("Listener has an address");
+            (server_name.to_string(), json!(address.to_string()))
+        })
+        .collect();
+
+    servers
+        .iter()
+        .zip(listeners)
+        .map(|((server_name, room_ids), listener)| {
+            let rooms: serde_json::Map<String, Value> = room_ids
+                .iter()
+                .map(|room_id| {
+                    (
+                        room_id.to_string(),
+                        json!({ "pdu_blobs": [], "room_db": null }),
+                    )
+                })
+                .collect();
+
+            let data_dir = base_dir.join(server_name);
+            fs::create_dir_all(&data_dir).expect("Could not create data directory");
+
+            write_json(
+                &data_dir.join("config.json"),
+                &json!({
+                    "server_name": server_name,
+                    "federation_addresses": federation_addresses,
+                }),
+            );
+            write_json(
+                &data_dir.join("persistent.json"),
+                &json!({ "rooms": rooms }),
+            );
+
+            let address = listener.local_addr().expect("Listener has an address");
+            let module_state = fluctlight_router::create_state_in(&data_dir)
+                .unwrap_or_else(|err| panic!("Could not create state of {}: {}", server_name, err));
+            let module_state = Arc::new(module_state);
+
+            // Never stops; the servers go away along with the test process
+            let serving_state = module_state.clone();
+            thread::spawn(move || serve(listener, serving_state));
+
+            TestServer {
+                server_name: server_name.to_string(),
+                data_dir,
+                address,
+                module_state,
+            }
+        })
+        .collect()
+}
+
+impl TestServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// Reads one of the files the server keeps, e.g. `persistent.json`.
+    pub fn read_json(&self, file_name: &str) -> Value {
+        let file = fs::File::open(self.data_dir.join(file_name))
+            .unwrap_or_else(|err| panic!("Could not open {}: {}", file_name, err));
+
+        serde_json::from_reader(file)
+            .unwrap_or_else(|err| panic!("Could not parse {}: {}", file_name, err))
+    }
+
+    /// Every PDU the server has on disk for a room, from `persistent.json` or
+    /// from the room's store if it was migrated. Only the first block of each
+    /// PDU file is read, as no test writes enough to fill one.
+    pub fn stored_pdus(&self, room_id: &str) -> Vec<Value> {
+        let persistent = self.read_json("persistent.json");
+        let room = &persistent["rooms"][room_id];
+        assert!(
+            room.is_object(),
+            "{} does not know {}",
+            self.server_name,
+            room_id
+        );
+
+        let mut pdus: Vec<Value> = room["pdu_blobs"]
+            .as_array()
+            .expect("PDU blobs are a list")
+            .iter()
+            .map(|pdu_blob| {
+                let pdu_blob = pdu_blob.as_str().expect("PDU blobs are strings");
+                serde_json::from_str(pdu_blob).expect("Stored PDU is valid JSON")
+            })
+            .collect();
+
+        if let Some(room_db) = room["room_db"].as_str() {
+            let mut file_names: Vec<String> = fs::read_dir(self.data_dir.join(room_db))
+                .unwrap_or_else(|err| panic!("Could not list {}: {}", room_db, err))
+                .map(|entry| entry.expect("Room store is listable").file_name())
+                .filter_map(|file_name| file_name.into_string().ok())
+                .filter(|file_name| file_name.ends_with("_pdus.0.json"))
+                .collect();
+            file_names.sort();
+
+            for file_name in file_names {
+                let path = Path::new(room_db).join(file_name);
+                let contents = fs::read_to_string(self.data_dir.join(&path))
+                    .unwrap_or_else(|err| panic!("Could not read {}: {}", path.display(), err));
+
+                for line in contents.lines() {
+                    let mut stored: Value = serde_json::from_str(line)
+                        .unwrap_or_else(|err| panic!("Torn line in {}: {}", path.display(), err));
+                    pdus.push(stored["pdu_blob"].take());
+                }
+            }
+        }
+
+        pdus
+    }
+
+    /// The key the server signs with, as it generated it on startup.
+    pub fn signing_key(&self) -> (String, KeyPair) {
+        let server_keys = self.read_json("server_keys.json");
+
+        let (key_name, key) = server_keys
+            .as_object()
+            .expect("Server keys are a map")
+            .iter()
+            .find(|(_key_name, key)| key["expired_ts"].is_null())
+            .expect("Server has an active key");
+
+        let key_pair_base64 = key["key_pair_base64"].as_str().expect("Key is a string");
+        let key_pair = base64::decode(key_pair_base64)
+            .ok()
+            .and_then(|key_pair| KeyPair::from_slice(&key_pair).ok())
+            .expect("Key pair is valid");
+
+        (key_name.clone(), key_pair)
+    }
+
+    /// Adds this server's signature to a JSON object, covering everything but
+    /// `signatures` and `unsigned`.
+    pub fn sign_json(&self, value: &mut Value) {
+        let object = value.as_object_mut().expect("Only objects can be signed");
+        let signatures = object.remove("signatures");
+        let unsigned = object.remove("unsigned");
+
+        let signature = self.sign_bytes(&canonical_json(value));
+
+        let object = value.as_object_mut().expect("Only objects can be signed");
+        if let Some(unsigned) = unsigned {
+            object.insert("unsigned".to_owned(), unsigned);
+        }
+
+        let mut signatures = signatures.unwrap_or_else(|| json!({}));
+        signatures[&self.server_name][&self.signing_key().0] = json!(signature);
+        object.insert("signatures".to_owned(), signatures);
+    }
+
+    /// Whether a JSON object carries a valid signature from this server.
+    pub fn has_signed_json(&self, value: &Value) -> bool {
+        let (key_name, key_pair) = self.signing_key();
+
+        let signature = value["signatures"][&self.server_name][&key_name]
+            .as_str()
+            .and_then(|signature| base64::decode_config(signature, base64::STANDARD_NO_PAD).ok())
+            .and_then(|signature| ed25519_compact::Signature::from_slice(&signature).ok());
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return false,
+        };
+
+        let mut value = value.clone();
+        let object = value.as_object_mut().expect("Only objects can be signed");
+        object.remove("signatures");
+        object.remove("unsigned");
+
+        key_pair
+            .pk
+            .verify(canonical_json(&value), &signature)
+            .is_ok()
+    }
+
+    /// A signed `m.room.message` PDU from one of this server's users.
+    pub fn message_pdu(&self, room_id: &str, body: &str) -> Value {
+        self.build_pdu(json!({
+            "content": { "body": body, "msgtype": "m.text" },
+            "room_id": room_id,
+            "type": "m.room.message",
+        }))
+    }
+
+    /// Hashes and signs a PDU, filling in whatever it leaves out as for the
+    /// first PDU from `@alice` on this server.
+    pub fn build_pdu(&self, fields: Value) -> Value {
+        let mut pdu = json!({
+            "auth_events": [],
+            "depth": 1,
+            "origin": self.server_name,
+            "origin_server_ts": now_millis(),
+            "prev_events": [],
+            "sender": format!("@alice:{}", self.server_name),
+        });
+        for (key, value) in fields.as_object().expect("PDUs are objects") {
+            pdu[key] = value.clone();
+        }
+
+        let content_hash = base64::encode_config(
+            Sha256::digest(canonical_json(&pdu)),
+            base64::STANDARD_NO_PAD,
+        );
+        pdu["hashes"] = json!({ "sha256": content_hash });
+
+        // Events are signed in their redacted form
+        let mut redacted_pdu = redact(&pdu);
+        self.sign_json(&mut redacted_pdu);
+        pdu["signatures"] = redacted_pdu["signatures"].take();
+
+        pdu
+    }
+
+    /// Syncs and closes the server's stores, as before the process exits;
+    /// it keeps answering requests.
+    pub fn shutdown(&self) {
+        if let Err(err) = fluctlight_router::shutdown(&self.module_state).into_result() {
+            panic!(
+                "{} could not shut down: {}",
+                self.server_name,
+                String::from(err)
+            );
+        }
+    }
+
+    /// Sends a request to another server with this one's `X-Matrix`
+    /// authorization, panicking unless it answers with a JSON success.
+    pub fn send_federation_request(
+        &self,
+        destination: &TestServer,
+        method: &str,
+        uri: &str,
+        content: Option<Value>,
+    ) -> Value {
+        self.try_federation_request(destination, method, uri, content)
+            .unwrap_or_else(|(status, body)| {
+                panic!("{} {} answered {}: {}", method, uri, status, body)
+            })
+    }
+
+    /// Like `send_federation_request`, but returns error statuses along with
+    /// the body instead of panicking.
+    pub fn try_federation_request(
+        &self,
+        destination: &TestServer,
+        method: &str,
+        uri: &str,
+        content: Option<Value>,
+    ) -> Result<Value, (u16, String)> {
+        let mut signed_json = json!({
+            "destination": destination.server_name,
+            "method": method,
+            "origin": self.server_name,
+            "uri": uri,
+        });
+        if let Some(content) = &content {
+            signed_json["content"] = content.clone();
+        }
+
+        let authorization = format!(
+            "X-Matrix origin={},key=\"{}\",sig=\"{}\"",
+            self.server_name,
+            self.signing_key().0,
+            self.sign_bytes(&canonical_json(&signed_json))
+        );
+
+        let request =
+            ureq::request(method, &destination.url(uri)).set("Authorization", &authorization);
+
+        let response = match content {
+            Some(content) => request.send_json(content),
+            None => request.call(),
+        };
+
+        match response {
+            Ok(response) => Ok(response.into_json().unwrap_or_else(|err| {
+                panic!("{} {} answered with invalid JSON: {}", method, uri, err)
+            })),
+            Err(ureq::Error::Status(status, response)) => {
+                Err((status, response.into_string().unwrap_or_default()))
+            }
+            Err(err) => panic!("{} {} failed: {}", method, uri, err),
+        }
+    }
+
+    fn sign_bytes(&self, bytes: &[u8]) -> String {
+        let signature = self.signing_key().1.sk.sign(bytes, None);
+
+        base64::encode_config(*signature, base64::STANDARD_NO_PAD)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is after 1970")
+        .as_millis() as u64
+}
+
+/// The ID of a PDU in room versions 4 and up, i.e. the URL-safe hash of its
+/// redacted form.
+pub fn event_id(pdu: &Value) -> String {
+    let mut redacted_pdu = redact(pdu);
+    let object = redacted_pdu.as_object_mut().expect("PDUs are objects");
+    object.remove("signatures");
+    object.remove("unsigned");
+
+    let reference_hash = Sha256::digest(canonical_json(&redacted_pdu));
+
+    format!(
+        "${}",
+        base64::encode_config(reference_hash, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Keeps only the content that survives redaction, for the PDU types tests
+/// use (as of room version 6).
+fn redact(pdu: &Value) -> Value {
+    let kept_keys: &[&str] = match pdu["type"].as_str() {
+        Some("m.room.create") => return pdu.clone(),
+        Some("m.room.member") => &["membership"],
+        Some("m.room.join_rules") => &["join_rule"],
+        Some("m.room.power_levels") => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        _ => &[],
+    };
+
+    let mut redacted_pdu = pdu.clone();
+    let content = redacted_pdu["content"]
+        .as_object_mut()
+        .expect("PDU content is an object");
+    content.retain(|key, _value| kept_keys.contains(&key.as_str()));
+
+    redacted_pdu
+}
+
+/// serde_json already writes `Value`s the canonical way (sorted keys, no
+/// whitespace), as long as all numbers are integers.
+fn canonical_json(value: &Value) -> Vec<u8> {
+    serde_json::to_vec(value).expect("Serialization should always succeed")
+}
+
+fn write_json(path: &Path, value: &Value) {
+    let file = fs::File::create(path)
+        .unwrap_or_else(|err| panic!("Could not create {}: {}", path.display(), err));
+
+    serde_json::to_writer_pretty(file, value)
+        .unwrap_or_else(|err| panic!("Could not write {}: {}", path.display(), err));
+}
+
+fn serve(listener: TcpListener, module_state: Arc<OpaqueModuleState>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_err) => continue,
+        };
+
+        // Requests may need an answer from another server (e.g. its keys),
+        // which may in turn call back, so each gets its own thread
+        let module_state = module_state.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &module_state) {
+                eprintln!("Test server could not answer: {}", err);
+            }
+        });
+    }
+}
+
+/// Answers one HTTP/1.1 request, understanding just enough of the protocol
+/// for what ureq sends, then closes the connection.
+fn handle_connection(stream: TcpStream, module_state: &OpaqueModuleState) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let (method, uri) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, uri, _version] => (method.to_owned(), uri.to_owned()),
+        _ => return Ok(()),
+    };
+
+    let mut header_lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => header_lines.push((name.to_owned(), value.trim().to_owned())),
+            None => break,
+        }
+    }
+
+    let headers: Vec<Header> = header_lines
+        .iter()
+        .map(|(name, value)| Header::new(name, value.as_bytes()))
+        .collect();
+
+    let verdict = fluctlight_router::check_request_head(RequestHead::new(
+        module_state,
+        &uri,
+        &method,
+        &headers,
+    ));
+
+    let response = match verdict.into_result() {
+        Ok(_body_limit) => {
+            let content_length = header_lines
+                .iter()
+                .find(|(name, _value)| name.eq_ignore_ascii_case("Content-Length"))
+                .and_then(|(_name, value)| value.parse().ok())
+                .unwrap_or(0);
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+
+            fluctlight_router::process_request(Request::new(
+                module_state,
+                &uri,
+                &method,
+                &headers,
+                &body,
+            ))
+            .into_result()
+            .unwrap_or_else(|err| Response::new(500, "text/plain", err.into_bytes().into()))
+        }
+        Err(response) => response,
+    };
+
+    write_response(stream, response)
+}
+
+fn write_response(mut stream: TcpStream, response: Response) -> std::io::Result<()> {
+    let extra_headers: Vec<(String, String)> = response
+        .headers()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+    let (status, content_type, body): (u16, &str, Cow<[u8]>) = response.into();
+
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    for (name, value) in extra_headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body)
+}
@@ -0,0 +1,432 @@
+//! Two servers federating with each other over loopback, end to end.
+
+mod common;
+
+use serde_json::{json, Value};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::{event_id, now_millis, start_federation, start_servers, TestServer};
+
+const SERVER_A: &str = "a.localhost";
+const SERVER_B: &str = "b.localhost";
+const ROOM_ID: &str = "!federation-test:a.localhost";
+
+#[test]
+fn notary_serves_keys_fetched_from_origin() {
+    let servers = start_federation(&[SERVER_A, SERVER_B], &[]);
+    let (a, b) = (&servers[0], &servers[1]);
+
+    let (key_name, _key_pair) = a.signing_key();
+    let public_key = &a.read_json("server_keys.json")[&key_name]["public_key_base64"];
+
+    let response: Value = ureq::post(&b.url("/_matrix/key/v2/query"))
+        .send_json(json!({ "server_keys": { SERVER_A: {} } }))
+        .expect("Key query failed")
+        .into_json()
+        .expect("Key query answered with invalid JSON");
+
+    let server_keys = &response["server_keys"][0];
+    assert_eq!(server_keys["server_name"], SERVER_A);
+    assert_eq!(&server_keys["verify_keys"][&key_name]["key"], public_key);
+    assert!(a.has_signed_json(server_keys), "A's signature is broken");
+    assert!(b.has_signed_json(server_keys), "B did not countersign");
+
+    let foreign_keys = b.read_json("foreign_keys.json");
+    assert!(foreign_keys[SERVER_A].is_array(), "B did not keep A's keys");
+}
+
+#[test]
+fn sent_message_is_stored_once() {
+    let servers = start_federation(&[SERVER_A, SERVER_B], &[ROOM_ID]);
+    let (a, b) = (&servers[0], &servers[1]);
+
+    let transaction = json!({
+        "origin": SERVER_A,
+        "origin_server_ts": now_millis(),
+        "pdus": [a.message_pdu(ROOM_ID, "Hello from A")],
+    });
+    let uri = "/_matrix/federation/v1/send/1";
+
+    let response = a.send_federation_request(b, "PUT", uri, Some(transaction.clone()));
+
+    let results = response["pdus"].as_object().expect("Results are a map");
+    assert_eq!(results.len(), 1);
+    for (event_id, result) in results {
+        assert!(
+            result.get("error").is_none(),
+            "{} failed: {}",
+            event_id,
+            result
+        );
+    }
+
+    // A retry gets the same answer, without storing anything twice
+    let retry_response = a.send_federation_request(b, "PUT", uri, Some(transaction));
+    assert_eq!(retry_response, response);
+
+    let persistent = b.read_json("persistent.json");
+    let pdu_blobs = persistent["rooms"][ROOM_ID]["pdu_blobs"]
+        .as_array()
+        .expect("Room is in B's persistent state");
+    assert_eq!(pdu_blobs.len(), 1);
+
+    let pdu: Value = serde_json::from_str(pdu_blobs[0].as_str().expect("PDU blobs are strings"))
+        .expect("Stored PDU is valid JSON");
+    assert_eq!(pdu["content"]["body"], "Hello from A");
+    assert_eq!(pdu["sender"], "@alice:a.localhost");
+
+    // Checking the PDU's signature had B fetch A's keys from A
+    let foreign_keys = b.read_json("foreign_keys.json");
+    assert!(
+        foreign_keys[SERVER_A].is_array(),
+        "B did not fetch A's keys"
+    );
+}
+
+#[test]
+fn transaction_fails_after_shutdown() {
+    let servers = start_federation(&[SERVER_A, SERVER_B], &[ROOM_ID]);
+    let (a, b) = (&servers[0], &servers[1]);
+
+    b.shutdown();
+
+    let transaction = json!({
+        "origin": SERVER_A,
+        "origin_server_ts": now_millis(),
+        "pdus": [a.message_pdu(ROOM_ID, "Too late")],
+    });
+    let uri = "/_matrix/federation/v1/send/1";
+
+    // Not on disk, so A has to send it again to whatever comes next
+    match a.try_federation_request(b, "PUT", uri, Some(transaction)) {
+        Err((status, _body)) => assert_eq!(status, 503),
+        Ok(response) => panic!("Accepted after shutdown: {}", response),
+    }
+
+    let persistent = b.read_json("persistent.json");
+    let pdu_blobs = persistent["rooms"][ROOM_ID]["pdu_blobs"]
+        .as_array()
+        .expect("Room is in B's persistent state");
+    assert!(pdu_blobs.is_empty(), "B stored a PDU after shutting down");
+}
+
+#[test]
+fn rotated_key_stays_in_old_verify_keys() {
+    let servers = start_federation(&[SERVER_A], &[]);
+    let a = &servers[0];
+
+    let (old_key_name, _key_pair) = a.signing_key();
+    let old_public_key =
+        a.read_json("server_keys.json")[&old_key_name]["public_key_base64"].clone();
+
+    let response: Value = ureq::get(&a.url("/admin/rotate_keys"))
+        .call()
+        .expect("Key rotation failed")
+        .into_json()
+        .expect("Key rotation answered with invalid JSON");
+    let text = response["text"].as_str().expect("Key rotation answered");
+    assert!(text.starts_with("Rotated to new key"), "{}", text);
+
+    let (new_key_name, _key_pair) = a.signing_key();
+    assert_ne!(new_key_name, old_key_name);
+
+    let server_keys: Value = ureq::get(&a.url("/_matrix/key/v2/server"))
+        .call()
+        .expect("Key request failed")
+        .into_json()
+        .expect("Key request answered with invalid JSON");
+
+    assert!(server_keys["verify_keys"][&new_key_name].is_object());
+    assert!(server_keys["verify_keys"].get(&old_key_name).is_none());
+
+    // Whatever A signed before can still be checked
+    let old_verify_key = &server_keys["old_verify_keys"][&old_key_name];
+    assert_eq!(old_verify_key["key"], old_public_key);
+    assert!(old_verify_key["expired_ts"].is_u64(), "{}", old_verify_key);
+
+    assert!(
+        a.has_signed_json(&server_keys),
+        "Not signed with the new key"
+    );
+}
+
+#[test]
+fn migration_racing_transactions_loses_no_pdus() {
+    let servers = start_federation(&[SERVER_A, SERVER_B], &[ROOM_ID]);
+    let (a, b) = (&servers[0], &servers[1]);
+
+    let transaction_count = 40;
+    let (accepted_sender, accepted) = std::sync::mpsc::channel();
+
+    let migration_text = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for transaction_id in 0..transaction_count {
+                let transaction = json!({
+                    "origin": SERVER_A,
+                    "origin_server_ts": now_millis(),
+                    "pdus": [a.message_pdu(ROOM_ID, &format!("Message {}", transaction_id))],
+                });
+                let uri = format!("/_matrix/federation/v1/send/{}", transaction_id);
+
+                let response = a.send_federation_request(b, "PUT", &uri, Some(transaction));
+                for (event_id, result) in response["pdus"].as_object().expect("Results are a map") {
+                    assert!(
+                        result.get("error").is_none(),
+                        "{} failed: {}",
+                        event_id,
+                        result
+                    );
+                }
+
+                let _ = accepted_sender.send(transaction_id);
+            }
+        });
+
+        // Some PDUs are copied in bulk, the rest arrive while that happens
+        // or after the room was switched over
+        accepted.iter().take(transaction_count / 4).for_each(drop);
+
+        let response: Value = ureq::get(&b.url(&format!("/admin/migrate/{}", ROOM_ID)))
+            .call()
+            .expect("Migration failed")
+            .into_json()
+            .expect("Migration answered with invalid JSON");
+
+        response["text"]
+            .as_str()
+            .expect("Migration answered")
+            .to_owned()
+    });
+    assert!(migration_text.starts_with("Migrated"), "{}", migration_text);
+
+    let persistent = b.read_json("persistent.json");
+    let room = &persistent["rooms"][ROOM_ID];
+    assert!(room["room_db"].is_string(), "Room was not switched over");
+    assert_eq!(room["pdu_blobs"], json!([]));
+
+    let mut bodies: Vec<String> = b
+        .stored_pdus(ROOM_ID)
+        .iter()
+        .map(|pdu| {
+            pdu["content"]["body"]
+                .as_str()
+                .expect("PDU has a body")
+                .to_owned()
+        })
+        .collect();
+    bodies.sort();
+
+    let mut expected_bodies: Vec<String> = (0..transaction_count)
+        .map(|transaction_id| format!("Message {}", transaction_id))
+        .collect();
+    expected_bodies.sort();
+
+    assert_eq!(bodies, expected_bodies);
+}
+
+#[test]
+fn joining_server_stores_the_room_state() {
+    let servers = start_servers(&[(SERVER_A, &[ROOM_ID]), (SERVER_B, &[])]);
+    let (a, b) = (&servers[0], &servers[1]);
+    create_room(a);
+
+    let join_text = admin_text(b, &format!("/admin/send/{}/@bob:b.localhost", ROOM_ID));
+    assert!(join_text.starts_with("Joined"), "{}", join_text);
+
+    let a_pdus = a.stored_pdus(ROOM_ID);
+    assert_eq!(a_pdus.len(), 7);
+    let join_pdu = a_pdus
+        .iter()
+        .find(|pdu| pdu["state_key"] == "@bob:b.localhost")
+        .expect("A did not store the join");
+    assert_eq!(join_pdu["type"], "m.room.member");
+    assert_eq!(join_pdu["content"]["membership"], "join");
+
+    // B gets the room's state, but none of its history
+    let b_pdus = b.stored_pdus(ROOM_ID);
+    let mut state_keys: Vec<(&str, &str)> = b_pdus
+        .iter()
+        .map(|pdu| {
+            (
+                pdu["type"].as_str().expect("PDU has a type"),
+                pdu["state_key"].as_str().expect("PDU is a state event"),
+            )
+        })
+        .collect();
+    state_keys.sort();
+    assert_eq!(
+        state_keys,
+        [
+            ("m.room.create", ""),
+            ("m.room.join_rules", ""),
+            ("m.room.member", "@alice:a.localhost"),
+            ("m.room.member", "@bob:b.localhost"),
+            ("m.room.power_levels", ""),
+        ]
+    );
+    assert!(b_pdus.contains(join_pdu), "B's join differs from A's");
+}
+
+#[test]
+fn backfill_fetches_history_from_before_the_join() {
+    let servers = start_servers(&[(SERVER_A, &[ROOM_ID]), (SERVER_B, &[])]);
+    let (a, b) = (&servers[0], &servers[1]);
+    let room_event_ids = create_room(a);
+
+    // A only lets servers in the room see its history
+    let refused_text = admin_text(
+        b,
+        &format!("/admin/backfill/{}/{}", ROOM_ID, room_event_ids[0]),
+    );
+    assert!(refused_text.starts_with("Error"), "{}", refused_text);
+
+    let join_text = admin_text(b, &format!("/admin/send/{}/@bob:b.localhost", ROOM_ID));
+    assert!(join_text.starts_with("Joined"), "{}", join_text);
+
+    let join_pdu = b
+        .stored_pdus(ROOM_ID)
+        .into_iter()
+        .find(|pdu| pdu["state_key"] == "@bob:b.localhost")
+        .expect("B did not store its join");
+
+    let backfill_text = admin_text(
+        b,
+        &format!("/admin/backfill/{}/{}", ROOM_ID, event_id(&join_pdu)),
+    );
+    assert_eq!(backfill_text, "Backfilled 7 PDUs.");
+
+    // Events B already had are not stored twice
+    let b_pdus = b.stored_pdus(ROOM_ID);
+    assert_eq!(b_pdus.len(), 7);
+
+    let mut bodies: Vec<&str> = b_pdus
+        .iter()
+        .filter_map(|pdu| pdu["content"]["body"].as_str())
+        .collect();
+    bodies.sort();
+    assert_eq!(bodies, ["Before the join 1", "Before the join 2"]);
+}
+
+/// Has `@alice` on A create a public room with some history, returning the
+/// event IDs in the order they were sent.
+fn create_room(a: &TestServer) -> Vec<String> {
+    let alice = format!("@alice:{}", SERVER_A);
+    let mut event_ids: Vec<String> = Vec::new();
+
+    let mut send = |fields: Value, auth_event_indices: &[usize]| {
+        let mut fields = fields;
+        fields["room_id"] = json!(ROOM_ID);
+        fields["depth"] = json!(event_ids.len() + 1);
+        fields["prev_events"] = json!(event_ids.last().into_iter().collect::<Vec<_>>());
+        fields["auth_events"] = json!(auth_event_indices
+            .iter()
+            .map(|index| &event_ids[*index])
+            .collect::<Vec<_>>());
+
+        // There is no client API, so even A's own users send PDUs to it over
+        // federation
+        let event_id = send_pdu(a, a, a.build_pdu(fields));
+        event_ids.push(event_id);
+    };
+
+    send(
+        json!({
+            "content": { "creator": alice, "room_version": "6" },
+            "state_key": "",
+            "type": "m.room.create",
+        }),
+        &[],
+    );
+    send(
+        json!({
+            "content": { "membership": "join" },
+            "state_key": alice,
+            "type": "m.room.member",
+        }),
+        &[0],
+    );
+    send(
+        json!({
+            "content": {
+                "ban": 50,
+                "events": {},
+                "events_default": 0,
+                "kick": 50,
+                "redact": 50,
+                "state_default": 50,
+                "users": { &alice: 100 },
+                "users_default": 0,
+            },
+            "state_key": "",
+            "type": "m.room.power_levels",
+        }),
+        &[0, 1],
+    );
+    send(
+        json!({
+            "content": { "join_rule": "public" },
+            "state_key": "",
+            "type": "m.room.join_rules",
+        }),
+        &[0, 1, 2],
+    );
+    for message_number in 1..=2 {
+        send(
+            json!({
+                "content": {
+                    "body": format!("Before the join {}", message_number),
+                    "msgtype": "m.text",
+                },
+                "type": "m.room.message",
+            }),
+            &[0, 1, 2],
+        );
+    }
+
+    event_ids
+}
+
+/// Sends a PDU in a transaction of its own, returning the event ID the
+/// receiving server gave it.
+fn send_pdu(from: &TestServer, to: &TestServer, pdu: Value) -> String {
+    static NEXT_TRANSACTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let transaction = json!({
+        "origin": from.server_name,
+        "origin_server_ts": now_millis(),
+        "pdus": [pdu],
+    });
+    let uri = format!(
+        "/_matrix/federation/v1/send/seed-{}",
+        NEXT_TRANSACTION_ID.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let response = from.send_federation_request(to, "PUT", &uri, Some(transaction));
+    let results = response["pdus"].as_object().expect("Results are a map");
+    assert_eq!(results.len(), 1);
+
+    let (event_id, result) = results.iter().next().expect("One result");
+    assert!(
+        result.get("error").is_none(),
+        "{} failed: {}",
+        event_id,
+        result
+    );
+
+    event_id.clone()
+}
+
+/// The text an admin route answered with.
+fn admin_text(server: &TestServer, path: &str) -> String {
+    let response: Value = ureq::get(&server.url(path))
+        .call()
+        .expect("Admin request failed")
+        .into_json()
+        .expect("Admin route answered with invalid JSON");
+
+    response["text"]
+        .as_str()
+        .expect("Admin route answered with text")
+        .to_owned()
+}